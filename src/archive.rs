@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use failure::{bail, ResultExt};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::cmd::{self, CommandOptions};
+use crate::config::Config;
+use crate::diff::{self, OutputData};
+use crate::output;
+use crate::Result;
+
+#[derive(Debug, StructOpt)]
+pub struct ExportOpts {
+    #[structopt(flatten)]
+    pub command: cmd::CommandOpts,
+    /// The file to write the archive to. Defaults to stdout
+    #[structopt(name = "FILE", long = "output", short = "o", parse(from_os_str))]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ImportOpts {
+    /// The archive to import, as written by `bp export`
+    #[structopt(name = "FILE", parse(from_os_str))]
+    pub file: PathBuf,
+    #[structopt(flatten)]
+    pub command: cmd::CommandOpts,
+    /// Replace the baseline if one has already been recorded for the command
+    #[structopt(long = "force")]
+    pub force: bool,
+}
+
+/// The version of the archive format written by this version.
+const ARCHIVE_VERSION: u32 = 1;
+
+/// A baseline exported from one machine to be imported on another. The runs are stored as they
+/// are in the baseline file, so an archive can also be given to '--baseline' directly.
+#[derive(Debug, Serialize, Deserialize)]
+struct Archive {
+    version: u32,
+    /// The command the baseline was recorded for. This is only informational, since the
+    /// baseline is installed for whichever command it is imported for.
+    command: String,
+    exported_at: SystemTime,
+    runs: VecDeque<OutputData>,
+}
+
+/// Just the version of an archive, which is checked before reading the rest of it.
+#[derive(Debug, Deserialize)]
+struct ArchiveVersion {
+    version: Option<u32>,
+}
+
+/// Write the recorded baseline for a command to an archive, so it can be shared with other
+/// machines.
+pub fn export(opts: &ExportOpts, config: Config) -> Result<i32> {
+    let command = CommandOptions::new(&opts.command, &config)?;
+    log::trace!("command: {:#?}", command);
+
+    let dir = opts.command.data_dir(&command, &config)?;
+    let runs = diff::load(&dir)?;
+    if runs.is_empty() {
+        log::info!("no runs of '{}' have been recorded", command);
+        return Ok(1);
+    }
+
+    let archive = Archive {
+        version: ARCHIVE_VERSION,
+        command: command.to_string(),
+        exported_at: SystemTime::now(),
+        runs,
+    };
+    match &opts.output {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|_| format!("failed to create file '{}'", path.display()))?;
+            let mut writer = BufWriter::new(file);
+            json::to_writer(&mut writer, &archive)
+                .map_err(io::Error::from)
+                .and_then(|()| writer.flush())
+                .with_context(|_| format!("failed to write to file '{}'", path.display()))?;
+            log::info!(
+                "exported {} run(s) of '{}' to '{}'",
+                archive.runs.len(),
+                command,
+                path.display()
+            );
+        }
+        None => {
+            json::to_writer(io::stdout(), &archive)?;
+            println!();
+        }
+    }
+    Ok(0)
+}
+
+/// Install a baseline from an archive written by `export` into the data directory of a
+/// command. The data directory is computed for the command on this machine, so the archive
+/// can be imported even if, for example, the working directory differs from where it was
+/// exported.
+pub fn import(opts: &ImportOpts, config: Config) -> Result<i32> {
+    let command = CommandOptions::new(&opts.command, &config)?;
+    log::trace!("command: {:#?}", command);
+
+    let archive = read(&opts.file)?;
+    if archive.runs.is_empty() {
+        bail!("archive '{}' has no recorded runs", opts.file.display());
+    }
+    if archive.command != command.to_string() {
+        log::warn!(
+            "the archive was exported for '{}', but is being imported for '{}'",
+            archive.command,
+            command
+        );
+    }
+
+    // The directory is prepared as for a run, so the command is recorded in it and a hash
+    // collision is isolated.
+    let dir = output::prepare_dir(
+        &opts.command.hash_dir(&command, &config)?,
+        &command,
+        diff::Access::Exclusive,
+        output::OnCollision::default(),
+    )?;
+    let count = archive.runs.len();
+    diff::install(&dir, archive.runs, opts.force, config.compress)?;
+    log::info!(
+        "imported {} run(s) of '{}' into '{}'",
+        count,
+        command,
+        dir.display()
+    );
+    Ok(0)
+}
+
+fn read(path: &Path) -> Result<Archive> {
+    let string = fs::read_to_string(path)
+        .with_context(|_| format!("failed to open archive '{}'", path.display()))?;
+    let version: ArchiveVersion = json::from_str(&string)
+        .with_context(|_| format!("failed to read JSON file '{}'", path.display()))?;
+    match version.version {
+        Some(ARCHIVE_VERSION) => (),
+        Some(version) => bail!(
+            "archive '{}' has version {}, but only version {} is supported",
+            path.display(),
+            version,
+            ARCHIVE_VERSION
+        ),
+        None => bail!(
+            "file '{}' is not an archive written by 'bp export'",
+            path.display()
+        ),
+    }
+    Ok(json::from_str(&string)
+        .with_context(|_| format!("failed to read JSON file '{}'", path.display()))?)
+}
@@ -0,0 +1,118 @@
+use std::io;
+use std::time::Duration;
+
+use failure::bail;
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::cmd;
+use crate::config::Config;
+use crate::{diff, util, Result};
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    /// The number of runs to measure
+    #[structopt(name = "RUNS", long = "runs", default_value = "10")]
+    pub runs: u32,
+    /// The number of runs before measuring, which are excluded from the statistics
+    #[structopt(name = "WARMUP", long = "warmup", default_value = "0")]
+    pub warmup: u32,
+    /// Print the statistics as JSON. Messages are written to stderr instead of stdout
+    #[structopt(long = "json")]
+    pub json: bool,
+    #[structopt(flatten)]
+    pub cmd: cmd::Opts,
+}
+
+/// The distribution of the total durations of the measured runs.
+#[derive(Debug, Serialize)]
+struct Bench {
+    runs: usize,
+    warmup: u32,
+    min: Duration,
+    median: Duration,
+    mean: Duration,
+    max: Duration,
+    /// The sample standard deviation, which is zero if only one run was measured.
+    stddev: Duration,
+    /// The total duration of each measured run, in order.
+    totals: Vec<Duration>,
+}
+
+/// Run the command repeatedly, stopping at the first run which fails, and print statistics
+/// about how long the runs took. Each run is recorded as usual, so if there is no baseline
+/// yet, the first run records one and the rest show the progress bar.
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    if opts.runs == 0 {
+        bail!("'--runs' must be at least 1");
+    }
+    if opts.cmd.pipeline.is_some() {
+        bail!("'--pipeline' is not supported with 'bench'");
+    }
+    if opts.cmd.dry_run {
+        bail!("'--dry-run' is not supported with 'bench'");
+    }
+
+    let count = opts.warmup + opts.runs;
+    let mut totals = Vec::with_capacity(opts.runs as usize);
+    for idx in 0..count {
+        if idx < opts.warmup {
+            log::info!("warmup run {} of {}", idx + 1, opts.warmup);
+        } else {
+            log::info!("run {} of {}", idx - opts.warmup + 1, opts.runs);
+        }
+
+        let (code, total) = cmd::run_timed(&opts.cmd, config.clone())?;
+        if code != 0 {
+            log::error!("stopping after a run failed with exit code {}", code);
+            return Ok(code);
+        }
+        if idx >= opts.warmup {
+            totals.push(total);
+        }
+    }
+
+    let bench = Bench::new(totals, opts.warmup);
+    if opts.json {
+        json::to_writer_pretty(io::stdout(), &bench)?;
+        println!();
+    } else {
+        let format = |dur| humantime::format_duration(util::truncate_millis(dur));
+        if bench.warmup == 0 {
+            println!("runs:    {}", bench.runs);
+        } else {
+            println!("runs:    {} ({} warmup)", bench.runs, bench.warmup);
+        }
+        println!("min:     {}", format(bench.min));
+        println!("median:  {}", format(bench.median));
+        println!("mean:    {}", format(bench.mean));
+        println!("max:     {}", format(bench.max));
+        println!("stddev:  {}", format(bench.stddev));
+    }
+
+    Ok(0)
+}
+
+impl Bench {
+    fn new(totals: Vec<Duration>, warmup: u32) -> Self {
+        debug_assert!(!totals.is_empty());
+        let secs: Vec<f64> = totals.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = if secs.len() > 1 {
+            secs.iter().map(|secs| (secs - mean).powi(2)).sum::<f64>() / (secs.len() - 1) as f64
+        } else {
+            0.0
+        };
+
+        Bench {
+            runs: totals.len(),
+            warmup,
+            min: totals.iter().copied().min().unwrap_or_default(),
+            median: diff::median(totals.clone()),
+            mean: Duration::from_secs_f64(mean),
+            max: totals.iter().copied().max().unwrap_or_default(),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+            totals,
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+
+use console::Term;
+use failure::bail;
+use lazy_static::lazy_static;
+use structopt::clap::{AppSettings, ErrorKind, Shell};
+use structopt::StructOpt;
+
+use crate::{
+    archive, bench, cmd, compare, config, eta, explain, gc, history, logger, output, path,
+    pipeline, stats, watch, Result,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    usage = "bp.exe [OPTIONS] <COMMAND>...\n    bp.exe [OPTIONS] --profile <PROFILE>\n    bp.exe [OPTIONS] --pipeline <PIPELINE>\n    bp.exe <SUBCOMMAND> [OPTIONS] <COMMAND>..."
+)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::TrailingVarArg"))]
+#[structopt(raw(global_setting = "structopt::clap::AppSettings::UnifiedHelpMessage"))]
+#[structopt(raw(global_setting = "structopt::clap::AppSettings::DisableVersion"))]
+#[structopt(raw(setting = "structopt::clap::AppSettings::SubcommandsNegateReqs"))]
+// Subcommands must be given before any other arguments, so that the command being run can
+// contain arguments which look like subcommands.
+#[structopt(raw(setting = "structopt::clap::AppSettings::ArgsNegateSubcommands"))]
+#[structopt(raw(after_help = "EXIT_STATUS_HELP.as_str()"))]
+struct Opts {
+    #[structopt(flatten)]
+    logger: logger::Opts,
+    #[structopt(flatten)]
+    config: config::Opts,
+    /// The exit code to return if bp itself fails, rather than the command
+    #[structopt(
+        name = "ERROR_CODE",
+        long = "error-code",
+        raw(default_value = "DEFAULT_ERROR_CODE_STR.as_str()"),
+        global = true
+    )]
+    error_code: i32,
+    /// The exit code to return if the command fails for any reason, including '--timeout',
+    /// instead of the codes listed below
+    #[structopt(name = "FAILURE_CODE", long = "failure-code", global = true)]
+    failure_code: Option<i32>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    #[structopt(flatten)]
+    cmd: cmd::Opts,
+}
+
+/// Subcommands for inspecting recorded data. If no subcommand is given, the command is run.
+// Only one instance is ever created, so the size of `watch` doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run a command repeatedly and print statistics about how long it takes
+    #[structopt(
+        name = "bench",
+        usage = "bp.exe bench [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Bench(bench::Opts),
+    /// Compare the most recent recorded runs of two commands
+    #[structopt(
+        name = "diff",
+        usage = "bp.exe diff [OPTIONS] <A> <B> [-- <COMMAND>...]"
+    )]
+    Diff(compare::Opts),
+    /// Print the expected duration of a command, without running it
+    #[structopt(
+        name = "eta",
+        usage = "bp.exe eta [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Eta(eta::Opts),
+    /// Run a command, showing how each line of its output matches the recorded output
+    #[structopt(
+        name = "explain",
+        usage = "bp.exe explain [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Explain(explain::Opts),
+    /// Write the recorded baseline of a command to an archive, to share it with other machines
+    #[structopt(
+        name = "export",
+        usage = "bp.exe export [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Export(archive::ExportOpts),
+    /// Show the durations of previous runs of a command
+    #[structopt(
+        name = "history",
+        usage = "bp.exe history [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    History(history::Opts),
+    /// Install a baseline from an archive written by `export` for a command
+    #[structopt(
+        name = "import",
+        usage = "bp.exe import [OPTIONS] <FILE> <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Import(archive::ImportOpts),
+    /// Print the directory where data for a command is stored
+    #[structopt(
+        name = "path",
+        usage = "bp.exe path [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Path(path::Opts),
+    /// Show a summary of the recorded output of a command
+    #[structopt(
+        name = "stats",
+        usage = "bp.exe stats [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Stats(stats::Opts),
+    /// Run a command, then run it again whenever files under the watched paths change
+    #[structopt(
+        name = "watch",
+        usage = "bp.exe watch [OPTIONS] <COMMAND>...",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Watch(watch::Opts),
+    /// Remove data recorded by older versions
+    #[structopt(name = "gc")]
+    Gc(gc::Opts),
+    /// Print a script which completes options for the given shell
+    #[structopt(
+        name = "completions",
+        raw(setting = "structopt::clap::AppSettings::Hidden")
+    )]
+    Completions(CompletionsOpts),
+}
+
+#[derive(Debug, StructOpt)]
+struct CompletionsOpts {
+    /// The shell to generate completions for
+    #[structopt(
+        name = "SHELL",
+        raw(possible_values = r#"&["bash", "zsh", "fish", "powershell"]"#)
+    )]
+    shell: Shell,
+}
+
+impl Opts {
+    /// Get the options for running a command, if the command is run by `bp` itself or by a
+    /// subcommand which runs it.
+    fn cmd_opts(&self) -> Option<&cmd::Opts> {
+        match &self.command {
+            None => Some(&self.cmd),
+            Some(Command::Bench(bench_opts)) => Some(&bench_opts.cmd),
+            Some(Command::Watch(watch_opts)) => Some(&watch_opts.cmd),
+            Some(_) => None,
+        }
+    }
+}
+
+/// The exit code returned if `bp` fails, unless changed with '--error-code'.
+const DEFAULT_ERROR_CODE: i32 = 17;
+
+lazy_static! {
+    /// The default of '--error-code', as shown in the help.
+    static ref DEFAULT_ERROR_CODE_STR: String = DEFAULT_ERROR_CODE.to_string();
+    /// The exit codes returned by `bp`, shown at the end of its help.
+    static ref EXIT_STATUS_HELP: String = format!(
+        "EXIT STATUS:
+    When running a command, bp returns its exit code, or 128 + N if it was killed by signal N.
+    Otherwise, or if bp itself fails, the codes are:
+
+    1      Invalid arguments, or a subcommand found nothing to report, e.g. no runs were recorded
+    {:<6} bp failed, e.g. the command couldn't be started (see '--error-code')
+    123    The command was killed by '--overtime-factor'
+    124    The command was killed by '--timeout'
+    130    The command was interrupted with Ctrl-C
+
+    With '--failure-code', any failure of the command returns the given code instead, but
+    failures of bp itself still return the '--error-code'.",
+        DEFAULT_ERROR_CODE
+    );
+}
+
+/// Run the `bp` command line tool, returning its exit code. See `EXIT_STATUS_HELP` for the
+/// codes which may be returned.
+pub fn main() -> i32 {
+    let mut error_code = DEFAULT_ERROR_CODE;
+    match run(&mut error_code) {
+        Ok(code) => code,
+        Err(err) => {
+            log::error!("{}", crate::fmt_error(&err));
+            error_code
+        }
+    }
+}
+
+/// Run the tool, setting `error_code` to the code to return if it fails once it is known.
+fn run(error_code: &mut i32) -> Result<i32> {
+    let args: Vec<OsString> = env::args_os().collect();
+    let mut opts = Opts::from_iter(&args);
+    // The config is read before the logger is initialized, since its defaults may change the
+    // logging options.
+    let config = config::read(&opts.config).and_then(|config| {
+        if !config.defaults.is_empty() {
+            opts = with_defaults(&opts, &args, &config.defaults)?;
+        }
+        Ok(config)
+    });
+    // This is set after the defaults are applied, since they may also change the error code.
+    *error_code = opts.error_code;
+    // Messages are written to stderr if the command's output or `bench --json` is written to
+    // stdout, so they aren't mixed into it.
+    let output_is_stdout = opts
+        .cmd_opts()
+        .is_some_and(|cmd_opts| cmd_opts.output_is_stdout());
+    let json_is_stdout = match &opts.command {
+        Some(Command::Bench(bench_opts)) => bench_opts.json,
+        _ => false,
+    };
+    let term = match opts.logger.progress_fd() {
+        _ if output_is_stdout || json_is_stdout => Term::stderr(),
+        Some(fd) => fd.term(),
+        None => Term::stdout(),
+    };
+    let record_only = opts.cmd_opts().is_some_and(|cmd_opts| cmd_opts.record_only);
+    logger::init(opts.logger, term, record_only);
+    log::trace!("options: {:#?}", opts);
+    if output_is_stdout && opts.logger.progress_fd() == Some(logger::ProgressFd::Stdout) {
+        bail!(
+            "'--progress-fd stdout' cannot be used with '--output {}'",
+            output::STDOUT_PATH
+        );
+    }
+    if json_is_stdout && opts.logger.progress_fd() == Some(logger::ProgressFd::Stdout) {
+        bail!("'--progress-fd stdout' cannot be used with 'bench --json'");
+    }
+
+    let config = config?;
+    log::trace!("config: {:#?}", config);
+
+    let code = match &opts.command {
+        Some(Command::Bench(bench_opts)) => bench::run(bench_opts, config),
+        Some(Command::Diff(diff_opts)) => compare::run(diff_opts, config),
+        Some(Command::Eta(eta_opts)) => eta::run(eta_opts, config),
+        Some(Command::Explain(explain_opts)) => explain::run(explain_opts, config),
+        Some(Command::Export(export_opts)) => archive::export(export_opts, config),
+        Some(Command::History(history_opts)) => history::run(history_opts, config),
+        Some(Command::Import(import_opts)) => archive::import(import_opts, config),
+        Some(Command::Path(path_opts)) => path::run(path_opts, config),
+        Some(Command::Stats(stats_opts)) => stats::run(stats_opts, config),
+        Some(Command::Watch(watch_opts)) => watch::run(watch_opts, config),
+        Some(Command::Gc(gc_opts)) => gc::run(gc_opts),
+        Some(Command::Completions(completions_opts)) => {
+            Opts::clap().gen_completions_to("bp", completions_opts.shell, &mut io::stdout());
+            Ok(0)
+        }
+        None => match &opts.cmd.pipeline {
+            Some(path) => pipeline::run(&opts.cmd, config, path),
+            None => cmd::run(&opts.cmd, config),
+        },
+    }?;
+
+    // Only subcommands which run the command report its failures.
+    let runs_command = match &opts.command {
+        None | Some(Command::Bench(_)) | Some(Command::Watch(_)) => true,
+        Some(_) => false,
+    };
+    match opts.failure_code {
+        Some(failure_code) if runs_command && code != 0 => Ok(failure_code),
+        _ => Ok(code),
+    }
+}
+
+/// Flags which can also be set by an environment variable, which takes precedence over the
+/// config's defaults.
+const ENV_FLAGS: &[(&str, &str)] = &[("data-dir", "BUILD_PROGRESS_DATA_DIR")];
+
+/// Parse the command line again, with the defaults from the config inserted before the given
+/// arguments. Since later flags override earlier ones, flags given explicitly take precedence,
+/// even if they are set to their built-in default.
+fn with_defaults(
+    opts: &Opts,
+    args: &[OsString],
+    defaults: &BTreeMap<String, toml::Value>,
+) -> Result<Opts> {
+    // Subcommands are always the first argument, and their flags must follow them.
+    let start = if opts.command.is_some() { 2 } else { 1 };
+    let parse = |defaults: &[OsString]| {
+        let args = args[..start].iter().chain(defaults).chain(&args[start..]);
+        Opts::clap()
+            .global_setting(AppSettings::AllArgsOverrideSelf)
+            .global_setting(AppSettings::ColorNever)
+            .get_matches_from_safe(args)
+            .map(|matches| Opts::from_clap(&matches))
+    };
+
+    let mut default_args = Vec::new();
+    for (name, value) in defaults {
+        if name == "config" {
+            bail!("the location of the config file can't be set in its defaults");
+        }
+        if ENV_FLAGS
+            .iter()
+            .any(|&(flag, var)| flag == name && env::var_os(var).is_some())
+        {
+            continue;
+        }
+
+        let flag_args = flag_args(name, value)?;
+        match parse(&flag_args) {
+            Ok(_) => default_args.extend(flag_args),
+            Err(ref err) if err.kind == ErrorKind::UnknownArgument => continue,
+            Err(err) => bail!(
+                "invalid default for flag '--{}' in config: {}",
+                name,
+                err.message
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .trim_start_matches("error: ")
+            ),
+        }
+    }
+
+    Ok(parse(&default_args)?)
+}
+
+/// Convert a default from the config to command line arguments.
+fn flag_args(name: &str, value: &toml::Value) -> Result<Vec<OsString>> {
+    let flag = format!("--{}", name);
+    Ok(match value {
+        toml::Value::Boolean(true) => vec![flag.into()],
+        toml::Value::Boolean(false) => Vec::new(),
+        toml::Value::Array(values) => {
+            let mut args = Vec::with_capacity(values.len());
+            for value in values {
+                args.extend(flag_args(name, value)?);
+            }
+            args
+        }
+        toml::Value::String(value) => vec![format!("{}={}", flag, value).into()],
+        toml::Value::Integer(value) => vec![format!("{}={}", flag, value).into()],
+        toml::Value::Float(value) => vec![format!("{}={}", flag, value).into()],
+        _ => bail!("invalid default for flag '{}' in config", flag),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], config: &str) -> Opts {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        let config: config::Config = toml::from_str(config).unwrap();
+        with_defaults(&Opts::from_iter(&args), &args, &config.defaults).unwrap()
+    }
+
+    #[test]
+    fn flags_override_config_defaults() {
+        let config = r#"
+            [defaults]
+            max-lines = 100
+            prefix-match = true
+            env = ["CARGO_HOME"]
+        "#;
+
+        let opts = parse(&["bp", "cargo", "build"], config);
+        assert_eq!(opts.cmd.max_lines, Some(100));
+        assert!(opts.cmd.prefix_match);
+
+        // A flag given on the command line wins, even though the default is inserted too.
+        let opts = parse(&["bp", "--max-lines", "5", "cargo", "build"], config);
+        assert_eq!(opts.cmd.max_lines, Some(5));
+        assert!(opts.cmd.prefix_match);
+    }
+
+    #[test]
+    fn unknown_defaults_are_ignored_by_subcommands() {
+        let opts = parse(&["bp", "gc"], "[defaults]\nmax-lines = 100\n");
+        assert!(matches!(opts.command, Some(Command::Gc(_))));
+    }
+
+    #[test]
+    fn watch_uses_command_options() {
+        let opts = parse(
+            &[
+                "bp",
+                "watch",
+                "--output",
+                "-",
+                "--record-only",
+                "cargo",
+                "build",
+            ],
+            "",
+        );
+        let cmd_opts = opts.cmd_opts().unwrap();
+        assert!(cmd_opts.output_is_stdout());
+        assert!(cmd_opts.record_only);
+
+        let opts = parse(&["bp", "gc"], "");
+        assert!(opts.cmd_opts().is_none());
+    }
+
+    #[test]
+    fn default_error_code() {
+        let opts = parse(&["bp", "true"], "");
+        assert_eq!(opts.error_code, DEFAULT_ERROR_CODE);
+        assert!(EXIT_STATUS_HELP.contains(&format!("\n    {}     bp failed", DEFAULT_ERROR_CODE)));
+    }
+}
@@ -1,135 +1,1308 @@
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::ffi::OsString;
-use std::fmt;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
 use std::io::{self, prelude::*, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fmt, mem};
 use structopt::StructOpt;
 
-use failure::ResultExt;
-use futures::future::Either;
+use failure::{bail, ResultExt};
 use futures::prelude::*;
 use indicatif::HumanDuration;
+use regex::RegexSet;
 use tokio::runtime::Runtime;
-use tokio::timer::Interval;
+use tokio::timer::{Delay, Interval};
 use tokio_io::{try_nb, AsyncRead};
 use tokio_process::CommandExt;
 
-use crate::config::Config;
-use crate::hash::hash;
+use crate::config::{Config, DataDirRule, Profile, ProgressMode};
+use crate::diff;
+use crate::hash;
 use crate::logger;
+use crate::normalize::Normalizer;
+use crate::notify;
+use crate::os_str;
 use crate::output;
-use crate::Result;
+use crate::util;
+use crate::{Error, Result};
+
+/// The exit code returned when the process is killed by `--timeout`, matching `timeout(1)`.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+/// The exit code returned when the process is killed by `--overtime-factor`.
+pub const OVERTIME_EXIT_CODE: i32 = 123;
+/// The exit code returned when the process is interrupted with Ctrl-C, matching shells.
+pub const INTERRUPT_EXIT_CODE: i32 = 130;
+/// The default maximum length of a line of output. Longer lines are split.
+const MAX_LINE_BYTES: usize = 4 * 1024 * 1024;
+/// The shortest interval between updates of the progress bar.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_millis(20);
+/// How long to wait for the process to exit after it is interrupted, before killing it.
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn run(opts: &Opts, config: Config) -> Result<i32> {
-    let command = CommandOptions::new(opts, config)?;
+    run_timed(opts, config).map(|(code, _)| code)
+}
+
+/// Run the command, returning its exit code and how long the final attempt took. The duration
+/// is zero with '--dry-run', since nothing is run.
+pub fn run_timed(opts: &Opts, mut config: Config) -> Result<(i32, Duration)> {
+    if opts.max_lines.is_some() {
+        config.max_lines = opts.max_lines;
+    }
+    if let Some(min_record_duration) = opts.min_record_duration {
+        config.min_record_duration = min_record_duration;
+    }
+    config.no_degrade = (config.no_degrade || opts.no_degrade) && !opts.force_record;
+    config.prefix_match |= opts.prefix_match;
+    config.trim_indent |= opts.trim_indent;
+    config.record_failures |= opts.record_failures;
+    config.notify |= opts.notify;
+    if let Some(notify_after) = opts.notify_after {
+        config.notify_after = notify_after;
+    }
+    if let Some(ms) = opts.refresh_interval {
+        config.progress.refresh_interval = Duration::from_millis(ms);
+    }
+    if let Some(mode) = opts.progress_mode {
+        config.progress.mode = mode;
+    }
+    let command = CommandOptions::new(&opts.command, &config)?;
     log::trace!("command: {:#?}", command);
+    log::debug!(
+        "command hash '{}' computed from '{}'",
+        command.hash(),
+        hash::escape(&command.hash_inputs())
+    );
+
+    if opts.dry_run {
+        return Ok((dry_run(opts, &config, &command)?, Duration::default()));
+    }
+    if opts.print_command {
+        log::info!("{}", command.to_shell_line());
+    }
+
+    let mut output = output::Writer::new(opts, &config, &command)?;
+    let baseline_len = output.diff().len();
+
+    let mut rt = Runtime::new()?;
+    let output = Arc::new(output);
+    let mut attempt = 0;
+    loop {
+        if let Some(len) = baseline_len {
+            let msg = format!("{:#}", HumanDuration(len));
+            logger::start_progress(len.as_millis() as u64, &msg, &config.progress);
+        }
+
+        let start = Instant::now();
+        let exit = run_once(
+            &mut rt,
+            opts,
+            &config,
+            &command,
+            &output,
+            baseline_len.is_some(),
+        )?;
+        let elapsed = start.elapsed();
+        let success = match exit {
+            Exit::Status(status) => status.success(),
+            Exit::Timeout | Exit::Overtime(_) | Exit::Interrupted => false,
+        };
+        // Only the final attempt is recorded, so that a transient failure doesn't prevent
+        // the baseline being updated.
+        let retry = !success && attempt < opts.retry && !exit.is_interrupted();
+        if exit.is_interrupted() {
+            output.abort()?;
+        } else if !retry {
+            output.finish(success)?;
+        }
+
+        match exit {
+            Exit::Status(status) if !status.success() => {
+                log::error!("process '{}' {}", command, describe_exit(status))
+            }
+            Exit::Status(_) => (),
+            Exit::Timeout => log::error!(
+                "process '{}' timed out after {}",
+                command,
+                humantime::format_duration(opts.timeout.unwrap_or_default())
+            ),
+            Exit::Overtime(len) => log_overtime(opts, &command, len),
+            Exit::Interrupted => log::error!("process '{}' was interrupted", command),
+        }
+
+        if retry {
+            attempt += 1;
+            log::info!(
+                "retrying process '{}' (attempt {} of {})",
+                command,
+                attempt + 1,
+                opts.retry + 1
+            );
+            if let Some(delay) = opts.retry_delay {
+                thread::sleep(delay);
+            }
+            output.reset()?;
+            continue;
+        }
+
+        let code = match exit {
+            Exit::Status(status) => exit_code(status),
+            Exit::Timeout => TIMEOUT_EXIT_CODE,
+            Exit::Overtime(_) => OVERTIME_EXIT_CODE,
+            Exit::Interrupted => INTERRUPT_EXIT_CODE,
+        };
+        output.finish_log(code)?;
+        if let Some(path) = output.path() {
+            log::info!("output log file is located at '{}'", path.display());
+        }
+
+        let mut summary = format!(
+            "'{}' finished in {} with exit code {}",
+            command,
+            humantime::format_duration(util::truncate_millis(elapsed)),
+            code
+        );
+        if let Some(path) = output.path() {
+            summary.push_str(&format!(", output logged to '{}'", path.display()));
+        }
+        notify::finished(&config, elapsed, &summary, code == 0);
+        logger::print_summary(summary);
+        return Ok((code, elapsed));
+    }
+}
+
+/// Print how the command would be run, and what has been recorded for it, without running it.
+fn dry_run(opts: &Opts, config: &Config, command: &CommandOptions) -> Result<i32> {
+    let dir = opts.command.data_dir(command, config)?;
+    println!("command:   {}", command);
+    println!("workdir:   {}", command.workdir.display());
+    println!("shell:     {}", command.shell);
+    for (idx, (key, val)) in command.stored_env().iter().enumerate() {
+        let label = if idx == 0 { "env:" } else { "" };
+        println!("{:<10} {}={}", label, key, val.to_string_lossy());
+    }
+    println!("hash:      {}", command.hash());
+    println!("data dir:  {}", dir.display());
+    print_baseline(&dir, config)?;
+    Ok(0)
+}
+
+/// Print a summary of the baseline recorded in `dir`, for `--dry-run`.
+pub fn print_baseline(dir: &Path, config: &Config) -> Result<()> {
+    let runs = match diff::load(dir) {
+        Ok(runs) => runs,
+        Err(err) => {
+            log::warn!("{}", crate::fmt_error(&err));
+            println!("baseline:  unavailable");
+            return Ok(());
+        }
+    };
+    if runs.is_empty() {
+        println!("baseline:  none, the next run will be recorded");
+    } else {
+        let total = diff::median(runs.iter().map(|run| run.total()).collect());
+        let normalizer = Normalizer::from_config(config)?;
+        let lines = diff::unique_line_count(&runs, &normalizer);
+        println!(
+            "baseline:  {} run(s), total {}, {} unique line(s)",
+            runs.len(),
+            humantime::format_duration(util::truncate_millis(total)),
+            lines
+        );
+    }
+
+    Ok(())
+}
+
+/// Log that the process was killed for running longer than `--overtime-factor` times its
+/// expected duration of `len`.
+pub fn log_overtime(opts: &Opts, command: &CommandOptions, len: Duration) {
+    log::error!(
+        "process '{}' was killed after running for {} times its expected duration of {}",
+        command,
+        opts.overtime_factor.unwrap_or_default(),
+        humantime::format_duration(util::truncate_millis(len))
+    );
+}
 
-    let mut output = output::Writer::new(opts, &command)?;
-    let progress_ticker = if let Some(len) = output.diff().len() {
-        let msg = format!("{:#}", HumanDuration(len));
-        logger::start_progress(len.as_millis() as u64, &msg);
+/// Run the command once, writing its output to `output`.
+///
+/// If Ctrl-C is pressed, the process is interrupted and given `INTERRUPT_TIMEOUT` to exit,
+/// before being killed. Pressing Ctrl-C again kills it immediately.
+pub fn run_once(
+    rt: &mut Runtime,
+    opts: &Opts,
+    config: &Config,
+    command: &CommandOptions,
+    output: &Arc<output::Writer>,
+    has_baseline: bool,
+) -> Result<Exit> {
+    let ticker = if has_baseline || opts.stall_warning.is_some() {
+        let mut interval = config.progress.refresh_interval;
+        if interval < MIN_REFRESH_INTERVAL {
+            log::warn!(
+                "refresh interval must be at least {}, using the minimum",
+                humantime::format_duration(MIN_REFRESH_INTERVAL)
+            );
+            interval = MIN_REFRESH_INTERVAL;
+        }
+        let output = output.clone();
+        let overtime_factor = opts.overtime_factor;
         Some(
-            Interval::new_interval(Duration::from_millis(200))
-                .for_each(|_| Ok(logger::tick_progress_bar())),
+            Interval::new_interval(interval)
+                .filter_map(move |_| {
+                    output.tick();
+                    overtime_factor.and_then(|factor| output.check_overtime(factor))
+                })
+                .into_future()
+                .map(|(len, _)| Exit::Overtime(len.expect("interval stream ended")))
+                .map_err(|(err, _)| Error::from(err)),
         )
     } else {
         None
     };
-
-    let mut rt = Runtime::new()?;
-    let output = Arc::new(output);
     let (output1, output2) = (output.clone(), output.clone());
-    let status_fut = command.spawn(
+    let (pid, status_fut) = command.spawn(
+        opts.stdin(command)?,
+        opts.line_options(),
         map_err(move |line| output1.write_stdout(line)),
         map_err(move |line| output2.write_stderr(line)),
     )?;
-    let status = if let Some(ticker) = progress_ticker {
-        match rt.block_on(status_fut.select2(ticker)) {
-            Ok(Either::A((status, _))) => status,
-            Ok(Either::B(_)) => unreachable!(),
-            Err(Either::A((err, _))) => return Err(err.into()),
-            Err(Either::B((err, _))) => return Err(err.into()),
-        }
+
+    // The child process is killed if its future is dropped before completion.
+    let mut exit_fut: Box<dyn Future<Item = Exit, Error = Error> + Send> =
+        Box::new(status_fut.map(Exit::Status).from_err());
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let interrupt_fut = interrupt(pid, interrupted.clone()).map(|()| Exit::Interrupted);
+    exit_fut = Box::new(select(exit_fut, interrupt_fut));
+    if let Some(timeout) = opts.timeout {
+        let delay = Delay::new(Instant::now() + timeout)
+            .map(|()| Exit::Timeout)
+            .from_err();
+        exit_fut = Box::new(select(exit_fut, delay));
+    }
+    if let Some(ticker) = ticker {
+        exit_fut = Box::new(select(exit_fut, ticker));
+    }
+
+    let exit = rt.block_on(exit_fut)?;
+    // The process usually exits by itself after being interrupted, but it should still be
+    // treated as an interrupted run.
+    if interrupted.load(Ordering::Relaxed) {
+        Ok(Exit::Interrupted)
     } else {
-        rt.block_on(status_fut)?
-    };
+        Ok(exit)
+    }
+}
+
+/// A future which completes once an interrupted process should be killed. The first Ctrl-C
+/// is forwarded to the process and sets `interrupted`, then the future completes when Ctrl-C
+/// is pressed again or `INTERRUPT_TIMEOUT` elapses.
+fn interrupt(pid: u32, interrupted: Arc<AtomicBool>) -> impl Future<Item = (), Error = Error> {
+    tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map_err(|(err, _)| Error::from(err))
+        .and_then(move |(_, rest)| {
+            interrupted.store(true, Ordering::Relaxed);
+            log::warn!(
+                "interrupted, waiting for the process to exit (press Ctrl-C again to kill it)"
+            );
+            forward_interrupt(pid);
 
-    output.finish(status.success())?;
+            let again = rest
+                .into_future()
+                .map(|_| ())
+                .map_err(|(err, _)| Error::from(err));
+            let timeout = Delay::new(Instant::now() + INTERRUPT_TIMEOUT).from_err();
+            select(again, timeout)
+        })
+}
 
-    if !status.success() {
-        log::error!("process '{}' exited unsuccessfully ({})", command, status);
+/// Send SIGINT to the process. If we are in the terminal's foreground process group, the
+/// terminal has already sent it to every process in the group, including the child.
+#[cfg(unix)]
+fn forward_interrupt(pid: u32) {
+    let fds = [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO];
+    let foreground = fds
+        .iter()
+        .any(|&fd| unsafe { libc::tcgetpgrp(fd) == libc::getpgrp() });
+    if !foreground {
+        log::debug!("sending SIGINT to process {}", pid);
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGINT);
+        }
     }
-    log::info!(
-        "output log file is located at '{}'",
-        output.path().display()
-    );
+}
+
+/// Windows sends Ctrl-C to every process attached to the console, so there is nothing to
+/// forward.
+#[cfg(not(unix))]
+fn forward_interrupt(_: u32) {}
 
-    Ok(status.code().unwrap_or(1))
+/// Ask the kernel to kill the process if bp exits before it does. The process is killed when
+/// its future is dropped, but that doesn't happen if bp is itself killed, e.g. with SIGKILL.
+///
+/// The signal is sent when the thread which spawned the process exits, rather than the whole
+/// of bp, so processes must be spawned on the main thread. Processes started by the command
+/// itself are not affected.
+#[cfg(target_os = "linux")]
+fn kill_on_exit(command: &mut Command) {
+    use std::os::unix::process::CommandExt as _;
+
+    let parent = unsafe { libc::getpid() };
+    let hook = move || {
+        if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // bp may have exited before the signal was requested.
+        if unsafe { libc::getppid() } != parent {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        Ok(())
+    };
+    // The hook only makes system calls, which is safe between fork and exec.
+    unsafe {
+        command.pre_exec(hook);
+    }
 }
 
+/// Other platforms have no way to kill the process if bp is killed, so it is only killed when
+/// bp exits normally.
+#[cfg(not(target_os = "linux"))]
+fn kill_on_exit(_: &mut Command) {}
+
 #[derive(Debug, StructOpt)]
 pub struct Opts {
-    /// The command to run
-    #[structopt(name = "COMMAND", required = true, parse(from_os_str))]
-    pub args: Vec<OsString>,
-    /// The file to pipe the command to, relative to workdir
+    #[structopt(flatten)]
+    pub command: CommandOpts,
+    /// Run the stages listed in the given TOML file in order, recording their combined output
+    /// under a baseline keyed by the pipeline's name
+    #[structopt(
+        name = "PIPELINE",
+        long = "pipeline",
+        conflicts_with = "COMMAND",
+        parse(from_os_str)
+    )]
+    pub pipeline: Option<PathBuf>,
+    /// The file to pipe the command to. A relative path is resolved against the command's
+    /// working directory (see '--workdir'), unless '--cwd-relative-output' is given. With '-',
+    /// the output is written to stdout unmodified, and messages and the progress bar are
+    /// written to stderr instead (see '--progress-fd')
     #[structopt(name = "OUTPUT", long = "output", short = "o", parse(from_os_str))]
     pub output: Option<PathBuf>,
+    /// Resolve a relative path given by '--output' against the directory bp is run from,
+    /// instead of the command's working directory
+    #[structopt(long = "cwd-relative-output", requires = "OUTPUT")]
+    pub cwd_relative_output: bool,
+    /// Append to the file given by '--output' instead of truncating it, starting each run with
+    /// a header line giving the time it started
+    #[structopt(long = "append-output", requires = "OUTPUT")]
+    pub append_output: bool,
+    /// Keep the log of each run in the 'logs' directory inside the data directory, named
+    /// using the given template, instead of overwriting a single log file. The template may
+    /// contain '{hash}' (the name of the data directory), '{timestamp}' (when the run started,
+    /// e.g. 20240131T120000Z), '{pid}' (the process ID of bp) and '{exit}' (the exit code, or
+    /// 'running' until the run finishes), e.g. "{timestamp}-{exit}.log"
+    #[structopt(
+        name = "TEMPLATE",
+        long = "output-template",
+        raw(conflicts_with_all = r#"&["OUTPUT", "read_only"]"#)
+    )]
+    pub output_template: Option<String>,
+    /// The number of logs to keep with '--output-template', 10 by default. The oldest logs are
+    /// removed when a run finishes. 0 keeps every log
+    #[structopt(name = "KEEP_LOGS", long = "keep-logs", requires = "TEMPLATE")]
+    pub keep_logs: Option<usize>,
+    /// Kill the command if it runs for longer than the given duration (e.g. "10m 30s")
+    #[structopt(
+        name = "TIMEOUT",
+        long = "timeout",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    pub timeout: Option<Duration>,
+    /// Kill the command if it runs for longer than the given multiple of its expected duration,
+    /// e.g. 2 to kill it at twice the expected duration. Unlike '--timeout', the limit adapts to
+    /// how long the command takes on each machine. Has no effect until a run has been recorded
+    #[structopt(
+        name = "FACTOR",
+        long = "overtime-factor",
+        raw(validator = "validate_overtime_factor")
+    )]
+    pub overtime_factor: Option<f64>,
+    /// Run the command again, up to the given number of times, if it fails. Only the output
+    /// of the final attempt is recorded
+    #[structopt(name = "RETRY", long = "retry", default_value = "0")]
+    pub retry: u32,
+    /// The time to wait before retrying a failed command (e.g. "5s")
+    #[structopt(
+        name = "RETRY_DELAY",
+        long = "retry-delay",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    pub retry_delay: Option<Duration>,
+    /// Warn if the command produces no output for the given duration (e.g. "5m")
+    #[structopt(
+        name = "STALL_WARNING",
+        long = "stall-warning",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    pub stall_warning: Option<Duration>,
+    /// Log a message each time the estimated progress passes a multiple of '--progress-step'.
+    /// This is enabled automatically if the progress bar can't be shown because the output is
+    /// not a terminal
+    #[structopt(long = "verbose-progress")]
+    pub verbose_progress: bool,
+    /// The percentage between messages logged by '--verbose-progress'
+    #[structopt(
+        name = "PROGRESS_STEP",
+        long = "progress-step",
+        default_value = "10",
+        raw(validator = "validate_progress_step")
+    )]
+    pub progress_step: u64,
+    /// Which of the command's output streams to write to the log file
+    #[structopt(
+        name = "CAPTURE",
+        long = "capture",
+        default_value = "both",
+        raw(possible_values = r#"&["stdout", "stderr", "both"]"#)
+    )]
+    pub capture: output::Streams,
+    /// Prefix each line of the command's output in the log and on the terminal with the time
+    /// it was output at: 'elapsed' for the time since the command started, matching the time
+    /// recorded in the baseline, or 'wallclock' for the time of day in UTC. Output printed by
+    /// '--tee' is left unmodified
+    #[structopt(
+        name = "TIMESTAMPS",
+        long = "timestamps",
+        raw(possible_values = r#"&["elapsed", "wallclock"]"#)
+    )]
+    pub timestamps: Option<output::Timestamps>,
+    /// Which of the command's output streams to print to the terminal
+    #[structopt(
+        name = "SHOW",
+        long = "show",
+        default_value = "both",
+        raw(possible_values = r#"&["stdout", "stderr", "both"]"#)
+    )]
+    pub show: output::Streams,
+    /// What to do if the data directory for the command records a different command, because
+    /// their hashes collide. With 'isolate', the command is recorded in a separate directory
+    #[structopt(
+        name = "ON_COLLISION",
+        long = "on-collision",
+        default_value = "isolate",
+        raw(possible_values = r#"&["warn", "error", "isolate"]"#)
+    )]
+    pub on_collision: output::OnCollision,
+    /// The maximum number of lines to record, overriding the config. Longer output is sampled
+    /// down to evenly spaced lines
+    #[structopt(name = "MAX_LINES", long = "max-lines")]
+    pub max_lines: Option<usize>,
+    /// Don't record runs which finish in less than the given duration (e.g. "2s"), overriding
+    /// the config
+    #[structopt(
+        name = "MIN_RECORD_DURATION",
+        long = "min-record-duration",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    pub min_record_duration: Option<Duration>,
+    /// Don't record runs which output less than half as many lines, or took less than half as
+    /// long, as is typical for the baseline, overriding the config
+    #[structopt(long = "no-degrade", conflicts_with = "force_record")]
+    pub no_degrade: bool,
+    /// Record the run even if it would degrade the baseline, overriding '--no-degrade' and the
+    /// config
+    #[structopt(long = "force-record")]
+    pub force_record: bool,
+    /// Match lines which aren't in the baseline against the recorded line sharing the longest
+    /// prefix with them, overriding the config
+    #[structopt(long = "prefix-match")]
+    pub prefix_match: bool,
+    /// Ignore leading whitespace when matching lines against previous runs, overriding the
+    /// config
+    #[structopt(long = "trim-indent")]
+    pub trim_indent: bool,
+    /// Record failed runs in a separate baseline, used to show progress until a run succeeds,
+    /// overriding the config
+    #[structopt(long = "record-failures")]
+    pub record_failures: bool,
+    /// Ring the terminal bell and show a desktop notification when the command finishes, if it
+    /// ran for at least '--notify-after', overriding the config. The bell isn't rung with
+    /// '--quiet'
+    #[structopt(long = "notify")]
+    pub notify: bool,
+    /// The minimum duration (e.g. "1m") of runs which '--notify' alerts for, overriding the
+    /// config. Defaults to 30 seconds
+    #[structopt(
+        name = "NOTIFY_AFTER",
+        long = "notify-after",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    pub notify_after: Option<Duration>,
+    /// The number of milliseconds between updates of the progress bar, overriding the config.
+    /// Must be at least 20
+    #[structopt(
+        name = "MS",
+        long = "refresh-interval",
+        raw(validator = "validate_refresh_interval")
+    )]
+    pub refresh_interval: Option<u64>,
+    /// How the position of the progress bar is estimated, overriding the config. 'time' uses
+    /// the recorded timings of the output; 'lines' uses the fraction of recorded lines seen,
+    /// which isn't skewed by running on a faster or slower machine, but assumes every line takes
+    /// about as long; 'hybrid' averages the two
+    #[structopt(
+        name = "PROGRESS_MODE",
+        long = "progress-mode",
+        raw(possible_values = r#"&["time", "lines", "hybrid"]"#)
+    )]
+    pub progress_mode: Option<ProgressMode>,
+    /// Split lines of output longer than the given number of bytes, so a command which writes
+    /// a lot of output without a newline doesn't use unbounded memory
+    #[structopt(
+        name = "MAX_LINE_BYTES",
+        long = "max-line-bytes",
+        default_value = "4194304"
+    )]
+    pub max_line_bytes: usize,
+    /// Only split the command's output on newlines. By default a carriage return also ends a
+    /// line, so progress can be tracked for tools which redraw a status line using them
+    #[structopt(long = "no-split-cr")]
+    pub no_split_cr: bool,
+    /// Discard any previously recorded output for the command before running it
+    #[structopt(long = "reset")]
+    pub reset: bool,
+    /// Read the command's stdin from the given file, relative to workdir, instead of inheriting
+    /// it
+    #[structopt(
+        name = "STDIN",
+        long = "stdin",
+        conflicts_with = "no_stdin",
+        parse(from_os_str)
+    )]
+    pub stdin: Option<PathBuf>,
+    /// Connect the command's stdin to the null device, so it never waits for input
+    #[structopt(long = "no-stdin")]
+    pub no_stdin: bool,
+    /// Read timings from the given baseline file, relative to workdir, instead of the one
+    /// recorded for the command. This allows a baseline to be shared between machines
+    #[structopt(name = "BASELINE", long = "baseline", parse(from_os_str))]
+    pub baseline: Option<PathBuf>,
+    /// Update the file given by '--baseline' with the timings of this run
+    #[structopt(long = "update-baseline", requires = "BASELINE")]
+    pub update_baseline: bool,
+    /// Compare the run against the incremental baseline, which is recorded separately for runs
+    /// that skip most of the command's work. By default, a run is treated as incremental if it
+    /// recognizes fewer than a quarter of the lines recorded in the first tenth of the full
+    /// baseline
+    #[structopt(
+        long = "incremental",
+        raw(conflicts_with_all = r#"&["full", "BASELINE"]"#)
+    )]
+    incremental: bool,
+    /// Compare the run against the full baseline, even if it looks like an incremental run
+    #[structopt(long = "full")]
+    full: bool,
+    /// Print the resolved command, its data directory and recorded baseline, without running it
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+    /// Log the command before running it, as a line which can be pasted into a shell to run it
+    /// the same way, including its working directory and environment variables. Secret
+    /// variables are read from the shell's environment instead
+    #[structopt(long = "print-command")]
+    pub print_command: bool,
+    /// Wait for other runs of the same command to finish, instead of failing
+    #[structopt(long = "wait", conflicts_with = "read_only")]
+    pub wait: bool,
+    /// The maximum time to wait with '--wait' (e.g. "5m")
+    #[structopt(
+        name = "WAIT_TIMEOUT",
+        long = "wait-timeout",
+        requires = "wait",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    pub wait_timeout: Option<Duration>,
+    /// Show progress using the recorded output, but don't record this run. This can be used
+    /// while another run of the same command is in progress. The output is only logged if
+    /// '--output' is given
+    #[structopt(long = "read-only", conflicts_with = "reset")]
+    pub read_only: bool,
+    /// Show progress using the recorded output, but don't record this run, so a run on an
+    /// unusually slow or fast machine doesn't skew the timings. Unlike '--read-only', the
+    /// baseline is locked while the command runs, so it is never read while half written. The
+    /// output is only logged if '--output' is given. Other runs using '--no-record' can run at
+    /// the same time, but runs which record their output can't
+    #[structopt(
+        long = "no-record",
+        raw(conflicts_with_all = r#"&["read_only", "reset", "wait"]"#)
+    )]
+    pub no_record: bool,
+    /// If the data directory isn't writable, store the data for this run in a temporary
+    /// directory instead of failing. Progress is shown using whatever was recorded there, which
+    /// may be lost when temporary files are cleaned up
+    #[structopt(long = "allow-temp-data", conflicts_with = "read_only")]
+    pub allow_temp_data: bool,
+    /// Write the command's output to stdout and stderr unmodified, instead of logging it. The
+    /// output is logged line by line above the progress bar by default, which suits most
+    /// tools; this is better for tools which redraw their own output, or write binary data
+    #[structopt(long = "tee", overrides_with = "no_tee")]
+    tee: bool,
+    /// Log the command's output (the default)
+    #[structopt(long = "no-tee", overrides_with = "tee")]
+    no_tee: bool,
+    /// Record the run without showing progress, implying '--tee'. The command's output is
+    /// passed through unmodified and informational messages are hidden, but warnings and errors
+    /// are still logged. With '--quiet', only the summary is printed, and with '--silent',
+    /// nothing is
+    #[structopt(
+        long = "record-only",
+        raw(conflicts_with_all = r#"&["read_only", "no_tee"]"#)
+    )]
+    pub record_only: bool,
+}
+
+impl Opts {
+    pub fn tee(&self) -> bool {
+        self.record_only || (self.tee && !self.no_tee)
+    }
+
+    /// Whether the command's output is written to stdout with '--output -'.
+    pub fn output_is_stdout(&self) -> bool {
+        self.output.as_deref() == Some(Path::new(output::STDOUT_PATH))
+    }
+
+    /// Resolve the path given by '--output' against the command's working directory, or the
+    /// current directory with '--cwd-relative-output'.
+    pub fn output_path(&self, path: &Path, workdir: &Path) -> Result<PathBuf> {
+        if self.cwd_relative_output {
+            Ok(env::current_dir()
+                .context("failed to get current directory")?
+                .join(path))
+        } else {
+            Ok(workdir.join(path))
+        }
+    }
+
+    /// Get the stdin to pass to the command. By default it inherits our stdin.
+    fn stdin(&self, cmd: &CommandOptions) -> Result<Stdio> {
+        if let Some(path) = &self.stdin {
+            let path = cmd.workdir.join(path);
+            let file = File::open(&path)
+                .with_context(|_| format!("failed to open file '{}'", path.display()))?;
+            Ok(file.into())
+        } else if self.no_stdin {
+            Ok(Stdio::null())
+        } else {
+            Ok(Stdio::inherit())
+        }
+    }
+
+    /// Get how the command's output should be split into lines.
+    pub fn line_options(&self) -> LineOptions {
+        LineOptions {
+            max_len: self.max_line_bytes,
+            split_cr: !self.no_split_cr,
+        }
+    }
+
+    /// Get the baseline chosen with '--incremental' or '--full', if any.
+    pub fn forced_baseline(&self) -> Option<diff::Baseline> {
+        if self.incremental {
+            Some(diff::Baseline::Incremental)
+        } else if self.full {
+            Some(diff::Baseline::Full)
+        } else {
+            None
+        }
+    }
+
+    /// Get how the recorded output for the command should be accessed.
+    pub fn access(&self) -> diff::Access {
+        if self.read_only {
+            diff::Access::ReadOnly
+        } else if self.no_record {
+            diff::Access::Shared
+        } else if self.wait {
+            diff::Access::Wait(self.wait_timeout)
+        } else {
+            diff::Access::Exclusive
+        }
+    }
+}
+
+/// Options which identify a command, and so determine where its data is stored.
+#[derive(Debug, StructOpt)]
+pub struct CommandOpts {
+    /// The command to run
+    #[structopt(
+        name = "COMMAND",
+        raw(required_unless_one = r#"&["PIPELINE", "PROFILE"]"#),
+        parse(from_os_str)
+    )]
+    pub args: Vec<OsString>,
+    /// Run a command defined in the config file. If a command is also given, it replaces the
+    /// profile's arguments
+    #[structopt(name = "PROFILE", long = "profile")]
+    pub profile: Option<String>,
+    /// Record the command's output separately from runs with a different tag, e.g. to keep
+    /// separate baselines for cold and warm caches. Runs without a tag are recorded as before
+    #[structopt(name = "TAG", long = "tag")]
+    pub tag: Option<String>,
+    /// Set an environment variable for the command, as KEY=VALUE. If no value is given
+    /// (i.e. just KEY), the variable is inherited from the current environment
+    #[structopt(
+        name = "ENV",
+        long = "env",
+        short = "e",
+        number_of_values = 1,
+        parse(try_from_str = "parse_env")
+    )]
+    pub env: Vec<(String, Option<OsString>)>,
+    /// Set environment variables for the command from a file of KEY=VALUE lines, such as a
+    /// `.env` file. Can be given multiple times, with later files overriding earlier ones, and
+    /// '--env' overriding both. Since the variables are part of the command's identity,
+    /// changing the file starts a new baseline
+    #[structopt(
+        name = "ENV_FILE",
+        long = "env-file",
+        number_of_values = 1,
+        parse(from_os_str)
+    )]
+    pub env_file: Vec<PathBuf>,
+    /// Run the command in the given directory instead of the current directory
+    #[structopt(name = "WORKDIR", long = "workdir", short = "C", parse(from_os_str))]
+    pub workdir: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub data_dir: DataDirOpts,
+    /// Run the command through the platform shell (`cmd /C` on Windows, `$SHELL -c` elsewhere)
+    #[structopt(long = "shell")]
+    pub shell: bool,
+    /// Which of the command's output streams to use to estimate progress. Choosing the stream
+    /// a tool reports progress on avoids errors when the two streams interleave differently
+    /// between runs: e.g. cargo reports progress on stderr, while make and ninja use stdout.
+    /// Commands using a single stream are recorded separately from those using both
+    #[structopt(
+        name = "PROGRESS_FROM",
+        long = "progress-from",
+        default_value = "both",
+        raw(possible_values = r#"&["stdout", "stderr", "both"]"#)
+    )]
+    pub progress_from: output::Streams,
+    /// Equivalent to '--progress-from stdout'
+    #[structopt(long = "stdout-progress-only", conflicts_with = "PROGRESS_FROM")]
+    pub stdout_progress_only: bool,
+}
+
+impl CommandOpts {
+    /// Get the output streams used to estimate progress.
+    pub fn progress_from(&self) -> output::Streams {
+        if self.stdout_progress_only {
+            output::Streams::Stdout
+        } else {
+            self.progress_from
+        }
+    }
+
+    /// Get the profile given by '--profile', if any.
+    fn profile<'c>(&self, config: &'c Config) -> Result<Option<&'c Profile>> {
+        let name = match &self.profile {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        match config.profiles.get(name) {
+            Some(profile) => Ok(Some(profile)),
+            None if config.profiles.is_empty() => {
+                bail!("unknown profile '{}', no profiles are defined", name)
+            }
+            None => {
+                let names: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+                bail!(
+                    "unknown profile '{}', available profiles are: {}",
+                    name,
+                    names.join(", ")
+                )
+            }
+        }
+    }
+
+    /// Get the directory to run the command in.
+    fn workdir(&self, profile: Option<&Profile>) -> Result<PathBuf> {
+        let cwd = env::current_dir().context("failed to get current directory")?;
+        let workdir = self
+            .workdir
+            .as_ref()
+            .or_else(|| profile.and_then(|profile| profile.workdir.as_ref()));
+        let dir = match workdir {
+            Some(dir) => util::normalize_path(&cwd.join(dir)),
+            None => return Ok(cwd),
+        };
+
+        let meta = fs::metadata(&dir)
+            .with_context(|_| format!("failed to access working directory '{}'", dir.display()))?;
+        if !meta.is_dir() {
+            bail!("working directory '{}' is not a directory", dir.display());
+        }
+        Ok(dir)
+    }
+
+    /// Get the directory where data for the given command is stored. If the directory named
+    /// by its hash records a different command, the directory it is isolated in is used.
+    pub fn data_dir(&self, cmd: &CommandOptions, config: &Config) -> Result<PathBuf> {
+        self.data_dir.dir_for(cmd, config)
+    }
+
+    /// Get the directory named by the hash of the given command, before checking for a hash
+    /// collision.
+    pub fn hash_dir(&self, cmd: &CommandOptions, config: &Config) -> Result<PathBuf> {
+        Ok(self.data_dir.base_for(cmd, config)?.join(cmd.hash()))
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DataDirOpts {
+    /// The directory to store command output in. Defaults to the user's data directory
+    #[structopt(
+        name = "DATA_DIR",
+        long = "data-dir",
+        raw(env_os = r#"OsStr::new("BUILD_PROGRESS_DATA_DIR")"#),
+        hide_env_values = true,
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
+}
+
+impl DataDirOpts {
+    /// Get the directory where data for the given command is stored, resolving hash collisions
+    /// the same way as a run with the default '--on-collision isolate'.
+    pub fn dir_for(&self, cmd: &CommandOptions, config: &Config) -> Result<PathBuf> {
+        let dir = self.base_for(cmd, config)?.join(cmd.hash());
+        output::prepare_dir(
+            &dir,
+            cmd,
+            diff::Access::ReadOnly,
+            output::OnCollision::Isolate,
+        )
+    }
+
+    /// Get the directory containing the data directory for the given command. Unless
+    /// '--data-dir' is given, the first rule in the config which matches the command is used.
+    pub fn base_for(&self, cmd: &CommandOptions, config: &Config) -> Result<PathBuf> {
+        if self.data_dir.is_none() {
+            for rule in &config.data_dir_rules {
+                if rule_matches(rule, cmd) {
+                    let workdir = cmd.workdir.to_string_lossy();
+                    let dir = cmd.workdir.join(rule.dir.replace("{workdir}", &workdir));
+                    log::debug!("using data directory '{}' from config rule", dir.display());
+                    return Ok(dir);
+                }
+            }
+        }
+        self.base()
+    }
+
+    /// Get the directory containing the data directories for all commands, ignoring any rules
+    /// in the config.
+    pub fn base(&self) -> Result<PathBuf> {
+        if let Some(dir) = &self.data_dir {
+            Ok(dir.clone())
+        } else if let Some(dir) = dirs::data_dir() {
+            Ok(dir.join(env!("CARGO_PKG_NAME")))
+        } else {
+            bail!("failed to get user's data directory, use '--data-dir' to set it explicitly");
+        }
+    }
+}
+
+/// Check whether a rule from the config applies to the command. A rule without any conditions
+/// matches every command.
+fn rule_matches(rule: &DataDirRule, cmd: &CommandOptions) -> bool {
+    if let Some(workdir) = &rule.workdir {
+        if !cmd.workdir.starts_with(workdir) {
+            return false;
+        }
+    }
+    if let Some(regex) = &rule.command {
+        if !regex.is_match(&cmd.to_string()) {
+            return false;
+        }
+    }
+    true
+}
+
+/// How the output of a command is split into lines.
+#[derive(Debug, Copy, Clone)]
+pub struct LineOptions {
+    /// The maximum length of a line. Longer lines are split.
+    pub max_len: usize,
+    /// Whether a carriage return ends a line, as well as a newline.
+    pub split_cr: bool,
+}
+
+impl Default for LineOptions {
+    fn default() -> Self {
+        LineOptions {
+            max_len: MAX_LINE_BYTES,
+            split_cr: true,
+        }
+    }
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Exit {
+    Status(ExitStatus),
+    Timeout,
+    /// The process was killed by `--overtime-factor`, having been expected to take the given
+    /// duration.
+    Overtime(Duration),
+    /// The process was interrupted with Ctrl-C.
+    Interrupted,
+}
+
+impl Exit {
+    fn is_interrupted(&self) -> bool {
+        matches!(self, Exit::Interrupted)
+    }
+}
+
+/// Get the exit code to return for a process which exited with `status`. On Unix, a process
+/// killed by a signal has no exit code, so `128 + signal` is returned, matching shells.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    1
+}
+
+/// Describe how an unsuccessful process exited, for example "terminated by SIGSEGV".
+pub fn describe_exit(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return match signal_name(signal) {
+                Some(name) => format!("terminated by {}", name),
+                None => format!("terminated by signal {}", signal),
+            };
+        }
+    }
+    format!("exited unsuccessfully ({})", status)
+}
+
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    Some(match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct CommandOptions<'a> {
     #[serde(with = "serde_args")]
     pub args: Cow<'a, [OsString]>,
     pub workdir: PathBuf,
+    #[serde(default)]
+    pub shell: bool,
+    /// The output streams used to estimate progress.
+    #[serde(default, skip_serializing_if = "output::Streams::is_both")]
+    pub progress_from: output::Streams,
+    /// A name given with `--tag` to record the command separately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    // Tables must come after values when serialized to TOML.
     #[serde(with = "serde_env")]
     pub env: BTreeMap<String, OsString>,
+    /// The names of variables in `env` whose values are replaced by a hash when stored.
+    #[serde(skip)]
+    pub secret_env: BTreeSet<String>,
+    /// The names of variables in `env` which only identify the command, and are removed from
+    /// its environment when it is run.
+    #[serde(skip)]
+    pub unset_env: BTreeSet<String>,
 }
 
 impl<'a> CommandOptions<'a> {
-    fn new(opts: &'a Opts, config: Config) -> Result<Self> {
-        debug_assert!(!opts.args.is_empty());
+    pub fn new(opts: &'a CommandOpts, config: &Config) -> Result<Self> {
+        if !opts.args.is_empty() {
+            return CommandOptions::with_args(Cow::Borrowed(&opts.args), opts, config);
+        }
 
-        let env = config
-            .env
-            .into_iter()
-            .filter_map(|key| env::var_os(&key).map(|val| (key, val)))
-            .collect();
+        let args: Vec<OsString> = match (&opts.profile, opts.profile(config)?) {
+            (Some(name), Some(profile)) => {
+                if profile.args.is_empty() {
+                    bail!("profile '{}' has no args", name);
+                }
+                profile.args.iter().map(OsString::from).collect()
+            }
+            _ => bail!("no command given"),
+        };
+        CommandOptions::with_args(Cow::Owned(args), opts, config)
+    }
+
+    /// Create the options for running `args` in `workdir`, without a shell or any additional
+    /// environment variables.
+    pub fn from_args(args: Vec<OsString>, workdir: PathBuf) -> CommandOptions<'static> {
+        CommandOptions {
+            args: Cow::Owned(args),
+            workdir,
+            shell: false,
+            progress_from: output::Streams::Both,
+            tag: None,
+            env: BTreeMap::new(),
+            secret_env: BTreeSet::new(),
+            unset_env: BTreeSet::new(),
+        }
+    }
 
+    /// Create the options for running `args` in the current directory, with the environment
+    /// variables given in the config.
+    pub fn from_config(args: Vec<OsString>, config: &Config) -> Result<CommandOptions<'static>> {
+        let (env, unset_env) = config_env(config)?;
         Ok(CommandOptions {
-            args: Cow::Borrowed(&opts.args),
+            args: Cow::Owned(args),
             workdir: env::current_dir().context("failed to get current directory")?,
+            shell: false,
+            progress_from: output::Streams::Both,
+            tag: None,
             env,
+            secret_env: config.secret_env.iter().cloned().collect(),
+            unset_env,
         })
     }
 
+    /// Create the options for running the given arguments, using the environment, shell and
+    /// profile given on the command line.
+    pub fn with_args(
+        args: Cow<'a, [OsString]>,
+        opts: &CommandOpts,
+        config: &Config,
+    ) -> Result<Self> {
+        debug_assert!(!args.is_empty());
+
+        let profile = opts.profile(config)?;
+        let (mut env, mut unset_env) = config_env(config)?;
+        // Variables given explicitly are always passed to the command.
+        let mut set = |key: String, val: OsString| {
+            unset_env.remove(&key);
+            env.insert(key, val);
+        };
+        if let Some(profile) = profile {
+            for (key, val) in &profile.env {
+                set(key.clone(), OsString::from(val));
+            }
+        }
+        for path in &opts.env_file {
+            for (key, val) in read_env_file(path)? {
+                set(key, OsString::from(val));
+            }
+        }
+        for (key, val) in &opts.env {
+            match val {
+                Some(val) => set(key.clone(), val.clone()),
+                None => {
+                    if let Some(val) = env::var_os(key) {
+                        set(key.clone(), val);
+                    }
+                }
+            }
+        }
+
+        Ok(CommandOptions {
+            args,
+            workdir: opts.workdir(profile)?,
+            shell: opts.shell,
+            progress_from: opts.progress_from(),
+            tag: opts.tag.clone(),
+            env,
+            secret_env: config.secret_env.iter().cloned().collect(),
+            unset_env,
+        })
+    }
+
+    /// Get the name of the directory where data for this command is stored. The name is
+    /// prefixed with the version of the hashing scheme, so directories created by older
+    /// versions can be identified and removed with `bp gc`.
     pub fn hash(&self) -> String {
-        hash(self)
+        format!("{}{}", hash::PREFIX, hash::hash(self))
+    }
+
+    /// Get the environment variables as they are stored in the data directory, with the values
+    /// of secret variables replaced by a hash. Different secret values still give different
+    /// hashes, so they are recorded separately.
+    pub fn stored_env(&self) -> Cow<'_, BTreeMap<String, OsString>> {
+        if !self.env.keys().any(|key| self.secret_env.contains(key)) {
+            return Cow::Borrowed(&self.env);
+        }
+
+        let mut env = self.env.clone();
+        for (key, val) in env.iter_mut() {
+            if self.secret_env.contains(key) {
+                *val = format!("<redacted:sha256:{}>", hash::hash(&*val)).into();
+            }
+        }
+        Cow::Owned(env)
+    }
+
+    /// Get a copy of the options as they are stored in the data directory, which doesn't
+    /// contain the values of secret environment variables.
+    pub fn to_stored(&self) -> CommandOptions<'static> {
+        CommandOptions {
+            args: Cow::Owned(self.args.to_vec()),
+            workdir: self.workdir.clone(),
+            shell: self.shell,
+            progress_from: self.progress_from,
+            tag: self.tag.clone(),
+            env: self.stored_env().into_owned(),
+            secret_env: BTreeSet::new(),
+            unset_env: BTreeSet::new(),
+        }
+    }
+
+    /// Get the program and arguments which are run, including the shell with '--shell'.
+    fn program_args(&self) -> Cow<'_, [OsString]> {
+        if self.shell {
+            Cow::Owned(shell_args(&self.args))
+        } else {
+            Cow::Borrowed(&self.args)
+        }
     }
 
-    fn spawn<O, E>(
+    /// Get a line which runs the command the same way when pasted into a POSIX shell, with its
+    /// working directory and environment variables. The values of secret variables are read
+    /// from the shell's environment rather than included. Arguments which aren't valid unicode
+    /// are converted lossily.
+    pub fn to_shell_line(&self) -> String {
+        let mut words = vec![
+            "cd".to_owned(),
+            shell_quote(&self.workdir.to_string_lossy()).into_owned(),
+            "&&".to_owned(),
+        ];
+        if !self.env.is_empty() {
+            words.push("env".to_owned());
+        }
+        for key in &self.unset_env {
+            words.push(format!("-u {}", shell_quote(key)));
+        }
+        for (key, val) in &self.env {
+            if self.unset_env.contains(key) {
+                continue;
+            }
+            let val = if self.secret_env.contains(key) {
+                format!("\"${}\"", key)
+            } else {
+                shell_quote(&val.to_string_lossy()).into_owned()
+            };
+            words.push(format!("{}={}", shell_quote(key), val));
+        }
+        for arg in self.program_args().iter() {
+            words.push(shell_quote(&arg.to_string_lossy()).into_owned());
+        }
+        words.join(" ")
+    }
+
+    /// Get the bytes which are hashed to identify this command.
+    pub fn hash_inputs(&self) -> Vec<u8> {
+        hash::inputs(self)
+    }
+
+    /// Run the command, passing each line of its stdout and stderr to `out` and `err`. The
+    /// process is killed if the returned future is dropped before it exits, and on Linux, if bp
+    /// exits without dropping it.
+    pub fn spawn<O, E>(
         &self,
+        stdin: Stdio,
+        line_opts: LineOptions,
         out: O,
         err: E,
-    ) -> Result<impl Future<Item = ExitStatus, Error = io::Error>>
+    ) -> Result<(u32, impl Future<Item = ExitStatus, Error = io::Error>)>
     where
         O: FnMut(Vec<u8>) -> io::Result<()>,
         E: FnMut(Vec<u8>) -> io::Result<()>,
     {
-        let mut child = Command::new(&self.args[0])
-            .args(&self.args[1..])
+        let args = self.program_args();
+        let mut command = Command::new(&args[0]);
+        command.args(&args[1..]);
+        for key in &self.unset_env {
+            command.env_remove(key);
+        }
+        kill_on_exit(&mut command);
+        let mut child = command
+            .current_dir(&self.workdir)
+            .envs(
+                self.env
+                    .iter()
+                    .filter(|(key, _)| !self.unset_env.contains(*key)),
+            )
+            .stdin(stdin)
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn_async()
             .with_context(|_| format!("failed to execute process '{}'", self))?;
-        let stdout = lines(child.stdout().take().unwrap()).for_each(out);
-        let stderr = lines(child.stderr().take().unwrap()).for_each(err);
-        Ok(child.join3(stdout, stderr).map(|(status, (), ())| status))
+        let stdout = lines(child.stdout().take().unwrap(), line_opts).for_each(out);
+        let stderr = lines(child.stderr().take().unwrap(), line_opts).for_each(err);
+        let pid = child.id();
+        Ok((
+            pid,
+            child.join3(stdout, stderr).map(|(status, (), ())| status),
+        ))
+    }
+}
+
+impl<'a> Hash for CommandOptions<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // OS strings are hashed in their canonical form, which matches how the standard
+        // library hashes them on Unix, so the hashes of existing commands are unchanged there.
+        self.args.len().hash(state);
+        for arg in self.args.iter() {
+            os_str::hash(arg, state);
+        }
+        self.workdir.hash(state);
+        self.shell.hash(state);
+        // Defaults are not hashed, so data recorded before the options were added is found.
+        if !self.progress_from.is_both() {
+            self.progress_from.hash(state);
+        }
+        if let Some(tag) = &self.tag {
+            tag.hash(state);
+        }
+        let env = self.stored_env();
+        env.len().hash(state);
+        for (key, val) in env.iter() {
+            key.hash(state);
+            os_str::hash(val, state);
+        }
     }
 }
 
@@ -143,16 +1316,36 @@ impl<'a> fmt::Display for CommandOptions<'a> {
     }
 }
 
+/// A stream of lines, including their terminators. The final line is yielded at EOF even if
+/// it has no trailing newline. Line terminators are ignored when matching lines against the
+/// baseline, so a partial final line matches the same line recorded with a newline.
 struct Lines<R> {
     rdr: R,
+    line: Vec<u8>,
+    max_len: usize,
+    split_cr: bool,
+    /// Whether `line` ends with a carriage return, and the next byte must be checked for a
+    /// newline which ends the same line.
+    pending_cr: bool,
 }
 
-fn lines<R>(rdr: R) -> Lines<BufReader<R>>
+fn lines<R>(rdr: R, opts: LineOptions) -> Lines<BufReader<R>>
 where
     R: AsyncRead,
 {
     Lines {
         rdr: BufReader::new(rdr),
+        line: Vec::new(),
+        max_len: opts.max_len.max(1),
+        split_cr: opts.split_cr,
+        pending_cr: false,
+    }
+}
+
+impl<R> Lines<R> {
+    fn take_line(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        self.pending_cr = false;
+        Ok(Some(mem::take(&mut self.line)).into())
     }
 }
 
@@ -164,76 +1357,681 @@ where
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
-        let mut line = Vec::new();
-        let n = try_nb!(self.rdr.read_until(b'\n', &mut line));
-        if n == 0 && line.len() == 0 {
-            return Ok(None.into());
+        // If the read would block partway through a line, the bytes read so far are kept in
+        // `self.line` until the rest of the line is available.
+        loop {
+            let (used, done) = {
+                let buf = try_nb!(self.rdr.fill_buf());
+                if buf.is_empty() {
+                    if self.line.is_empty() {
+                        return Ok(None.into());
+                    }
+                    return self.take_line();
+                }
+
+                let (used, done) = if self.pending_cr {
+                    // A CRLF sequence is a single line ending.
+                    (if buf[0] == b'\n' { 1 } else { 0 }, true)
+                } else {
+                    // A line which is split exactly at the limit keeps its line ending, rather
+                    // than producing an empty line.
+                    let remaining = self.max_len - self.line.len();
+                    let end = buf.len().min(remaining + 1);
+                    let split_cr = self.split_cr;
+                    match buf[..end]
+                        .iter()
+                        .position(|&b| b == b'\n' || (split_cr && b == b'\r'))
+                    {
+                        // The next byte isn't available yet, so it can't be checked for a
+                        // newline.
+                        Some(idx) if buf[idx] == b'\r' && idx + 1 == buf.len() => {
+                            self.pending_cr = true;
+                            (idx + 1, false)
+                        }
+                        Some(idx) if buf[idx] == b'\r' && buf[idx + 1] == b'\n' => (idx + 2, true),
+                        Some(idx) => (idx + 1, true),
+                        None => {
+                            let used = buf.len().min(remaining);
+                            (used, self.line.len() + used >= self.max_len)
+                        }
+                    }
+                };
+                self.line.extend_from_slice(&buf[..used]);
+                (used, done)
+            };
+            self.rdr.consume(used);
+            if done {
+                return self.take_line();
+            }
+        }
+    }
+}
+
+/// Get the arguments which run `args` as a script in the platform shell.
+fn shell_args(args: &[OsString]) -> Vec<OsString> {
+    let mut script = OsString::new();
+    for (idx, arg) in args.iter().enumerate() {
+        if idx != 0 {
+            script.push(" ");
         }
-        Ok(Some(line).into())
+        script.push(arg);
+    }
+
+    if cfg!(windows) {
+        vec!["cmd".into(), "/C".into(), script]
+    } else {
+        let shell = env::var_os("SHELL").unwrap_or_else(|| OsString::from("/bin/sh"));
+        vec![shell, "-c".into(), script]
+    }
+}
+
+/// Quote a string so that a POSIX shell reads it as a single word.
+fn shell_quote(s: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:,+@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(format!("'{}'", s.replace('\'', r"'\''")))
     }
 }
 
+/// Get the environment variables from the config which identify the command, and the names of
+/// those which are removed from its environment. See `Config::pass_env` and `Config::hash_env`.
+fn config_env(config: &Config) -> Result<(BTreeMap<String, OsString>, BTreeSet<String>)> {
+    config_env_in(config, &env::vars_os().collect())
+}
+
+/// Get the variables from the config as `config_env` does, reading their values from `vars`
+/// rather than the environment of this process.
+fn config_env_in(
+    config: &Config,
+    vars: &BTreeMap<OsString, OsString>,
+) -> Result<(BTreeMap<String, OsString>, BTreeSet<String>)> {
+    let mut env = expand_env(&config.env, vars)?;
+    let pass_env = expand_env(&config.pass_env, vars)?;
+    let hash_env = expand_env(&config.hash_env, vars)?;
+
+    let unset_env = hash_env
+        .keys()
+        .filter(|key| !env.contains_key(*key) && !pass_env.contains_key(*key))
+        .cloned()
+        .collect();
+    env.retain(|key, _| !pass_env.contains_key(key));
+    env.extend(hash_env);
+    Ok((env, unset_env))
+}
+
+/// Get the values of the variables in `vars` named in the config. Entries may be literal
+/// names, glob patterns such as `CARGO_*`, or regular expressions prefixed with `re:`.
+fn expand_env(
+    patterns: &[String],
+    vars: &BTreeMap<OsString, OsString>,
+) -> Result<BTreeMap<String, OsString>> {
+    let mut literals = Vec::new();
+    let mut regexes = Vec::new();
+    for pattern in patterns {
+        if let Some(regex) = pattern.strip_prefix("re:") {
+            regexes.push(format!("^(?:{})$", regex));
+        } else if pattern.contains(&['*', '?'][..]) {
+            regexes.push(glob_to_regex(pattern));
+        } else {
+            literals.push(pattern);
+        }
+    }
+
+    let mut env: BTreeMap<String, OsString> = literals
+        .into_iter()
+        .filter_map(|key| {
+            let val = vars.get(OsStr::new(key))?;
+            Some((key.clone(), val.clone()))
+        })
+        .collect();
+    if !regexes.is_empty() {
+        let set = RegexSet::new(&regexes).context("invalid environment variable pattern")?;
+        for (key, val) in vars {
+            if let Some(key) = key.to_str() {
+                if set.is_match(key) {
+                    env.insert(key.to_owned(), val.clone());
+                }
+            }
+        }
+    }
+    Ok(env)
+}
+
+/// Convert a glob pattern, in which `*` and `?` match any characters, to an anchored regular
+/// expression.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut literal = String::new();
+    for c in glob.chars() {
+        match c {
+            '*' | '?' => {
+                regex.push_str(&regex::escape(&literal));
+                literal.clear();
+                regex.push_str(if c == '*' { ".*" } else { "." });
+            }
+            c => literal.push(c),
+        }
+    }
+    regex.push_str(&regex::escape(&literal));
+    regex.push('$');
+    regex
+}
+
+fn validate_overtime_factor(s: String) -> std::result::Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor >= 1.0 => Ok(()),
+        _ => Err("must be a number of at least 1".to_owned()),
+    }
+}
+
+fn validate_refresh_interval(s: String) -> std::result::Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(ms) if ms >= MIN_REFRESH_INTERVAL.as_millis() as u64 => Ok(()),
+        _ => Err(format!(
+            "must be a whole number of at least {}",
+            MIN_REFRESH_INTERVAL.as_millis()
+        )),
+    }
+}
+
+fn validate_progress_step(s: String) -> std::result::Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(1..=100) => Ok(()),
+        _ => Err("must be a whole number between 1 and 100".to_owned()),
+    }
+}
+
+fn parse_env(s: &str) -> std::result::Result<(String, Option<OsString>), String> {
+    let (key, val) = match s.find('=') {
+        Some(idx) => (&s[..idx], Some(OsString::from(&s[idx + 1..]))),
+        None => (s, None),
+    };
+    if key.is_empty() {
+        return Err(format!("invalid environment variable '{}': empty name", s));
+    }
+    Ok((key.to_owned(), val))
+}
+
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|_| format!("failed to open env file '{}'", path.display()))?;
+    Ok(parse_env_file(&contents)
+        .with_context(|_| format!("failed to read env file '{}'", path.display()))?)
+}
+
+/// Parse the variables in a dotenv file. Each line is `KEY=VALUE`, optionally prefixed with
+/// `export`. Values may be double quoted, which allows escapes such as `\n`, or single quoted,
+/// which are taken literally. Blank lines and lines starting with `#` are skipped, as are
+/// comments after an unquoted value.
+fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let (key, val) = match line.find('=') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim_start()),
+            None => bail!("expected KEY=VALUE on line {}", idx + 1),
+        };
+        if key.is_empty() {
+            bail!("empty variable name on line {}", idx + 1);
+        }
+        let val = match parse_env_value(val) {
+            Some(val) => val,
+            None => bail!("unterminated quoted value on line {}", idx + 1),
+        };
+        vars.push((key.to_owned(), val));
+    }
+    Ok(vars)
+}
+
+/// Parse a value in a dotenv file, returning `None` if a quote is not closed.
+fn parse_env_value(val: &str) -> Option<String> {
+    let mut chars = val.chars();
+    match chars.next() {
+        Some('"') => {
+            let mut result = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => return Some(result),
+                    '\\' => match chars.next()? {
+                        'n' => result.push('\n'),
+                        'r' => result.push('\r'),
+                        't' => result.push('\t'),
+                        ch => result.push(ch),
+                    },
+                    ch => result.push(ch),
+                }
+            }
+        }
+        Some('\'') => {
+            let end = val[1..].find('\'')?;
+            Some(val[1..1 + end].to_owned())
+        }
+        _ => {
+            let val = match val.find(" #") {
+                Some(idx) => &val[..idx],
+                None => val,
+            };
+            Some(val.trim_end().to_owned())
+        }
+    }
+}
+
+fn select<A, B>(a: A, b: B) -> impl Future<Item = A::Item, Error = A::Error>
+where
+    A: Future,
+    B: Future<Item = A::Item, Error = A::Error>,
+{
+    a.select(b).map(|(item, _)| item).map_err(|(err, _)| err)
+}
+
 fn map_err<A, R>(mut f: impl FnMut(A) -> Result<R>) -> impl FnMut(A) -> io::Result<R> {
     move |a| f(a).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
 }
 
 mod serde_args {
-    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use std::borrow::Cow;
     use std::ffi::OsString;
 
+    use crate::os_str;
+
     pub(super) fn serialize<S>(val: &Cow<'_, [OsString]>, ser: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        use serde::ser::{Error, SerializeSeq};
-
-        let mut ser = ser.serialize_seq(Some(val.len()))?;
-        for s in val.iter() {
-            if let Some(s) = s.to_str() {
-                ser.serialize_element(s)?;
-            } else {
-                return Err(S::Error::custom("unpaired surrogate in string"))
-            }
-        }
-        ser.end()
+        let stored: Vec<String> = val.iter().map(|s| os_str::to_stored(s)).collect();
+        stored.serialize(ser)
     }
 
     pub(super) fn deserialize<'de, D>(de: D) -> Result<Cow<'static, [OsString]>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        Vec::<String>::deserialize(de).map(|s| s.into_iter().map(OsString::from).collect())
+        use serde::de::Error;
+
+        Vec::<String>::deserialize(de)?
+            .into_iter()
+            .map(|s| os_str::from_stored(s).map_err(D::Error::custom))
+            .collect()
     }
 }
 
 mod serde_env {
-    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use std::collections::BTreeMap;
     use std::ffi::OsString;
 
+    use crate::os_str;
+
     pub(super) fn serialize<S>(val: &BTreeMap<String, OsString>, ser: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        use serde::ser::{Error, SerializeMap};
-
-        let mut ser = ser.serialize_map(Some(val.len()))?;
-        for (k, s) in val.iter() {
-            if let Some(s) = s.to_str() {
-                ser.serialize_entry(k, s)?;
-            } else {
-                return Err(S::Error::custom("unpaired surrogate in string"))
-            }
-        }
-        ser.end()
+        let stored: BTreeMap<&str, String> = val
+            .iter()
+            .map(|(k, s)| (k.as_str(), os_str::to_stored(s)))
+            .collect();
+        stored.serialize(ser)
     }
 
     pub(super) fn deserialize<'de, D>(de: D) -> Result<BTreeMap<String, OsString>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        BTreeMap::<String, String>::deserialize(de)
-            .map(|s| s.into_iter().map(|(k, s)| (k, OsString::from(s))).collect())
+        use serde::de::Error;
+
+        BTreeMap::<String, String>::deserialize(de)?
+            .into_iter()
+            .map(|(k, s)| Ok((k, os_str::from_stored(s).map_err(D::Error::custom)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::normalize::Normalizer;
+
+    #[test]
+    fn secret_env_is_redacted() {
+        let command = |token: &str| {
+            let mut command = CommandOptions::from_args(
+                vec!["cargo".into(), "publish".into()],
+                PathBuf::from("/src"),
+            );
+            command
+                .env
+                .insert("CARGO_TOKEN".to_owned(), OsString::from(token));
+            command
+                .env
+                .insert("RUSTFLAGS".to_owned(), OsString::from("-Dwarnings"));
+            command.secret_env.insert("CARGO_TOKEN".to_owned());
+            command
+        };
+
+        let stored = toml::to_string_pretty(&command("hunter2").to_stored()).unwrap();
+        assert!(!stored.contains("hunter2"));
+
+        let read: CommandOptions = toml::from_str(&stored).unwrap();
+        assert_eq!(read.env["RUSTFLAGS"], "-Dwarnings");
+        assert!(read.env["CARGO_TOKEN"]
+            .to_str()
+            .unwrap()
+            .starts_with("<redacted:sha256:"));
+        assert_eq!(read, command("hunter2").to_stored());
+        assert_ne!(read, command("swordfish").to_stored());
+        assert_ne!(command("hunter2").hash(), command("swordfish").hash());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_env_is_stored() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut command = CommandOptions::from_args(
+            vec![OsStr::from_bytes(b"caf\xe9").to_owned()],
+            PathBuf::from("/src"),
+        );
+        command
+            .env
+            .insert("NAME".to_owned(), OsStr::from_bytes(b"\xff").to_owned());
+        let hash = command.hash();
+
+        let stored = toml::to_string_pretty(&command).unwrap();
+        let read: CommandOptions = toml::from_str(&stored).unwrap();
+        assert_eq!(read, command);
+        assert_eq!(read.hash(), hash);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn killed_by_signal() {
+        let mut command =
+            CommandOptions::from_args(vec!["kill -TERM $$".into()], env::current_dir().unwrap());
+        command.shell = true;
+
+        let (_, status_fut) = command
+            .spawn(
+                Stdio::null(),
+                LineOptions::default(),
+                |_| Ok(()),
+                |_| Ok(()),
+            )
+            .unwrap();
+        let status = Runtime::new().unwrap().block_on(status_fut).unwrap();
+        assert_eq!(exit_code(status), 128 + libc::SIGTERM);
+        assert_eq!(describe_exit(status), "terminated by SIGTERM");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn final_line_without_newline() {
+        let command = CommandOptions::from_args(
+            vec!["printf".into(), "first\\nsecond".into()],
+            env::current_dir().unwrap(),
+        );
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let (lines1, lines2) = (lines.clone(), lines.clone());
+        let (_, status_fut) = command
+            .spawn(
+                Stdio::null(),
+                LineOptions::default(),
+                move |line| {
+                    lines1.lock().unwrap().push(line);
+                    Ok(())
+                },
+                move |line| {
+                    lines2.lock().unwrap().push(line);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let status = Runtime::new().unwrap().block_on(status_fut).unwrap();
+        assert!(status.success());
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(*lines, vec![b"first\n".to_vec(), b"second".to_vec()]);
+        let normalizer = Normalizer::default();
+        assert_eq!(normalizer.key(&lines[1]), normalizer.key(b"second\n"));
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut data = vec![b'a'; 3 * 1024 + 100];
+        data.extend_from_slice(b"\nend\n");
+
+        let lines = lines_of(&data, 1024);
+        assert_eq!(
+            lines.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![1024, 1024, 1024, 101, 4]
+        );
+        assert_eq!(lines.concat(), data);
+
+        // A newline directly after the limit stays with its line.
+        let lines = lines_of(b"aaaa\nbb\n", 4);
+        assert_eq!(lines, vec![b"aaaa\n".to_vec(), b"bb\n".to_vec()]);
+    }
+
+    #[test]
+    fn carriage_returns_split_lines() {
+        let lines = lines_of(b"10%\r50%\r100%\r\ndone\n", 1024);
+        assert_eq!(
+            lines,
+            vec![
+                b"10%\r".to_vec(),
+                b"50%\r".to_vec(),
+                b"100%\r\n".to_vec(),
+                b"done\n".to_vec()
+            ]
+        );
+    }
+
+    fn lines_of(data: &[u8], max_len: usize) -> Vec<Vec<u8>> {
+        let opts = LineOptions {
+            max_len,
+            split_cr: true,
+        };
+        lines(io::Cursor::new(data.to_vec()), opts)
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stdin_from_file() {
+        let command = CommandOptions::from_args(vec!["cat".into()], env::current_dir().unwrap());
+
+        let path = env::temp_dir().join(format!("bp-stdin-{}", std::process::id()));
+        fs::write(&path, "first\nsecond\n").unwrap();
+        let stdin = File::open(&path).unwrap();
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines1 = lines.clone();
+        let (_, status_fut) = command
+            .spawn(
+                stdin.into(),
+                LineOptions::default(),
+                move |line| {
+                    lines1.lock().unwrap().push(line);
+                    Ok(())
+                },
+                |_| Ok(()),
+            )
+            .unwrap();
+        let status = Runtime::new().unwrap().block_on(status_fut);
+        fs::remove_file(&path).unwrap();
+        assert!(status.unwrap().success());
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(*lines, vec![b"first\n".to_vec(), b"second\n".to_vec()]);
+    }
+
+    #[test]
+    fn env_file_quoting() {
+        let vars = parse_env_file(
+            r#"
+# comment
+export PLAIN=value # trailing comment
+EMPTY=
+DOUBLE="two words # not a comment"
+ESCAPED="line\n\"quoted\""
+SINGLE='$literal \n'
+  SPACED = padded
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("PLAIN".to_owned(), "value".to_owned()),
+                ("EMPTY".to_owned(), "".to_owned()),
+                ("DOUBLE".to_owned(), "two words # not a comment".to_owned()),
+                ("ESCAPED".to_owned(), "line\n\"quoted\"".to_owned()),
+                ("SINGLE".to_owned(), "$literal \\n".to_owned()),
+                ("SPACED".to_owned(), "padded".to_owned()),
+            ]
+        );
+
+        assert!(parse_env_file("NO_VALUE\n").is_err());
+        assert!(parse_env_file("=value\n").is_err());
+        assert!(parse_env_file("OPEN=\"unterminated\n").is_err());
+    }
+
+    #[test]
+    fn env_file_precedence() {
+        let dir = env::temp_dir().join(format!("bp-env-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.env");
+        let second = dir.join("second.env");
+        fs::write(&first, "A=first\nB=first\nC=first\n").unwrap();
+        fs::write(&second, "B=second\nC=second\n").unwrap();
+
+        let opts = CommandOpts::from_iter(&[
+            OsStr::new("bp"),
+            OsStr::new("--env-file"),
+            first.as_os_str(),
+            OsStr::new("--env-file"),
+            second.as_os_str(),
+            OsStr::new("--env"),
+            OsStr::new("C=cli"),
+            OsStr::new("true"),
+        ]);
+        let command = CommandOptions::new(&opts, &Config::default());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let env = command.unwrap().env;
+        assert_eq!(env["A"], "first");
+        assert_eq!(env["B"], "second");
+        assert_eq!(env["C"], "cli");
+    }
+
+    #[test]
+    fn pass_env_and_hash_env() {
+        let vars = [
+            "BP_SPLIT_BOTH",
+            "BP_SPLIT_TERM",
+            "BP_SPLIT_HASH",
+            "BP_SPLIT_ALIAS",
+        ]
+        .iter()
+        .map(|name| (OsString::from(name), OsString::from("1")))
+        .collect();
+        let config = Config {
+            env: vec!["BP_SPLIT_*".to_owned()],
+            pass_env: vec!["BP_SPLIT_TERM".to_owned(), "BP_SPLIT_ALIAS".to_owned()],
+            hash_env: vec!["BP_SPLIT_HASH".to_owned(), "BP_SPLIT_ALIAS".to_owned()],
+            ..Config::default()
+        };
+        let (env, unset_env) = config_env_in(&config, &vars).unwrap();
+        let hashed: Vec<&str> = env.keys().map(String::as_str).collect();
+        assert_eq!(hashed, ["BP_SPLIT_ALIAS", "BP_SPLIT_BOTH", "BP_SPLIT_HASH"]);
+        // Variables also matched by `env` are still passed to the command.
+        assert!(unset_env.is_empty());
+
+        let config = Config {
+            hash_env: vec!["BP_SPLIT_HASH".to_owned()],
+            ..Config::default()
+        };
+        let (env, unset_env) = config_env_in(&config, &vars).unwrap();
+        assert!(env.contains_key("BP_SPLIT_HASH"));
+        assert!(unset_env.contains("BP_SPLIT_HASH"));
+    }
+
+    #[test]
+    fn shell_line_is_quoted() {
+        let mut command = CommandOptions::from_args(
+            vec!["printf".into(), "%s\\n".into(), "it's here".into()],
+            PathBuf::from("/tmp/my dir"),
+        );
+        command.env.insert("PLAIN".to_owned(), "a=b".into());
+        command.env.insert("TOKEN".to_owned(), "hunter2".into());
+        command.env.insert("HIDDEN".to_owned(), "x".into());
+        command.secret_env.insert("TOKEN".to_owned());
+        command.unset_env.insert("HIDDEN".to_owned());
+        assert_eq!(
+            command.to_shell_line(),
+            r#"cd '/tmp/my dir' && env -u HIDDEN PLAIN='a=b' TOKEN="$TOKEN" printf '%s\n' 'it'\''s here'"#
+        );
+    }
+
+    #[test]
+    fn data_dir_rule_precedence() {
+        let rule = |workdir: Option<&str>, command: Option<&str>, dir: &str| DataDirRule {
+            workdir: workdir.map(PathBuf::from),
+            command: command.map(|pattern| regex::Regex::new(pattern).unwrap()),
+            dir: dir.to_owned(),
+        };
+        let config = Config {
+            data_dir_rules: vec![
+                rule(Some("/src/project"), Some("^cargo test"), "/data/test"),
+                rule(Some("/src/project"), None, "/data/project"),
+                rule(None, None, "/data/other"),
+            ],
+            ..Config::default()
+        };
+        let command = |args: &[&str], workdir: &str| {
+            CommandOptions::from_args(
+                args.iter().map(OsString::from).collect(),
+                PathBuf::from(workdir),
+            )
+        };
+        let base_for = |opts: &DataDirOpts, args: &[&str], workdir: &str| {
+            opts.base_for(&command(args, workdir), &config).unwrap()
+        };
+
+        let opts = DataDirOpts { data_dir: None };
+        assert_eq!(
+            base_for(&opts, &["cargo", "test"], "/src/project/sub"),
+            Path::new("/data/test")
+        );
+        assert_eq!(
+            base_for(&opts, &["cargo", "build"], "/src/project"),
+            Path::new("/data/project")
+        );
+        // Workdirs only match whole path components.
+        assert_eq!(
+            base_for(&opts, &["cargo", "test"], "/src/project2"),
+            Path::new("/data/other")
+        );
+
+        let opts = DataDirOpts {
+            data_dir: Some(PathBuf::from("/data/flag")),
+        };
+        assert_eq!(
+            base_for(&opts, &["cargo", "test"], "/src/project"),
+            Path::new("/data/flag")
+        );
     }
 }
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use failure::bail;
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::cmd::{self, CommandOptions};
+use crate::config::Config;
+use crate::diff::{self, OutputData, Stream};
+use crate::normalize::Normalizer;
+use crate::Result;
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub data_dir: cmd::DataDirOpts,
+    /// The data directory of the first command, or its name within the data directory. If a
+    /// command is given, the tag of the first run to compare
+    #[structopt(name = "A", parse(from_os_str))]
+    pub a: PathBuf,
+    /// The data directory of the second command, or its name within the data directory. If a
+    /// command is given, the tag of the second run to compare
+    #[structopt(name = "B", parse(from_os_str))]
+    pub b: PathBuf,
+    /// Compare runs of the given command recorded with the tags A and B. The command is run in
+    /// the current directory with the environment variables from the config; for other
+    /// options, get the data directories to compare with `bp path`
+    #[structopt(name = "COMMAND", raw(last = "true"), parse(from_os_str))]
+    pub command: Vec<OsString>,
+    /// Show the timing of every line, as well as the summary. Lines are shown as they were
+    /// recorded, which is after normalization: ANSI escape sequences are removed, and text
+    /// matching a 'normalize' pattern in the config is replaced with a NUL character
+    #[structopt(long = "verbose")]
+    pub verbose: bool,
+    /// Print the comparison as JSON
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// A comparison of the most recent runs recorded in two data directories.
+#[derive(Debug, Serialize)]
+struct Comparison {
+    a_total_ms: u64,
+    b_total_ms: u64,
+    delta_ms: i64,
+    /// The number of lines output by both runs.
+    common: usize,
+    /// The number of lines only output by the second run.
+    added: usize,
+    /// The number of lines only output by the first run.
+    removed: usize,
+    /// Every line from either run, with `--verbose`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lines: Option<Vec<LineDiff>>,
+}
+
+#[derive(Debug, Serialize)]
+struct LineDiff {
+    status: Status,
+    stream: Stream,
+    /// The line as recorded, after normalization.
+    line: String,
+    a_ms: Option<u64>,
+    b_ms: Option<u64>,
+    delta_ms: Option<i64>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Common,
+    Added,
+    Removed,
+}
+
+/// Compare the most recent runs recorded for two commands, showing which lines were added or
+/// removed and how the timing of the others changed.
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    let (a, b) = if opts.command.is_empty() {
+        let base = opts.data_dir.base()?;
+        (resolve(&base, &opts.a)?, resolve(&base, &opts.b)?)
+    } else {
+        let mut command = CommandOptions::from_config(opts.command.clone(), &config)?;
+        command.tag = Some(opts.a.to_string_lossy().into_owned());
+        let a = opts.data_dir.dir_for(&command, &config)?;
+        command.tag = Some(opts.b.to_string_lossy().into_owned());
+        let b = opts.data_dir.dir_for(&command, &config)?;
+        (a, b)
+    };
+    let a = load_latest(&a)?;
+    let b = load_latest(&b)?;
+
+    let normalizer = Normalizer::from_config(&config)?;
+    let comparison = compare(&a, &b, &normalizer, opts.verbose);
+
+    if opts.json {
+        json::to_writer_pretty(io::stdout(), &comparison)?;
+        println!();
+        return Ok(0);
+    }
+
+    if let Some(lines) = &comparison.lines {
+        println!(
+            "{:<8} {:>10} {:>10} {:>10}  {:<6}  line",
+            "status", "a", "b", "delta", "stream"
+        );
+        for line in lines {
+            println!(
+                "{:<8} {:>10} {:>10} {:>10}  {:<6}  {}",
+                format!("{:?}", line.status).to_lowercase(),
+                line.a_ms.map(fmt_millis).unwrap_or_else(|| "-".to_owned()),
+                line.b_ms.map(fmt_millis).unwrap_or_else(|| "-".to_owned()),
+                line.delta_ms
+                    .map(fmt_delta)
+                    .unwrap_or_else(|| "-".to_owned()),
+                line.stream,
+                line.line
+            );
+        }
+        println!();
+    }
+    println!(
+        "total:    {} -> {} ({})",
+        fmt_millis(comparison.a_total_ms),
+        fmt_millis(comparison.b_total_ms),
+        fmt_delta(comparison.delta_ms)
+    );
+    println!(
+        "lines:    {} common, {} added, {} removed",
+        comparison.common, comparison.added, comparison.removed
+    );
+    Ok(0)
+}
+
+/// Get the data directory for an argument, which may be a path or the name of a directory
+/// within the base data directory.
+fn resolve(base: &Path, arg: &Path) -> Result<PathBuf> {
+    if arg.is_dir() {
+        return Ok(arg.to_owned());
+    }
+    let dir = base.join(arg);
+    if dir.is_dir() {
+        Ok(dir)
+    } else {
+        bail!("data directory '{}' does not exist", arg.display());
+    }
+}
+
+fn load_latest(dir: &Path) -> Result<OutputData> {
+    match diff::load(dir)?.pop_back() {
+        Some(run) => Ok(run),
+        None => bail!("no runs have been recorded in '{}'", dir.display()),
+    }
+}
+
+fn compare(a: &OutputData, b: &OutputData, normalizer: &Normalizer, verbose: bool) -> Comparison {
+    let a_lines: Vec<_> = a.timings(normalizer).collect();
+    let b_lines: Vec<_> = b.timings(normalizer).collect();
+    let a_map: HashMap<_, _> = a_lines.iter().map(|(key, dur)| (key, *dur)).collect();
+    let b_map: HashMap<_, _> = b_lines.iter().map(|(key, dur)| (key, *dur)).collect();
+
+    // Lines are listed in the order of the second run, followed by removed lines in the order
+    // of the first run.
+    let mut lines = Vec::new();
+    for (key, b_dur) in &b_lines {
+        let a_dur = a_map.get(key).cloned();
+        let status = if a_dur.is_some() {
+            Status::Common
+        } else {
+            Status::Added
+        };
+        lines.push(line_diff(status, key, a_dur, Some(*b_dur)));
+    }
+    for (key, a_dur) in &a_lines {
+        if !b_map.contains_key(key) {
+            lines.push(line_diff(Status::Removed, key, Some(*a_dur), None));
+        }
+    }
+
+    let count = |status| lines.iter().filter(|line| line.status == status).count();
+    Comparison {
+        a_total_ms: millis(a.total()),
+        b_total_ms: millis(b.total()),
+        delta_ms: millis(b.total()) as i64 - millis(a.total()) as i64,
+        common: count(Status::Common),
+        added: count(Status::Added),
+        removed: count(Status::Removed),
+        lines: if verbose { Some(lines) } else { None },
+    }
+}
+
+fn line_diff(
+    status: Status,
+    (stream, key): &(Stream, Vec<u8>),
+    a: Option<Duration>,
+    b: Option<Duration>,
+) -> LineDiff {
+    let a_ms = a.map(millis);
+    let b_ms = b.map(millis);
+    LineDiff {
+        status,
+        stream: *stream,
+        line: String::from_utf8_lossy(key).into_owned(),
+        a_ms,
+        b_ms,
+        delta_ms: match (a_ms, b_ms) {
+            (Some(a), Some(b)) => Some(b as i64 - a as i64),
+            _ => None,
+        },
+    }
+}
+
+fn millis(dur: Duration) -> u64 {
+    dur.as_millis() as u64
+}
+
+fn fmt_millis(ms: u64) -> String {
+    humantime::format_duration(Duration::from_millis(ms)).to_string()
+}
+
+fn fmt_delta(ms: i64) -> String {
+    let sign = if ms < 0 { '-' } else { '+' };
+    format!("{}{}", sign, fmt_millis(ms.unsigned_abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(lines: &[(&str, u64)], total_ms: u64) -> OutputData {
+        let dur = |ms: u64| json::json!({ "secs": ms / 1000, "nanos": ms % 1000 * 1_000_000 });
+        let lines: Vec<_> = lines
+            .iter()
+            .map(|&(line, ms)| json::json!({ "data": base64::encode(line), "dur": dur(ms) }))
+            .collect();
+        json::from_value(json::json!({ "lines": lines, "total": dur(total_ms) })).unwrap()
+    }
+
+    #[test]
+    fn compare_counts_lines() {
+        let a = run(&[("build", 100), ("test", 500), ("lint", 700)], 1000);
+        let b = run(&[("build", 300), ("test", 400), ("doc", 600)], 800);
+        let comparison = compare(&a, &b, &Normalizer::default(), true);
+
+        assert_eq!(comparison.a_total_ms, 1000);
+        assert_eq!(comparison.b_total_ms, 800);
+        assert_eq!(comparison.delta_ms, -200);
+        assert_eq!(comparison.common, 2);
+        assert_eq!(comparison.added, 1);
+        assert_eq!(comparison.removed, 1);
+
+        let lines = comparison.lines.unwrap();
+        let summary: Vec<_> = lines
+            .iter()
+            .map(|line| (line.status, line.line.as_str(), line.delta_ms))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Status::Common, "build", Some(200)),
+                (Status::Common, "test", Some(-100)),
+                (Status::Added, "doc", None),
+                (Status::Removed, "lint", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_omits_lines_unless_verbose() {
+        let a = run(&[("build", 100)], 100);
+        let b = run(&[("build", 100)], 250);
+        let comparison = compare(&a, &b, &Normalizer::default(), false);
+        assert_eq!(comparison.delta_ms, 150);
+        assert!(comparison.lines.is_none());
+        assert_eq!(fmt_delta(comparison.delta_ms), "+150ms");
+        assert_eq!(fmt_delta(-1500), "-1s 500ms");
+    }
+}
@@ -1,13 +1,17 @@
-use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
-use std::path::PathBuf;
-use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fmt, fs, io};
 
-use failure::{Fail, ResultExt};
+use failure::{bail, format_err, Fail, ResultExt};
+use regex::Regex;
+use serde::de::{self, Deserializer, Visitor};
 use serde::Deserialize;
 use structopt::StructOpt;
 
-use crate::Result;
+use crate::{util, Result};
 
 #[derive(Debug, StructOpt)]
 pub struct Opts {
@@ -23,47 +27,580 @@ pub struct Opts {
     config: Option<PathBuf>,
 }
 
-#[derive(Deserialize, Default, Debug)]
+/// The config file. String values, except for the names in `env`, `pass_env` and `hash_env`,
+/// may refer to environment variables as `${VAR}`, or `${VAR:-default}` to use `default` if
+/// `VAR` is unset or empty.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
 pub struct Config {
+    /// Environment variables which identify the command, so a run with different values is
+    /// recorded separately, and which are passed to it. Entries may be literal names, glob
+    /// patterns (e.g. `CARGO_*`) or regular expressions prefixed with `re:`.
     pub env: Vec<String>,
+    /// Environment variables which are passed to the command, but don't identify it, such as
+    /// `TERM`. These take precedence over `env`, so e.g. `env = ["CARGO_*"]` with
+    /// `pass_env = ["CARGO_TERM_*"]` records runs with different terminal settings together.
+    /// The command inherits the whole environment anyway, so this is only needed to exclude
+    /// variables matched by `env`. Entries take the same form as in `env`.
+    pub pass_env: Vec<String>,
+    /// Environment variables which identify the command, but are removed from its environment.
+    /// A variable listed here and in `pass_env` is treated as if it was listed in `env`.
+    /// Entries take the same form as in `env`.
+    pub hash_env: Vec<String>,
+    /// The names of environment variables whose values are secret, such as access tokens. The
+    /// values of these variables are still passed to the command and distinguish its recorded
+    /// output, but only a hash of them is stored in the data directory.
+    pub secret_env: Vec<String>,
+    /// The number of previous runs to blend timings over.
+    pub runs: usize,
+    /// Regular expressions matching volatile parts of lines, such as timestamps or temporary
+    /// paths, which are ignored when matching lines against previous runs.
+    pub normalize: Vec<String>,
+    /// Regular expressions matching lines which carry little information about progress, such
+    /// as per-file messages. Matching lines are still logged, but are not recorded in the
+    /// baseline or used to track progress.
+    pub ignore: Vec<String>,
+    /// The maximum number of lines to record for each run. Longer output is sampled down to
+    /// evenly spaced lines, which keeps the baseline small for very noisy commands, at the
+    /// cost of a less precise estimate, since progress is interpolated between the recorded
+    /// lines. Unlimited by default.
+    pub max_lines: Option<usize>,
+    /// Runs which finish in less than this duration (e.g. "2s") are not recorded, so quick
+    /// commands don't leave baselines behind. Existing baselines are still used. Every run is
+    /// recorded by default.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub min_record_duration: Duration,
+    /// Don't record runs which would degrade the baseline, because they output less than half
+    /// as many lines or took less than half as long as the median of the recorded runs. This
+    /// keeps atypical runs, such as incremental builds which aren't detected as such, from
+    /// pushing good runs out of the baseline. Disabled by default.
+    pub no_degrade: bool,
+    /// Match lines which aren't in the baseline against the recorded line on the same stream
+    /// which shares the longest prefix with them, if the prefix is at least half of the line.
+    /// This helps for lines with a volatile suffix, such as `Compiling foo v1.2.3 (/tmp/xyz)`,
+    /// without writing `normalize` patterns. A line which matches exactly is always preferred.
+    /// This is disabled by default, since it makes matching slower.
+    pub prefix_match: bool,
+    /// Ignore leading whitespace when matching lines against previous runs, for tools which
+    /// indent lines by context that can change between runs, such as nested test output. The
+    /// whitespace is still written to the log file. This is cheaper than `prefix_match` or
+    /// `normalize` patterns, and is disabled by default.
+    pub trim_indent: bool,
+    /// Record failed runs in a separate baseline, which is used to show progress until a run
+    /// of the command succeeds. The baseline of successful runs is never mixed with failed
+    /// runs. By default, failed runs are not recorded, unless no run has been recorded yet.
+    pub record_failures: bool,
+    /// Ring the terminal bell and show a desktop notification when a run finishes, if it took
+    /// at least `notify_after`. Desktop notifications are shown with `notify-send` on Linux and
+    /// AppleScript on macOS, and are skipped if those aren't available. The bell isn't rung
+    /// with `--quiet`.
+    pub notify: bool,
+    /// The minimum duration (e.g. "1m") of runs which `notify` alerts for, so quick commands
+    /// don't cause notifications. Defaults to 30 seconds.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub notify_after: Duration,
+    /// Options for drawing the progress bar.
+    pub progress: ProgressConfig,
+    /// Named commands, which can be run with `--profile`.
+    pub profiles: BTreeMap<String, Profile>,
+    /// Rules choosing where data is stored for particular commands, instead of the user's data
+    /// directory. Rules are checked in order, and the first which matches is used. Rules are
+    /// ignored if '--data-dir' is given, and don't apply to pipelines.
+    pub data_dir_rules: Vec<DataDirRule>,
+    /// Compress the output log and recorded baseline with gzip. Baselines are read correctly
+    /// whether or not they are compressed.
+    pub compress: bool,
+    /// Default values for command-line flags, keyed by their long name without the leading
+    /// dashes, e.g. `debug = true` or `data-dir = "/tmp/bp"`. Flags which can be repeated, such
+    /// as `env`, can be given a list, and values given on the command line are added to it. A
+    /// flag set to `false` is left unset.
+    ///
+    /// A flag given on the command line always takes precedence, even if it is set to its
+    /// built-in default, followed by its environment variable, then the value here, and finally
+    /// the built-in default. Defaults for flags which the subcommand being run does not accept
+    /// are ignored. The location of the config file itself can't be set here.
+    pub defaults: BTreeMap<String, toml::Value>,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            env: Vec::new(),
+            pass_env: Vec::new(),
+            hash_env: Vec::new(),
+            secret_env: Vec::new(),
+            runs: 5,
+            normalize: Vec::new(),
+            ignore: Vec::new(),
+            max_lines: None,
+            min_record_duration: Duration::from_secs(0),
+            no_degrade: false,
+            prefix_match: false,
+            trim_indent: false,
+            record_failures: false,
+            notify: false,
+            notify_after: Duration::from_secs(30),
+            progress: ProgressConfig::default(),
+            profiles: BTreeMap::new(),
+            data_dir_rules: Vec::new(),
+            compress: false,
+            defaults: BTreeMap::new(),
+        }
+    }
+}
+
+/// A command defined in the config file.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// The command to run.
+    pub args: Vec<String>,
+    /// Environment variables to set for the command, in addition to those given by `env`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// The directory to run the command in, relative to the current directory.
+    pub workdir: Option<PathBuf>,
+}
+
+/// A rule choosing where data is stored for the commands it matches. A rule matches a command
+/// if all of its conditions do, so a rule with no conditions matches every command.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DataDirRule {
+    /// Match commands run in this directory or any of its subdirectories. A relative path is
+    /// resolved against the directory containing the config file.
+    pub workdir: Option<PathBuf>,
+    /// Match commands whose arguments, joined with spaces, match this regular expression.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub command: Option<Regex>,
+    /// The directory to store data for matching commands in, which may contain `{workdir}`
+    /// to refer to the command's working directory. Relative paths are resolved against the
+    /// working directory, so `.build-progress` keeps the data next to the command.
+    pub dir: String,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct ProgressConfig {
+    /// A custom `indicatif` template for the progress bar. The ETA is available as `{prefix}`
+    /// and the expected total duration as `{msg}`.
+    pub template: Option<String>,
+    /// The characters used to draw the filled, current and empty parts of the bar.
+    pub progress_chars: String,
+    /// The width of the bar in the default template, or `auto` to fill the terminal and follow
+    /// it as it is resized. Custom templates control the width themselves, using e.g. `{bar:40}`
+    /// or `{wide_bar}`.
+    pub bar_width: BarWidth,
+    /// The weight given to the latest estimate each time the progress bar is updated, between
+    /// 0 and 1. Lower values make the bar glide more smoothly, but lag further behind the
+    /// estimate. 1 disables smoothing.
+    pub smoothing: f64,
+    /// How often the progress bar is updated, e.g. "500ms". A longer interval reduces flicker
+    /// on slow terminals or over SSH. Values below 20ms are rounded up.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub refresh_interval: Duration,
+    /// How the position of the progress bar is estimated: `time`, `lines` or `hybrid`.
+    pub mode: ProgressMode,
+}
+
+impl Default for ProgressConfig {
+    fn default() -> Self {
+        ProgressConfig {
+            template: None,
+            progress_chars: "=> ".to_owned(),
+            bar_width: BarWidth::Fixed(64),
+            smoothing: 0.5,
+            refresh_interval: Duration::from_millis(200),
+            mode: ProgressMode::Time,
+        }
+    }
+}
+
+/// How the position of the progress bar is estimated from the run's output.
+///
+/// Timings are skewed when a command runs on a faster or slower machine than it was recorded
+/// on, whereas counting lines is independent of machine speed, but assumes each line takes
+/// about as long as any other. Whichever mode is used, the position is measured in the
+/// baseline's duration, so the ETA and progress bar templates work the same way.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressMode {
+    /// Use the recorded time of the last recognized line, advancing with real time until the
+    /// next one.
+    Time,
+    /// Use the fraction of the baseline's lines which have been recognized.
+    Lines,
+    /// Use the average of the positions given by `time` and `lines`.
+    Hybrid,
+}
+
+impl FromStr for ProgressMode {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "time" => Ok(ProgressMode::Time),
+            "lines" => Ok(ProgressMode::Lines),
+            "hybrid" => Ok(ProgressMode::Hybrid),
+            _ => Err(format_err!("invalid progress mode '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum BarWidth {
+    Auto,
+    Fixed(u16),
+}
+
+impl<'de> Deserialize<'de> for BarWidth {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct BarWidthVisitor;
+
+        impl<'de> Visitor<'de> for BarWidthVisitor {
+            type Value = BarWidth;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a positive integer or \"auto\"")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> std::result::Result<BarWidth, E>
+            where
+                E: de::Error,
+            {
+                match value {
+                    1..=0xffff => Ok(BarWidth::Fixed(value as u16)),
+                    _ => Err(E::invalid_value(de::Unexpected::Signed(value), &self)),
+                }
+            }
+
+            fn visit_str<E>(self, value: &str) -> std::result::Result<BarWidth, E>
+            where
+                E: de::Error,
+            {
+                match value {
+                    "auto" => Ok(BarWidth::Auto),
+                    _ => Err(E::invalid_value(de::Unexpected::Str(value), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(BarWidthVisitor)
+    }
+}
+
+/// Deserialize a duration given in a human readable format, such as "1m 30s".
+fn deserialize_duration<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(de::Error::custom)
+}
+
+/// Deserialize a regular expression, so an invalid pattern is reported when the config is read.
+fn deserialize_regex<'de, D>(deserializer: D) -> std::result::Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    match Regex::new(&pattern) {
+        Ok(regex) => Ok(Some(regex)),
+        Err(err) => Err(de::Error::custom(format!(
+            "invalid regex '{}': {}",
+            pattern, err
+        ))),
+    }
+}
+
+/// The names of project-local config files, searched for in the current directory and its
+/// parents.
+const PROJECT_CONFIG_NAMES: &[&str] = &[".build-progress.toml", "build-progress.toml"];
+
 pub fn read(opts: &Opts) -> Result<Config> {
-    let (config_path, required) = if let Some(path) = &opts.config {
-        (Cow::Borrowed(&**path), true)
-    } else if let Some(dir) = dirs::config_dir() {
-        let path = dir
-            .join(env!("CARGO_PKG_NAME"))
-            .join("config")
-            .with_extension("toml");
-        (Cow::Owned(path), false)
+    // An explicit config file overrides both the global and project config. Otherwise the
+    // project config is merged on top of the global config.
+    let value = if let Some(path) = &opts.config {
+        read_file(path, true)?
     } else {
-        log::debug!("unable to resolve config path");
-        return Ok(Config::default());
+        let mut value = if let Some(dir) = dirs::config_dir() {
+            let path = dir
+                .join(env!("CARGO_PKG_NAME"))
+                .join("config")
+                .with_extension("toml");
+            read_file(&path, false)?
+        } else {
+            log::debug!("unable to resolve config path");
+            None
+        };
+
+        if let Some(path) = find_project_config()? {
+            if let Some(project) = read_file(&path, true)? {
+                value = Some(match value {
+                    Some(global) => merge(global, project),
+                    None => project,
+                });
+            }
+        }
+
+        value
     };
 
-    let config_file = match fs::read_to_string(&config_path) {
+    match value {
+        Some(value) => Ok(value.try_into().context("invalid config")?),
+        None => Ok(Config::default()),
+    }
+}
+
+fn read_file(path: &Path, required: bool) -> Result<Option<toml::Value>> {
+    let config_file = match fs::read_to_string(path) {
         Ok(file) => file,
         Err(ref err) if !required && err.kind() == io::ErrorKind::NotFound => {
-            log::debug!(
-                "failed to open config file '{}': {}",
-                config_path.display(),
-                err
-            );
-            return Ok(Config::default());
+            log::debug!("failed to open config file '{}': {}", path.display(), err);
+            return Ok(None);
         }
         Err(err) => {
             return Err(err
-                .context(format!(
-                    "failed to open config file '{}'",
-                    config_path.display()
-                ))
+                .context(format!("failed to open config file '{}'", path.display()))
                 .into());
         }
     };
 
-    log::debug!("reading config from file '{}'", config_path.display());
-    let config = toml::from_str(&config_file)
-        .with_context(|_| format!("failed to read TOML file '{}'", config_path.display()))?;
-    Ok(config)
+    log::debug!("reading config from file '{}'", path.display());
+    let mut value = toml::from_str(&config_file)
+        .with_context(|_| format!("failed to read TOML file '{}'", path.display()))?;
+    // Each file is expanded and resolved before merging, so relative paths are resolved
+    // against the directory of the file they were given in.
+    expand_config(&mut value)?;
+    let path = env::current_dir()
+        .context("failed to get current directory")?
+        .join(path);
+    if let Some(dir) = path.parent() {
+        resolve_rule_workdirs(&mut value, dir);
+    }
+    Ok(Some(value))
+}
+
+/// Resolve relative `workdir` conditions in `data_dir_rules` against `dir`, the directory
+/// containing the config file, so the rules match the same commands wherever bp is run from.
+fn resolve_rule_workdirs(value: &mut toml::Value, dir: &Path) {
+    let rules = match value
+        .get_mut("data_dir_rules")
+        .and_then(toml::Value::as_array_mut)
+    {
+        Some(rules) => rules,
+        None => return,
+    };
+    for rule in rules {
+        if let Some(toml::Value::String(workdir)) = rule.get_mut("workdir") {
+            let resolved = util::normalize_path(&dir.join(&*workdir));
+            *workdir = resolved.to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// Search the current directory and its parents for a project config file, stopping at the
+/// root of a git repository.
+fn find_project_config() -> Result<Option<PathBuf>> {
+    let cwd = env::current_dir().context("failed to get current directory")?;
+    for dir in cwd.ancestors() {
+        for name in PROJECT_CONFIG_NAMES {
+            let path = dir.join(name);
+            if path.is_file() {
+                return Ok(Some(path));
+            }
+        }
+
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Expand references to environment variables in the string values of the config. The `env`
+/// list is skipped, since it holds the names of variables rather than values.
+fn expand_config(value: &mut toml::Value) -> Result<()> {
+    if let toml::Value::Table(table) = value {
+        for (key, value) in table.iter_mut() {
+            if !["env", "pass_env", "hash_env"].contains(&key.as_str()) {
+                expand_value(value, key)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn expand_value(value: &mut toml::Value, key: &str) -> Result<()> {
+    match value {
+        toml::Value::String(string) => {
+            *string = expand(string, |name| env::var_os(name))
+                .with_context(|_| format!("failed to expand config value '{}'", key))?;
+        }
+        toml::Value::Array(array) => {
+            for value in array {
+                expand_value(value, key)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, value) in table.iter_mut() {
+                expand_value(value, &format!("{}.{}", key, name))?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in `string` with values from `lookup`. A `$` which
+/// isn't followed by `{` is left as it is.
+fn expand(string: &str, lookup: impl Fn(&str) -> Option<std::ffi::OsString>) -> Result<String> {
+    let mut result = String::with_capacity(string.len());
+    let mut rest = string;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("unterminated variable reference in '{}'", string),
+        };
+        let (name, default) = match rest[start + 2..end].find(":-") {
+            Some(idx) => (
+                &rest[start + 2..start + 2 + idx],
+                Some(&rest[start + 4 + idx..end]),
+            ),
+            None => (&rest[start + 2..end], None),
+        };
+        if name.is_empty() {
+            bail!("empty variable name in '{}'", string);
+        }
+
+        // As in the shell, the default is also used if the variable is set but empty.
+        let value = lookup(name).filter(|value| default.is_none() || !value.is_empty());
+        match (value, default) {
+            (Some(value), _) => match value.into_string() {
+                Ok(value) => result.push_str(&value),
+                Err(_) => bail!("environment variable '{}' is not valid unicode", name),
+            },
+            (None, Some(default)) => result.push_str(default),
+            (None, None) => bail!("environment variable '{}' is not defined", name),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Merge two TOML values, with keys in `over` taking precedence over keys in `base`.
+fn merge(base: toml::Value, over: toml::Value) -> toml::Value {
+    match (base, over) {
+        (toml::Value::Table(mut base), toml::Value::Table(over)) => {
+            for (key, over) in over {
+                let value = match base.remove(&key) {
+                    Some(base) => merge(base, over),
+                    None => over,
+                };
+                base.insert(key, value);
+            }
+            toml::Value::Table(base)
+        }
+        (_, over) => over,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<std::ffi::OsString> {
+        match name {
+            "HOME" => Some("/home/user".into()),
+            "EMPTY" => Some("".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand_defined() {
+        assert_eq!(
+            expand("${HOME}/.cache/bp", lookup).unwrap(),
+            "/home/user/.cache/bp"
+        );
+        assert_eq!(
+            expand("${HOME}${HOME}", lookup).unwrap(),
+            "/home/user/home/user"
+        );
+        assert_eq!(expand("${EMPTY}", lookup).unwrap(), "");
+        assert_eq!(expand("^a$|$HOME", lookup).unwrap(), "^a$|$HOME");
+    }
+
+    #[test]
+    fn expand_undefined() {
+        assert!(expand("${MISSING}/bp", lookup).is_err());
+        assert!(expand("${HOME", lookup).is_err());
+        assert!(expand("${}", lookup).is_err());
+    }
+
+    #[test]
+    fn expand_default() {
+        assert_eq!(expand("${MISSING:-/tmp}/bp", lookup).unwrap(), "/tmp/bp");
+        assert_eq!(expand("${EMPTY:-/tmp}", lookup).unwrap(), "/tmp");
+        assert_eq!(expand("${HOME:-/tmp}", lookup).unwrap(), "/home/user");
+        assert_eq!(expand("${MISSING:-}", lookup).unwrap(), "");
+    }
+
+    #[test]
+    fn expand_config_skips_env() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            env = ["${MISSING}"]
+            [profiles.test]
+            args = ["${MISSING:-cargo}", "test"]
+            "#,
+        )
+        .unwrap();
+        expand_config(&mut value).unwrap();
+        assert_eq!(value["env"][0].as_str(), Some("${MISSING}"));
+        assert_eq!(value["profiles"]["test"]["args"][0].as_str(), Some("cargo"));
+    }
+
+    #[test]
+    fn rule_workdirs_are_relative_to_config_file() {
+        let dir = env::temp_dir().join(format!("bp-config-rules-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            [[data_dir_rules]]
+            workdir = "sub/../project"
+            command = "^cargo "
+            dir = "target/bp"
+            "#,
+        )
+        .unwrap();
+        let config = read(&Opts { config: Some(path) });
+        fs::remove_dir_all(&dir).unwrap();
+
+        let config = config.unwrap();
+        let rule = &config.data_dir_rules[0];
+        assert_eq!(rule.workdir, Some(dir.join("project")));
+        assert!(rule.command.as_ref().unwrap().is_match("cargo build"));
+    }
+
+    #[test]
+    fn invalid_rule_regex_fails_to_load() {
+        let value: toml::Value = toml::from_str(
+            r#"
+            [[data_dir_rules]]
+            command = "cargo ("
+            dir = "target/bp"
+            "#,
+        )
+        .unwrap();
+        let err = value.try_into::<Config>().unwrap_err();
+        assert!(err.to_string().contains("invalid regex 'cargo ('"));
+    }
 }
@@ -1,142 +1,1083 @@
 use std::collections::hash_map::{Entry, HashMap};
-use std::fmt;
-use std::fs::File;
-use std::io::{prelude::*, BufReader, SeekFrom};
-use std::mem::replace;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, BufWriter};
 use std::path::{Path, PathBuf};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, iter, mem, thread};
 
 use failure::{bail, Fail, ResultExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use fs2::{self, FileExt};
+use indicatif::HumanDuration;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::util::{open_or_create, FileEntry};
+use crate::config::{Config, ProgressMode};
+use crate::history;
+use crate::normalize::Normalizer;
+use crate::util::{open_or_create, replace_file, truncate_millis, with_suffix};
 use crate::Result;
 
 pub struct Writer {
-    file: File,
+    /// The lock file for the baseline, which is locked for as long as the writer exists. The
+    /// baseline itself is replaced when it is written, so it can't be locked directly. This is
+    /// `None` if the baseline is not being updated.
+    lock_file: Option<File>,
+    /// Whether the run is recorded when it finishes. This is false for shared access, which
+    /// locks the baseline only to read it.
+    record: bool,
     path: PathBuf,
+    /// The file to record the run's duration in, or `None` for read-only writers.
+    history_path: Option<PathBuf>,
     orig: Option<OrigOutput>,
+    /// The baseline for incremental runs, or `None` if they are not recorded separately, as for
+    /// a baseline file given by `--baseline`.
+    incremental_path: Option<PathBuf>,
+    incremental: Option<OrigOutput>,
+    /// The baseline of failed runs, or `None` if they are not recorded separately.
+    failed_path: Option<PathBuf>,
+    /// The baseline of failed runs, which is only read if no run has succeeded yet.
+    failed: Option<OrigOutput>,
+    /// The baseline chosen by the user, if any.
+    forced: Option<Baseline>,
+    /// The baseline the run is compared against, once it is known.
+    selected: Option<Baseline>,
     curr: CurrOutput,
+    runs: usize,
+    max_lines: Option<usize>,
+    min_record_duration: Duration,
+    no_degrade: bool,
+    compress: bool,
+    normalizer: Normalizer,
+    mode: ProgressMode,
+    smoother: Smoother,
 }
 
+/// Exponential smoothing of the estimated position, so the progress bar glides rather than
+/// jumping when lines are recognized.
+#[derive(Debug)]
+struct Smoother {
+    alpha: f64,
+    value: Duration,
+}
+
+/// How the baseline file is accessed. Only one process may update the baseline for a command at
+/// a time, so it is locked exclusively unless it is only being read.
+#[derive(Debug, Copy, Clone)]
+pub enum Access {
+    /// Lock the baseline, failing if another process has it locked.
+    Exclusive,
+    /// Lock the baseline, waiting for any other process to release it first. If a timeout is
+    /// given, fail if the lock is not acquired within it.
+    Wait(Option<Duration>),
+    /// Read the baseline without locking it, and never update it. A baseline which is being
+    /// rewritten by another process at the same time is ignored.
+    ReadOnly,
+    /// Lock the baseline for reading, so it can't be rewritten while the run is in progress,
+    /// but never update it. Other processes may read the baseline at the same time, but fail
+    /// to record a run until the lock is released.
+    Shared,
+}
+
+/// The interval at which to retry taking the lock when waiting for another process.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Which of a command's baselines a run is compared against, and recorded in.
+///
+/// Commands such as incremental builds do much less work when little has changed, so a full
+/// baseline would wildly overestimate their duration. Runs like this are recorded in a separate
+/// baseline. Unless the user chooses one, the full baseline is used until a tenth of its
+/// duration has passed. If by then the run has recognized fewer than a quarter of the lines
+/// recorded in that time, or has already finished, it is treated as incremental from then on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Baseline {
+    Full,
+    Incremental,
+}
+
+impl fmt::Display for Baseline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Baseline::Full => write!(f, "full"),
+            Baseline::Incremental => write!(f, "incremental"),
+        }
+    }
+}
+
+/// The fraction of the full baseline's duration after which the baseline is selected.
+const SELECT_WINDOW: u32 = 10;
+/// A run is incremental if it recognizes fewer than one in this many of the lines expected
+/// before the baseline is selected.
+const SELECT_RATIO: usize = 4;
+
 impl Writer {
-    pub fn new(dir: &Path) -> Result<Self> {
+    pub fn new(dir: &Path, config: &Config, access: Access) -> Result<Self> {
         let path = dir.join("orig").with_extension("json");
-        log::debug!("opening or creating output file '{}'", path.display());
-
-        let (file, _) = open_or_create(&path)?;
-        match file.as_ref().try_lock_exclusive() {
-            Ok(()) => (),
-            Err(ref err) if err.kind() == fs2::lock_contended_error().kind() => bail!(
-                "file '{}' is being accessed by another process",
-                path.display()
-            ),
-            Err(err) => {
-                return Err(err
-                    .context(format!("failed to lock file '{}'", path.display()))
-                    .into());
+        let incremental_path = dir.join("incremental").with_extension("json");
+        let failed_path = if config.record_failures {
+            Some(dir.join("failed").with_extension("json"))
+        } else {
+            None
+        };
+        Writer::open(
+            dir,
+            path,
+            Some(incremental_path),
+            failed_path,
+            true,
+            config,
+            access,
+        )
+    }
+
+    /// Create a writer which reads timings from the given baseline file, instead of the one in
+    /// the command's data directory. The baseline is only updated if `update` is true.
+    pub fn with_baseline(
+        dir: &Path,
+        path: PathBuf,
+        update: bool,
+        config: &Config,
+        access: Access,
+    ) -> Result<Self> {
+        Writer::open(dir, path, None, None, update, config, access)
+    }
+
+    fn open(
+        dir: &Path,
+        path: PathBuf,
+        incremental_path: Option<PathBuf>,
+        failed_path: Option<PathBuf>,
+        update: bool,
+        config: &Config,
+        access: Access,
+    ) -> Result<Self> {
+        let normalizer = Normalizer::from_config(config)?;
+
+        let (lock_file, mut orig, mut incremental) = if let Access::ReadOnly = access {
+            let incremental = match &incremental_path {
+                Some(path) => read_unlocked(path, &normalizer)?,
+                None => None,
+            };
+            (None, read_unlocked(&path, &normalizer)?, incremental)
+        } else if !update {
+            if !path.exists() {
+                bail!("baseline file '{}' does not exist", path.display());
+            }
+            (None, read_unlocked(&path, &normalizer)?, None)
+        } else {
+            // Both baselines are covered by the lock on the full baseline.
+            let lock_path = with_suffix(&path, ".lock");
+            log::debug!("opening or creating lock file '{}'", lock_path.display());
+            let (lock_file, _) = open_or_create(&lock_path)?;
+            lock(lock_file.as_ref(), &path, access)?;
+            let orig = OrigOutput::new(&path, &normalizer)?;
+            let incremental = match &incremental_path {
+                Some(path) => OrigOutput::new(path, &normalizer)?,
+                None => None,
+            };
+            (Some(lock_file.into()), orig, incremental)
+        };
+        // Failed runs are only used to show progress until a run succeeds.
+        let mut failed = match &failed_path {
+            Some(path) if orig.is_none() => {
+                if lock_file.is_some() {
+                    OrigOutput::new(path, &normalizer)?
+                } else {
+                    read_unlocked(path, &normalizer)?
+                }
+            }
+            _ => None,
+        };
+        if failed.is_some() {
+            log::debug!("no successful run has been recorded, using the baseline of failed runs");
+        }
+        if config.prefix_match {
+            for orig in orig.iter_mut().chain(&mut incremental).chain(&mut failed) {
+                orig.index_prefixes();
             }
         }
 
-        let orig = OrigOutput::new(&file, &path)?;
+        let history_path = match access {
+            Access::ReadOnly | Access::Shared => None,
+            _ => Some(dir.join("history").with_extension("json")),
+        };
+
         Ok(Writer {
-            file: file.into(),
+            lock_file,
+            record: !matches!(access, Access::Shared),
             path,
+            history_path,
             orig,
+            incremental_path,
+            incremental,
+            failed_path,
+            failed,
+            forced: None,
+            selected: None,
             curr: CurrOutput::new(),
+            runs: config.runs,
+            max_lines: config.max_lines,
+            min_record_duration: config.min_record_duration,
+            no_degrade: config.no_degrade,
+            compress: config.compress,
+            normalizer,
+            mode: config.progress.mode,
+            smoother: Smoother::new(config.progress.smoothing),
         })
     }
 
+    /// Compare the run against the given baseline, instead of choosing one from its output.
+    pub fn force_baseline(&mut self, baseline: Baseline) {
+        self.forced = Some(baseline);
+        self.selected = Some(baseline);
+    }
+
+    /// The baseline the run is being compared against. This is the full baseline until the
+    /// run has been selected as incremental.
+    pub fn baseline(&self) -> Baseline {
+        self.selected.unwrap_or(Baseline::Full)
+    }
+
+    fn active(&self) -> Option<&OrigOutput> {
+        match self.baseline() {
+            Baseline::Full => self.orig.as_ref().or(self.failed.as_ref()),
+            Baseline::Incremental => self.incremental.as_ref(),
+        }
+    }
+
+    /// Select the baseline, if it hasn't been chosen yet and enough of the run has passed to
+    /// tell whether it is incremental. See `Baseline` for details.
+    fn select(&mut self, elapsed: Duration, finished: bool) {
+        if self.selected.is_some() || self.incremental_path.is_none() {
+            return;
+        }
+        // The first run is always recorded in the full baseline.
+        let orig = match &self.orig {
+            Some(orig) => orig,
+            None => return,
+        };
+        let window = orig.total / SELECT_WINDOW;
+        if elapsed < window && !finished {
+            return;
+        }
+
+        let expected = orig.durs.iter().take_while(|&&dur| dur <= window).count();
+        let baseline = if elapsed < window || orig.recognized * SELECT_RATIO < expected {
+            Baseline::Incremental
+        } else {
+            Baseline::Full
+        };
+        log::debug!(
+            "using the {} baseline, since {} of {} lines were recognized in the first {}",
+            baseline,
+            orig.recognized,
+            expected,
+            humantime::format_duration(truncate_millis(window.min(elapsed)))
+        );
+        self.selected = Some(baseline);
+        if baseline == Baseline::Incremental {
+            // The position in the incremental baseline is unrelated to the position in the full
+            // baseline, so start smoothing again from there.
+            self.smoother.value = self.raw_completed();
+        }
+    }
+
     pub fn len(&self) -> Option<Duration> {
-        self.orig.as_ref().map(|orig| orig.data.total)
+        self.active().map(|orig| orig.total)
     }
 
+    /// The estimated position in the baseline, smoothed over the calls to `tick`.
     pub fn completed(&self) -> Duration {
-        self.orig
-            .as_ref()
-            .map(|orig| orig.elapsed)
-            .unwrap_or_default()
+        self.smoother.value
     }
 
-    pub fn write_line(&mut self, line: Vec<u8>) -> Result<()> {
-        if let Some(ref mut orig) = self.orig {
-            orig.write_line(&line);
+    /// The estimated position in the baseline, without smoothing. This depends on the
+    /// progress mode, but is always measured in the baseline's duration.
+    pub fn raw_completed(&self) -> Duration {
+        let orig = match self.active() {
+            Some(orig) => orig,
+            None => return Duration::default(),
+        };
+        let by_lines = || orig.total.mul_f64(orig.recognized_fraction());
+        match self.mode {
+            ProgressMode::Time => orig.position,
+            ProgressMode::Lines => by_lines(),
+            ProgressMode::Hybrid => (orig.position + by_lines()) / 2,
         }
+    }
 
-        self.curr.write_line(line);
+    /// The fraction of the baseline's lines which have been recognized so far, or `None` if
+    /// there is no baseline.
+    pub fn line_fraction(&self) -> Option<f64> {
+        self.active().map(OrigOutput::recognized_fraction)
+    }
 
-        Ok(())
+    /// The time since the run started.
+    pub fn elapsed(&self) -> Duration {
+        self.curr.start.elapsed()
+    }
+
+    /// The wall-clock time at which the run started.
+    pub fn started_at(&self) -> SystemTime {
+        self.curr.started_at
+    }
+
+    /// Advance the position in time between lines, so the progress bar moves smoothly when
+    /// lines are recognized infrequently.
+    pub fn tick(&mut self) {
+        let elapsed = self.curr.start.elapsed();
+        for orig in self
+            .orig
+            .iter_mut()
+            .chain(&mut self.incremental)
+            .chain(&mut self.failed)
+        {
+            orig.advance(elapsed);
+        }
+        self.select(elapsed, false);
+        let raw = self.raw_completed();
+        self.smoother.update(raw);
+        log::debug!(
+            "position: {}ms (raw {}ms, {:.0}% of lines recognized)",
+            self.smoother.value.as_millis(),
+            raw.as_millis(),
+            self.line_fraction().unwrap_or_default() * 100.0
+        );
+    }
+
+    /// Move the smoothed position to the estimate, so the final position is shown exactly
+    /// when the process finishes.
+    pub fn settle(&mut self) {
+        self.smoother.value = self.raw_completed();
+    }
+
+    /// Estimate the time remaining until the process completes, or `None` if there is no
+    /// baseline to compare against.
+    pub fn eta(&self) -> Option<Eta> {
+        let total = self.len()?;
+        if self.curr.start.elapsed() > total {
+            Some(Eta::Overtime)
+        } else {
+            Some(Eta::Remaining(total - self.completed().min(total)))
+        }
+    }
+
+    /// Discard the output written so far, so that the command can be run again.
+    pub fn reset(&mut self) {
+        self.curr = CurrOutput::new();
+        self.smoother.value = Duration::default();
+        self.selected = self.forced;
+        for orig in self
+            .orig
+            .iter_mut()
+            .chain(&mut self.incremental)
+            .chain(&mut self.failed)
+        {
+            orig.reset();
+        }
+    }
+
+    pub fn write_line(&mut self, stream: Stream, line: &[u8]) -> Result<LineMatch> {
+        let elapsed = self.curr.start.elapsed();
+        if self.normalizer.is_ignored(line) {
+            return Ok(LineMatch {
+                elapsed,
+                recorded: None,
+                recognized: false,
+            });
+        }
+
+        let key = (stream, self.normalizer.key(line));
+        // Lines are matched against both baselines, so that either can be used once the
+        // baseline is selected.
+        let full = match self.orig.as_mut().or(self.failed.as_mut()) {
+            Some(orig) => orig.write_line(&key, elapsed),
+            None => (None, false),
+        };
+        let incremental = match self.incremental {
+            Some(ref mut orig) => orig.write_line(&key, elapsed),
+            None => (None, false),
+        };
+        self.select(elapsed, false);
+        let (recorded, recognized) = match self.baseline() {
+            Baseline::Full => full,
+            Baseline::Incremental => incremental,
+        };
+
+        self.curr.write_line(key, elapsed);
+
+        Ok(LineMatch {
+            elapsed,
+            recorded,
+            recognized,
+        })
     }
 
     pub fn finish(&mut self, success: bool) -> Result<()> {
-        if success || self.orig.is_none() {
-            log::debug!("saving process output to file '{}'", self.path.display());
-            log::trace!("current output: {:#?}", self.curr);
-            self.file.seek(SeekFrom::Start(0))?;
-            self.file.set_len(0)?;
-            self.curr.finish(&self.file, &self.path)?;
+        let total = self.curr.start.elapsed();
+        self.select(total, true);
+        self.append_history(success);
+        self.report_accuracy(success, total);
+
+        if self.lock_file.is_none() || !self.record {
+            return Ok(());
+        }
+
+        if total < self.min_record_duration {
+            log::debug!(
+                "not recording the run, since it finished in less than {}",
+                humantime::format_duration(self.min_record_duration)
+            );
+            return Ok(());
+        }
+
+        if !success {
+            if let Some(failed_path) = self.failed_path.clone() {
+                let failed = match self.failed.take() {
+                    Some(failed) => Some(failed),
+                    None => OrigOutput::new(&failed_path, &self.normalizer)?,
+                };
+                return self.record(&failed_path, failed);
+            }
+        }
+
+        let (path, orig) = match (self.baseline(), &self.incremental_path) {
+            (Baseline::Incremental, Some(path)) => (path.clone(), self.incremental.take()),
+            _ => (self.path.clone(), self.orig.take()),
+        };
+        if success || orig.is_none() {
+            self.record(&path, orig)?;
         }
 
         Ok(())
     }
+
+    /// Add the run to the runs recorded in `orig`, and write them to `path`.
+    fn record(&mut self, path: &Path, orig: Option<OrigOutput>) -> Result<()> {
+        log::debug!("saving process output to file '{}'", path.display());
+        let curr = self.curr.finish(self.max_lines);
+        log::trace!("current output: {:#?}", curr);
+
+        let mut runs = orig.map(|orig| orig.runs).unwrap_or_default();
+        if self.no_degrade && !runs.is_empty() {
+            if let Some(reason) = degrades(&runs, &curr) {
+                log::info!(
+                    "not recording the run, since {}. Use '--force-record' to record it anyway",
+                    reason
+                );
+                return Ok(());
+            }
+        }
+        runs.push_back(curr);
+        while runs.len() > self.runs.max(1) {
+            runs.pop_front();
+        }
+
+        write_runs(path, self.compress, &StoredOutput::Runs { runs })
+    }
+
+    /// Log how far the baseline's prediction was from the duration of the run.
+    fn report_accuracy(&self, success: bool, total: Duration) {
+        if !success {
+            return;
+        }
+        let predicted = match self.len() {
+            Some(predicted) => predicted,
+            None => return,
+        };
+        if let Some(error) = history::relative_error(predicted, total) {
+            log::debug!(
+                "the run took {}, {:+.1}% compared to the prediction of {}",
+                humantime::format_duration(truncate_millis(total)),
+                error * 100.0,
+                humantime::format_duration(truncate_millis(predicted))
+            );
+        }
+    }
+
+    /// Record an interrupted run in the history. Unlike a failed run, the baseline is never
+    /// written, even if there is no previous baseline, since the output is incomplete.
+    pub fn abort(&mut self) {
+        self.append_history(false)
+    }
+
+    /// Append the run to the history. The history is only informational, so failing to write
+    /// it is logged rather than failing the run.
+    fn append_history(&self, success: bool) {
+        if let Some(history_path) = &self.history_path {
+            log::debug!("appending run to history file '{}'", history_path.display());
+            let result = history::append(
+                history_path,
+                history::Record {
+                    recorded_at: SystemTime::now(),
+                    total: self.curr.start.elapsed(),
+                    success,
+                    predicted: self.len(),
+                },
+            );
+            if let Err(err) = result {
+                log::warn!("{}", crate::fmt_error(&err));
+            }
+        }
+    }
 }
 
 impl Drop for Writer {
     fn drop(&mut self) {
-        let _ = self.file.unlock();
+        if let Some(file) = &self.lock_file {
+            let _ = file.unlock();
+        }
+    }
+}
+
+/// Runs with less than this fraction of the median line count or total duration of the
+/// recorded runs are not recorded with `no_degrade`.
+const DEGRADE_FRACTION: f64 = 0.5;
+
+/// Check whether recording `curr` would degrade a baseline of `runs`, because it has far fewer
+/// lines or finished far sooner than is typical, returning the reason if so.
+fn degrades(runs: &VecDeque<OutputData>, curr: &OutputData) -> Option<String> {
+    let lines = median_count(runs.iter().map(OutputData::line_count).collect());
+    if (curr.line_count() as f64) < lines as f64 * DEGRADE_FRACTION {
+        return Some(format!(
+            "it output {} line(s), compared to a median of {} in the baseline",
+            curr.line_count(),
+            lines
+        ));
+    }
+    let total = median(runs.iter().map(OutputData::total).collect());
+    if curr.total() < total.mul_f64(DEGRADE_FRACTION) {
+        return Some(format!(
+            "it finished in {}, compared to a median of {} in the baseline",
+            humantime::format_duration(truncate_millis(curr.total())),
+            humantime::format_duration(truncate_millis(total))
+        ));
+    }
+    None
+}
+
+fn median_count(mut counts: Vec<usize>) -> usize {
+    counts.sort_unstable();
+    counts[counts.len() / 2]
+}
+
+/// Write a baseline to a temporary file, and then move it over the existing baseline, so the
+/// baseline is left intact if writing fails part way through.
+fn write_runs(path: &Path, compress: bool, data: &StoredOutput) -> Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|_| format!("failed to create file '{}'", tmp_path.display()))?;
+    let result = if compress {
+        let mut encoder = GzEncoder::new(BufWriter::new(&file), Compression::default());
+        json::to_writer(&mut encoder, data)
+            .map_err(io::Error::from)
+            .and_then(|()| encoder.finish()?.flush())
+    } else {
+        let mut writer = BufWriter::new(&file);
+        json::to_writer(&mut writer, data)
+            .map_err(io::Error::from)
+            .and_then(|()| writer.flush())
+    }
+    .and_then(|()| file.sync_all());
+    drop(file);
+
+    if let Err(err) = result {
+        fs::remove_file(&tmp_path).ok();
+        return Err(err
+            .context(format!("failed to write to file '{}'", tmp_path.display()))
+            .into());
+    }
+    replace_file(&tmp_path, path).with_context(|_| {
+        format!(
+            "failed to move file '{}' to '{}'",
+            tmp_path.display(),
+            path.display()
+        )
+    })?;
+    Ok(())
+}
+
+fn lock(file: &File, path: &Path, access: Access) -> Result<()> {
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        let result = match access {
+            Access::Shared => FileExt::try_lock_shared(file),
+            _ => file.try_lock_exclusive(),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(ref err) if err.kind() == fs2::lock_contended_error().kind() => match access {
+                Access::Wait(timeout) => {
+                    if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                        bail!(
+                            "timed out waiting for file '{}' to be released by another process",
+                            path.display()
+                        );
+                    }
+                    if !waiting {
+                        log::info!(
+                            "waiting for file '{}' to be released by another process",
+                            path.display()
+                        );
+                        waiting = true;
+                    }
+                    thread::sleep(LOCK_POLL_INTERVAL);
+                }
+                _ => bail!(
+                    "file '{}' is being accessed by another process, use '--wait' to wait for \
+                     it or '--read-only' to run without recording output",
+                    path.display()
+                ),
+            },
+            Err(err) => {
+                return Err(err
+                    .context(format!("failed to lock file '{}'", path.display()))
+                    .into());
+            }
+        }
+    }
+}
+
+/// How a line of output compared against the baseline.
+#[derive(Debug, Copy, Clone)]
+pub struct LineMatch {
+    /// The time into the current run at which the line was output.
+    pub elapsed: Duration,
+    /// The blended time at which the line was output in previous runs, if it is in the
+    /// baseline.
+    pub recorded: Option<Duration>,
+    /// Whether the line advanced the progress bar. Lines in the baseline are ignored if they
+    /// are output earlier than lines which have already been recognized.
+    pub recognized: bool,
+}
+
+/// The minimum length of the prefix shared by lines matched with `prefix_match`.
+const MIN_PREFIX_LEN: usize = 8;
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// If no lines have been recognized for this long, fall back to estimating progress by
+/// line count.
+const STALL_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Copy, Clone)]
+pub enum Eta {
+    Remaining(Duration),
+    /// The process has been running for longer than the baseline total.
+    Overtime,
+}
+
+impl fmt::Display for Eta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Eta::Remaining(dur) => fmt::Display::fmt(&HumanDuration(*dur), f),
+            Eta::Overtime => write!(f, "overtime"),
+        }
     }
 }
 
 #[derive(Debug)]
 struct OrigOutput {
-    data: OutputData,
-    map: HashMap<Vec<u8>, u32>,
+    runs: VecDeque<OutputData>,
+    map: HashMap<Key, u32>,
+    durs: Vec<Duration>,
+    total: Duration,
+    line_count: usize,
+    /// The median position of each line in the output of sampled runs, in the same order as
+    /// `durs`. This is `None` for lines from runs which were not sampled.
+    indices: Vec<Option<u32>>,
     seq: u32,
     elapsed: Duration,
+    /// The most recently recognized line, and the value of `lines_seen` at that time.
+    last_recognized: Option<(u32, usize)>,
+    /// The time into the current run at which a line was last recognized.
+    recognized_at: Duration,
+    /// The number of lines recognized so far.
+    recognized: usize,
+    /// The keys of the baseline's lines in sorted order, with their position in `durs`, if
+    /// lines may be matched by prefix.
+    prefixes: Option<Vec<(Key, u32)>>,
+    lines_seen: usize,
+    position: Duration,
 }
 
-impl OrigOutput {
-    fn new(file: &FileEntry, path: &Path) -> Result<Option<Self>> {
-        if let FileEntry::Existing(file) = file {
-            let mut data: OutputData = json::from_reader(BufReader::new(file))
-                .with_context(|_| format!("failed to read JSON file '{}'", path.display()))?;
-            log::trace!("original output: {:#?}", data);
-            let map = data
-                .lines
-                .iter_mut()
-                .enumerate()
-                .map(|(seq, line)| (replace(&mut line.data, Vec::new()), seq as u32))
-                .collect();
-            Ok(Some(OrigOutput {
-                data,
-                map,
-                seq: 0,
-                elapsed: Duration::from_secs(0),
-            }))
+/// Load the recorded runs of a command from its data directory without modifying them. An
+/// empty list is returned if the command has not been run before.
+///
+/// The baseline is replaced atomically when it is written, so it doesn't need to be locked.
+pub fn load(dir: &Path) -> Result<VecDeque<OutputData>> {
+    let path = dir.join("orig").with_extension("json");
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(err) => {
+            return Err(err
+                .context(format!("failed to open file '{}'", path.display()))
+                .into());
+        }
+    };
+    if file.metadata()?.len() == 0 {
+        return Ok(VecDeque::new());
+    }
+
+    read_runs(&file, &path)
+}
+
+/// Replace the baseline in a command's data directory with the given runs, for example from
+/// a baseline recorded on another machine. Unless `replace` is true, this fails if a baseline
+/// has already been recorded. The incremental baseline is left unchanged.
+pub fn install(
+    dir: &Path,
+    runs: VecDeque<OutputData>,
+    replace: bool,
+    compress: bool,
+) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|_| format!("failed to create directory '{}'", dir.display()))?;
+    let path = dir.join("orig").with_extension("json");
+    let lock_path = with_suffix(&path, ".lock");
+    log::debug!("opening or creating lock file '{}'", lock_path.display());
+    let (lock_file, _) = open_or_create(&lock_path)?;
+    // The lock is released when the file is closed.
+    lock(lock_file.as_ref(), &path, Access::Wait(None))?;
+
+    if !replace && !load(dir)?.is_empty() {
+        bail!(
+            "a baseline has already been recorded in '{}', use '--force' to replace it",
+            dir.display()
+        );
+    }
+    log::debug!("saving imported runs to file '{}'", path.display());
+    write_runs(&path, compress, &StoredOutput::Runs { runs })
+}
+
+/// Read a baseline without locking it. Since another process may be recording a run at the same
+/// time, the file is ignored if it can't be parsed.
+fn read_unlocked(path: &Path, normalizer: &Normalizer) -> Result<Option<OrigOutput>> {
+    log::debug!("opening output file '{}' for reading", path.display());
+    match File::open(path) {
+        Ok(file) => match read_runs(&file, path) {
+            Ok(runs) => Ok(OrigOutput::from_runs(runs, normalizer)),
+            Err(err) => {
+                log::warn!("{}", crate::fmt_error(&err));
+                Ok(None)
+            }
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err
+            .context(format!("failed to open file '{}'", path.display()))
+            .into()),
+    }
+}
+
+/// The first bytes of a gzip stream.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+fn read_runs(file: &File, path: &Path) -> Result<VecDeque<OutputData>> {
+    // The baseline may or may not be compressed, depending on the config when it was written.
+    let mut reader = BufReader::new(file);
+    let buf = reader
+        .fill_buf()
+        .with_context(|_| format!("failed to read file '{}'", path.display()))?;
+    if buf.is_empty() {
+        // The file was created by a run which didn't record any output.
+        return Ok(VecDeque::new());
+    }
+    let compressed = buf.starts_with(GZIP_MAGIC);
+    let data: StoredOutput = if compressed {
+        json::from_reader(GzDecoder::new(reader))
+    } else {
+        json::from_reader(reader)
+    }
+    .with_context(|_| format!("failed to read JSON file '{}'", path.display()))?;
+    log::trace!("original output: {:#?}", data);
+    Ok(match data {
+        StoredOutput::Runs { runs } => runs,
+        StoredOutput::Single(data) => iter::once(data).collect(),
+    })
+}
+
+impl Smoother {
+    fn new(alpha: f64) -> Self {
+        let alpha = if alpha > 0.0 && alpha <= 1.0 {
+            alpha
         } else {
-            Ok(None)
+            log::warn!("smoothing must be greater than 0 and at most 1, using default");
+            0.5
+        };
+        Smoother {
+            alpha,
+            value: Duration::default(),
+        }
+    }
+
+    /// Move the smoothed value towards `raw`. Since the raw position never decreases, neither
+    /// does the smoothed value.
+    fn update(&mut self, raw: Duration) {
+        let value = self.value.as_secs_f64();
+        let next = value + self.alpha * (raw.as_secs_f64() - value);
+        self.value = Duration::from_secs_f64(next.max(0.0)).max(self.value);
+    }
+}
+
+impl OrigOutput {
+    /// Read the baseline file, which must be locked. If it is corrupt, for example because it
+    /// was written by an older version which crashed part way through, it is backed up and the
+    /// run continues without a baseline.
+    fn new(path: &Path, normalizer: &Normalizer) -> Result<Option<Self>> {
+        log::debug!("opening output file '{}'", path.display());
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err
+                    .context(format!("failed to open file '{}'", path.display()))
+                    .into());
+            }
+        };
+
+        let runs = read_runs(&file, path);
+        drop(file);
+        match runs {
+            Ok(runs) => Ok(OrigOutput::from_runs(runs, normalizer)),
+            Err(err) => {
+                log::warn!("{}", crate::fmt_error(&err));
+                let backup_path = with_suffix(path, ".corrupt");
+                replace_file(path, &backup_path).with_context(|_| {
+                    format!(
+                        "failed to move file '{}' to '{}'",
+                        path.display(),
+                        backup_path.display()
+                    )
+                })?;
+                log::warn!(
+                    "the baseline has been moved to '{}', and a new one will be recorded",
+                    backup_path.display()
+                );
+                Ok(None)
+            }
         }
     }
 
-    fn write_line(&mut self, line: &[u8]) {
-        if let Some(&seq) = self.map.get(line) {
+    fn from_runs(runs: VecDeque<OutputData>, normalizer: &Normalizer) -> Option<Self> {
+        if runs.is_empty() {
+            return None;
+        }
+
+        // Take the median duration of each line over all runs it appeared in, and order
+        // the lines by their blended duration. Baselines recorded by older versions may
+        // contain raw lines, and the normalization config may have changed, so they are
+        // normalized here too.
+        let mut line_durs: HashMap<Key, (Vec<Duration>, Vec<u32>)> = HashMap::new();
+        for run in &runs {
+            if let Some(recorded_at) = run.recorded_at {
+                log::debug!(
+                    "loading run recorded at {}",
+                    humantime::format_rfc3339_seconds(recorded_at)
+                );
+            }
+            for line in &run.lines {
+                if normalizer.is_ignored(&line.data) {
+                    continue;
+                }
+                let key = (line.stream, normalizer.key(&line.data));
+                let (durs, indices) = line_durs.entry(key).or_default();
+                durs.push(line.dur);
+                indices.extend(line.index);
+            }
+        }
+        let mut lines: Vec<(Key, Duration, Option<u32>)> = line_durs
+            .into_iter()
+            .map(|(line, (durs, mut indices))| {
+                indices.sort();
+                let index = indices.get(indices.len() / 2).cloned();
+                (line, median(durs), index)
+            })
+            .collect();
+        lines.sort_by_key(|&(_, dur, _)| dur);
+
+        let durs = lines.iter().map(|&(_, dur, _)| dur).collect();
+        let indices = lines.iter().map(|&(_, _, index)| index).collect();
+        let map = lines
+            .into_iter()
+            .enumerate()
+            .map(|(seq, (line, _, _))| (line, seq as u32))
+            .collect();
+        let total = median(runs.iter().map(|run| run.total).collect());
+        let mut line_counts: Vec<usize> = runs.iter().map(|run| run.line_count()).collect();
+        line_counts.sort();
+        let line_count = line_counts[line_counts.len() / 2];
+        Some(OrigOutput {
+            runs,
+            map,
+            durs,
+            total,
+            line_count,
+            indices,
+            seq: 0,
+            elapsed: Duration::from_secs(0),
+            last_recognized: None,
+            recognized_at: Duration::from_secs(0),
+            recognized: 0,
+            prefixes: None,
+            lines_seen: 0,
+            position: Duration::from_secs(0),
+        })
+    }
+
+    /// Allow lines which aren't in the baseline to be matched by their longest common prefix
+    /// with a recorded line.
+    fn index_prefixes(&mut self) {
+        let mut prefixes: Vec<(Key, u32)> = self
+            .map
+            .iter()
+            .map(|(key, &seq)| (key.clone(), seq))
+            .collect();
+        prefixes.sort();
+        self.prefixes = Some(prefixes);
+    }
+
+    /// Find the recorded line which shares the longest prefix with `key`. Since the keys are
+    /// sorted, it is one of the neighbours of the position `key` would be inserted at. The
+    /// prefix must be at least `MIN_PREFIX_LEN` bytes and half of the line, so lines which
+    /// share only a common word such as "Compiling" don't match.
+    fn match_prefix(&self, key: &Key) -> Option<u32> {
+        let prefixes = self.prefixes.as_ref()?;
+        let idx = prefixes.partition_point(|(other, _)| other < key);
+
+        let min_len = MIN_PREFIX_LEN.max(key.1.len().div_ceil(2));
+        prefixes[idx.saturating_sub(1)..(idx + 1).min(prefixes.len())]
+            .iter()
+            .filter(|((stream, _), _)| *stream == key.0)
+            .map(|((_, line), seq)| (common_prefix_len(line, &key.1), *seq))
+            .filter(|&(len, _)| len >= min_len)
+            .max_by_key(|&(len, _)| len)
+            .map(|(_, seq)| seq)
+    }
+
+    fn reset(&mut self) {
+        self.seq = 0;
+        self.elapsed = Duration::from_secs(0);
+        self.last_recognized = None;
+        self.recognized_at = Duration::from_secs(0);
+        self.recognized = 0;
+        self.lines_seen = 0;
+        self.position = Duration::from_secs(0);
+    }
+
+    /// Get the fraction of the baseline's lines which have been output so far, regardless
+    /// of whether they were recognized.
+    fn line_fraction(&self) -> f64 {
+        if self.line_count == 0 {
+            return 0.0;
+        }
+        (self.lines_seen as f64 / self.line_count as f64).min(1.0)
+    }
+
+    /// Get the fraction of the baseline's distinct lines which have been recognized so far.
+    fn recognized_fraction(&self) -> f64 {
+        if self.durs.is_empty() {
+            return 0.0;
+        }
+        (self.recognized as f64 / self.durs.len() as f64).min(1.0)
+    }
+
+    /// Get the recorded time of the next line expected in the output, or the total duration if
+    /// the last line has been recognized.
+    fn next_expected(&self) -> Duration {
+        let next = match self.last_recognized {
+            Some((seq, _)) => seq as usize + 1,
+            None => 0,
+        };
+        self.durs.get(next).cloned().unwrap_or(self.total)
+    }
+
+    /// Advance the position with real time since the last recognized line, assuming the run
+    /// is progressing at the same rate as the baseline. The position never passes the next
+    /// expected line's recorded time.
+    fn advance(&mut self, elapsed: Duration) {
+        let since = elapsed.checked_sub(self.recognized_at).unwrap_or_default();
+        let position = (self.elapsed + since).min(self.next_expected());
+        self.position = self.position.max(position);
+    }
+
+    /// Estimate the position between the last recognized line and the next line in a sampled
+    /// baseline, from the number of lines output since. The estimate never passes the next
+    /// line's recorded time.
+    fn interpolate(&self) -> Option<Duration> {
+        let (seq, seen) = self.last_recognized?;
+        let next = seq as usize + 1;
+        let from = self.indices[seq as usize]?;
+        let to = (*self.indices.get(next)?)?;
+        if to <= from {
+            return None;
+        }
+
+        let fraction = ((self.lines_seen - seen) as f64 / f64::from(to - from)).min(1.0);
+        let (start, end) = (self.durs[seq as usize], self.durs[next]);
+        Some(start + (end - start).mul_f64(fraction))
+    }
+
+    /// Update the position for a new line, returning its recorded duration if it is in the
+    /// baseline, and whether it was recognized.
+    fn write_line(&mut self, key: &Key, elapsed: Duration) -> (Option<Duration>, bool) {
+        self.lines_seen += 1;
+        let mut recorded = None;
+        let mut recognized = false;
+        let seq = match self.map.get(key) {
+            Some(&seq) => Some(seq),
+            None => self.match_prefix(key),
+        };
+        if let Some(seq) = seq {
+            recorded = Some(self.durs[seq as usize]);
             if self.seq <= seq {
-                log::trace!("recognized line '{}'", String::from_utf8_lossy(line));
-                self.elapsed = self.data.lines[seq as usize].dur;
-                log::trace!("elapsed: {:#}", indicatif::HumanDuration(self.elapsed));
+                recognized = true;
+                log::trace!(
+                    "recognized {} line '{}'",
+                    key.0,
+                    String::from_utf8_lossy(&key.1)
+                );
+                self.elapsed = self.durs[seq as usize];
+                self.recognized_at = elapsed;
+                self.recognized += 1;
+                self.last_recognized = Some((seq, self.lines_seen));
+                log::trace!("elapsed: {:#}", HumanDuration(self.elapsed));
             }
 
             self.seq += 1;
         }
+
+        let mut position = self.elapsed;
+        if let Some(interpolated) = self.interpolate() {
+            position = position.max(interpolated);
+        }
+        if elapsed - self.recognized_at >= STALL_TIMEOUT {
+            // The output has diverged from the baseline, so fall back to estimating progress
+            // from the number of lines output.
+            position = position.max(self.total.mul_f64(self.line_fraction()));
+        }
+        // Output may be slightly reordered between runs, but the progress bar should never
+        // move backwards.
+        self.position = self.position.max(position);
+        (recorded, recognized)
     }
 }
 
 #[derive(Debug)]
 struct CurrOutput {
-    data: OutputData,
-    map: HashMap<Vec<u8>, LineData>,
+    lines: Vec<Line>,
+    /// The number of lines written, including duplicates.
+    line_index: u32,
+    map: HashMap<Key, LineData>,
     start: Instant,
+    /// The wall-clock time corresponding to `start`, which unlike `Instant` can be compared
+    /// across processes.
+    started_at: SystemTime,
 }
 
 #[derive(Debug)]
@@ -148,49 +1089,130 @@ struct LineData {
 impl CurrOutput {
     fn new() -> Self {
         CurrOutput {
-            data: OutputData {
-                lines: Vec::new(),
-                total: Duration::from_secs(0),
-            },
+            lines: Vec::new(),
+            line_index: 0,
             map: HashMap::new(),
             start: Instant::now(),
+            started_at: SystemTime::now(),
         }
     }
 
-    fn write_line(&mut self, line: Vec<u8>) {
-        let dur = self.start.elapsed();
-        let seq = self.data.lines.len() as u32;
-        match self.map.entry(line) {
+    fn write_line(&mut self, key: Key, dur: Duration) {
+        let seq = self.lines.len() as u32;
+        let index = self.line_index;
+        self.line_index += 1;
+        let stream = key.0;
+        match self.map.entry(key) {
             Entry::Occupied(mut entry) => entry.get_mut().dup = true,
             Entry::Vacant(entry) => {
-                self.data.lines.push(Line {
+                self.lines.push(Line {
                     data: Vec::new(),
                     dur,
+                    stream,
+                    index: Some(index),
                 });
                 entry.insert(LineData { seq, dup: false });
             }
         };
     }
 
-    fn finish(&mut self, file: &File, path: &Path) -> Result<()> {
-        self.data.total = self.start.elapsed();
-        for (line, data) in self.map.drain() {
+    /// Get the recorded output. If there are more than `max_lines` lines, they are sampled
+    /// down to evenly spaced lines, and their positions are kept so progress can be
+    /// interpolated between them.
+    fn finish(&mut self, max_lines: Option<usize>) -> OutputData {
+        let total = self.start.elapsed();
+        for ((_, line), data) in self.map.drain() {
             if !data.dup {
-                self.data.lines[data.seq as usize].data = line;
+                self.lines[data.seq as usize].data = line;
             }
         }
-        self.data.lines.retain(|line| !line.data.is_empty());
+        let mut lines = mem::take(&mut self.lines);
+        lines.retain(|line| !line.data.is_empty());
 
-        json::to_writer(file, &self.data)
-            .with_context(|_| format!("failed to write to file '{}'", path.display()))?;
-        Ok(())
+        let mut sampled_from = None;
+        match max_lines {
+            Some(max_lines) if lines.len() > max_lines => {
+                log::debug!("sampling {} lines down to {}", lines.len(), max_lines);
+                sampled_from = Some(lines.len());
+                lines = sample(lines, max_lines);
+            }
+            _ => {
+                for line in &mut lines {
+                    line.index = None;
+                }
+            }
+        }
+
+        OutputData {
+            lines,
+            total,
+            recorded_at: Some(self.started_at),
+            sampled_from,
+        }
     }
 }
 
+/// The format of the baseline file. Older versions stored a single run.
 #[derive(Debug, Serialize, Deserialize)]
-struct OutputData {
+#[serde(untagged)]
+enum StoredOutput {
+    Runs { runs: VecDeque<OutputData> },
+    Single(OutputData),
+}
+
+/// The output of a single recorded run of a command.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutputData {
     lines: Vec<Line>,
     total: Duration,
+    /// The time at which the run started. This is `None` for runs recorded by older versions.
+    #[serde(default)]
+    recorded_at: Option<SystemTime>,
+    /// The number of lines in the run before it was sampled down to `max_lines`, or `None` if
+    /// every line was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampled_from: Option<usize>,
+}
+
+impl OutputData {
+    /// The total duration of the run.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// The time at which the run started, if known.
+    pub fn recorded_at(&self) -> Option<SystemTime> {
+        self.recorded_at
+    }
+
+    /// The number of lines recorded. Lines which were output more than once are not recorded,
+    /// since they cannot be used to track progress.
+    pub fn line_count(&self) -> usize {
+        self.sampled_from.unwrap_or(self.lines.len())
+    }
+
+    /// Get the keys of the recorded lines, with the time into the run at which each was
+    /// output.
+    pub fn timings<'a>(
+        &'a self,
+        normalizer: &'a Normalizer,
+    ) -> impl Iterator<Item = ((Stream, Vec<u8>), Duration)> + 'a {
+        self.lines
+            .iter()
+            .filter(move |line| !normalizer.is_ignored(&line.data))
+            .map(move |line| ((line.stream, normalizer.key(&line.data)), line.dur))
+    }
+
+    /// Get the keys used to match the recorded lines against new output.
+    pub fn keys<'a>(
+        &'a self,
+        normalizer: &'a Normalizer,
+    ) -> impl Iterator<Item = (Stream, Vec<u8>)> + 'a {
+        self.lines
+            .iter()
+            .filter(move |line| !normalizer.is_ignored(&line.data))
+            .map(move |line| (line.stream, normalizer.key(&line.data)))
+    }
 }
 
 #[derive(Serialize, Deserialize, Hash, Eq, PartialEq)]
@@ -198,17 +1220,87 @@ struct Line {
     #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
     data: Vec<u8>,
     dur: Duration,
+    #[serde(default)]
+    stream: Stream,
+    /// The position of the line in the output, counting duplicates. This is only recorded in
+    /// sampled runs, which don't contain every line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<u32>,
+}
+
+/// The output stream a line was written to.
+#[derive(
+    Debug, Default, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    #[default]
+    Stdout,
+    Stderr,
 }
 
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Lines are matched by their normalized contents, and the stream they were written to so
+/// that identical lines on stdout and stderr aren't conflated.
+type Key = (Stream, Vec<u8>);
+
 impl fmt::Debug for Line {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Line")
             .field("data", &String::from_utf8_lossy(&self.data))
             .field("dur", &self.dur)
+            .field("stream", &self.stream)
+            .field("index", &self.index)
             .finish()
     }
 }
 
+/// Keep `max` evenly spaced lines, always including the first and last.
+fn sample(lines: Vec<Line>, max: usize) -> Vec<Line> {
+    let max = max.max(2);
+    let len = lines.len();
+    let mut keep = (0..max).map(|idx| idx * (len - 1) / (max - 1)).peekable();
+    lines
+        .into_iter()
+        .enumerate()
+        .filter_map(|(idx, line)| {
+            if keep.peek() == Some(&idx) {
+                keep.next();
+                Some(line)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Count the distinct lines over all recorded runs, after normalization.
+pub fn unique_line_count(runs: &VecDeque<OutputData>, normalizer: &Normalizer) -> usize {
+    runs.iter()
+        .flat_map(|run| run.keys(normalizer))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Get the median of a non-empty list of durations.
+pub fn median(mut durs: Vec<Duration>) -> Duration {
+    debug_assert!(!durs.is_empty());
+    durs.sort();
+    let mid = durs.len() / 2;
+    match durs.len() % 2 {
+        0 => (durs[mid - 1] + durs[mid]) / 2,
+        _ => durs[mid],
+    }
+}
+
 fn as_base64<T, S>(key: &T, serializer: S) -> std::result::Result<S::Ok, S::Error>
 where
     T: AsRef<[u8]>,
@@ -225,3 +1317,569 @@ where
     let bytes = base64::decode(&string).map_err(de::Error::custom)?;
     Ok(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs};
+
+    fn line(data: &str, millis: u64) -> Line {
+        Line {
+            data: data.as_bytes().to_owned(),
+            dur: Duration::from_millis(millis),
+            stream: Stream::Stdout,
+            index: None,
+        }
+    }
+
+    #[test]
+    fn completed_is_monotonic() {
+        let run = OutputData {
+            lines: vec![
+                line("a", 100),
+                line("b", 200),
+                line("c", 300),
+                line("d", 400),
+            ],
+            total: Duration::from_millis(500),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let mut orig =
+            OrigOutput::from_runs(iter::once(run).collect(), &Normalizer::default()).unwrap();
+
+        let mut completed = Vec::new();
+        for line in &["b", "d", "c", "a"] {
+            orig.write_line(
+                &(Stream::Stdout, line.as_bytes().to_owned()),
+                Duration::from_secs(0),
+            );
+            completed.push(orig.position);
+        }
+
+        assert_eq!(
+            completed,
+            [200, 400, 400, 400]
+                .iter()
+                .map(|&millis| Duration::from_millis(millis))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_matches_baseline() {
+        let latin1 = b"caf\xe9\n";
+        let utf16 = b"h\x00i\x00\n";
+        let run = OutputData {
+            lines: vec![
+                Line {
+                    data: latin1.to_vec(),
+                    dur: Duration::from_millis(100),
+                    stream: Stream::Stdout,
+                    index: None,
+                },
+                Line {
+                    data: utf16.to_vec(),
+                    dur: Duration::from_millis(200),
+                    stream: Stream::Stdout,
+                    index: None,
+                },
+            ],
+            total: Duration::from_millis(300),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let normalizer = Normalizer::default();
+        let mut orig = OrigOutput::from_runs(iter::once(run).collect(), &normalizer).unwrap();
+
+        // A line which would be identical to the first after a lossy UTF-8 conversion must not
+        // match it.
+        let elapsed = Duration::from_secs(0);
+        orig.write_line(&(Stream::Stdout, normalizer.key(b"caf\xe8\n")), elapsed);
+        assert_eq!(orig.position, Duration::from_millis(0));
+        orig.write_line(&(Stream::Stdout, normalizer.key(latin1)), elapsed);
+        assert_eq!(orig.position, Duration::from_millis(100));
+        orig.write_line(&(Stream::Stdout, normalizer.key(utf16)), elapsed);
+        assert_eq!(orig.position, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn crlf_matches_lf_baseline() {
+        let run = OutputData {
+            lines: vec![line("a\n", 100), line("b\n", 200)],
+            total: Duration::from_millis(300),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let normalizer = Normalizer::default();
+        let mut orig = OrigOutput::from_runs(iter::once(run).collect(), &normalizer).unwrap();
+
+        let elapsed = Duration::from_secs(0);
+        orig.write_line(&(Stream::Stdout, normalizer.key(b"a\r\n")), elapsed);
+        assert_eq!(orig.position, Duration::from_millis(100));
+        orig.write_line(&(Stream::Stdout, normalizer.key(b"b\r\n")), elapsed);
+        assert_eq!(orig.position, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn sampled_baseline_interpolates() {
+        let mut curr = CurrOutput::new();
+        for idx in 0..10 {
+            let key = (Stream::Stdout, idx.to_string().into_bytes());
+            curr.write_line(key, Duration::from_millis(100 * idx));
+        }
+        let run = curr.finish(Some(3));
+        assert_eq!(run.lines.len(), 3);
+        assert_eq!(run.line_count(), 10);
+
+        let mut orig =
+            OrigOutput::from_runs(iter::once(run).collect(), &Normalizer::default()).unwrap();
+        let elapsed = Duration::from_secs(0);
+        orig.write_line(&(Stream::Stdout, b"0".to_vec()), elapsed);
+        assert_eq!(orig.position, Duration::from_millis(0));
+
+        // The next recorded line is 4 lines later, at 400ms.
+        for line in &["1", "2"] {
+            orig.write_line(&(Stream::Stdout, line.as_bytes().to_owned()), elapsed);
+        }
+        assert_eq!(orig.position, Duration::from_millis(200));
+
+        orig.write_line(&(Stream::Stdout, b"4".to_vec()), elapsed);
+        assert_eq!(orig.position, Duration::from_millis(400));
+
+        // Interpolation stops at the next recorded line.
+        for idx in 0..10 {
+            let line = format!("unrecognized {}", idx).into_bytes();
+            orig.write_line(&(Stream::Stdout, line), elapsed);
+        }
+        assert_eq!(orig.position, Duration::from_millis(900));
+    }
+
+    #[test]
+    fn advance_stops_at_next_line() {
+        let run = OutputData {
+            lines: vec![line("a", 100), line("b", 500)],
+            total: Duration::from_millis(600),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let mut orig =
+            OrigOutput::from_runs(iter::once(run).collect(), &Normalizer::default()).unwrap();
+
+        orig.advance(Duration::from_millis(50));
+        assert_eq!(orig.position, Duration::from_millis(50));
+        orig.advance(Duration::from_millis(200));
+        assert_eq!(orig.position, Duration::from_millis(100));
+
+        // The first line was output late, so time is measured from when it was recognized.
+        orig.write_line(&(Stream::Stdout, b"a".to_vec()), Duration::from_millis(300));
+        orig.advance(Duration::from_millis(400));
+        assert_eq!(orig.position, Duration::from_millis(200));
+        orig.advance(Duration::from_millis(1000));
+        assert_eq!(orig.position, Duration::from_millis(500));
+
+        orig.write_line(
+            &(Stream::Stdout, b"b".to_vec()),
+            Duration::from_millis(1000),
+        );
+        orig.advance(Duration::from_millis(2000));
+        assert_eq!(orig.position, Duration::from_millis(600));
+    }
+
+    #[test]
+    fn recognized_fraction_ignores_timing() {
+        // Most of the recorded time is spent before the last line.
+        let run = OutputData {
+            lines: vec![
+                line("a", 100),
+                line("b", 200),
+                line("c", 900),
+                line("d", 1000),
+            ],
+            total: Duration::from_millis(1000),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let mut orig =
+            OrigOutput::from_runs(iter::once(run).collect(), &Normalizer::default()).unwrap();
+
+        for line in &["a", "b", "unrecognized"] {
+            orig.write_line(
+                &(Stream::Stdout, line.as_bytes().to_owned()),
+                Duration::from_millis(10),
+            );
+        }
+        assert_eq!(orig.position, Duration::from_millis(200));
+        assert_eq!(orig.recognized_fraction(), 0.5);
+    }
+
+    #[test]
+    fn corrupt_baseline_is_replaced() {
+        let dir = env::temp_dir().join(format!("bp-corrupt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("orig.json");
+        fs::write(&path, b"{\"runs\": [{\"lin").unwrap();
+
+        let config = Config::default();
+        let mut writer = Writer::new(&dir, &config, Access::Exclusive).unwrap();
+        assert!(writer.len().is_none());
+        writer.write_line(Stream::Stdout, b"Finished\n").unwrap();
+        writer.finish(true).unwrap();
+        drop(writer);
+
+        let runs = read_runs(&File::open(&path).unwrap(), &path).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(
+            fs::read(dir.join("orig.json.corrupt")).unwrap(),
+            b"{\"runs\": [{\"lin"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_history_is_replaced() {
+        let dir = env::temp_dir().join(format!("bp-corrupt-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.json");
+        fs::write(&path, b"[{\"recorded_at\":").unwrap();
+
+        let config = Config::default();
+        let mut writer = Writer::new(&dir, &config, Access::Exclusive).unwrap();
+        writer.write_line(Stream::Stdout, b"Finished\n").unwrap();
+        writer.finish(true).unwrap();
+        drop(writer);
+
+        assert_eq!(history::read(&path).unwrap().len(), 1);
+        assert_eq!(
+            fs::read(dir.join("history.json.corrupt")).unwrap(),
+            b"[{\"recorded_at\":"
+        );
+        // The baseline is still recorded.
+        assert_eq!(load(&dir).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failures_are_recorded_separately() {
+        let dir = env::temp_dir().join(format!("bp-record-failures-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            record_failures: true,
+            ..Config::default()
+        };
+        let run = |success: bool| {
+            let mut writer = Writer::new(&dir, &config, Access::Exclusive).unwrap();
+            let len = writer.len();
+            writer.write_line(Stream::Stdout, b"error\n").unwrap();
+            writer.finish(success).unwrap();
+            len
+        };
+
+        assert!(run(false).is_none());
+        assert!(dir.join("failed.json").is_file());
+        assert!(!dir.join("orig.json").exists());
+
+        // Failed runs are used until a run succeeds, but never mixed with successful runs.
+        assert!(run(true).is_some());
+        let runs = load(&dir).unwrap();
+        assert_eq!(runs.len(), 1);
+        run(false);
+        assert_eq!(load(&dir).unwrap().len(), 1);
+        let failed_path = dir.join("failed.json");
+        let failed = read_runs(&File::open(&failed_path).unwrap(), &failed_path).unwrap();
+        assert_eq!(failed.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn degrades_baseline() {
+        let run = |lines: usize, total_secs: u64| OutputData {
+            lines: (0..lines)
+                .map(|idx| line(&format!("line {}", idx), idx as u64))
+                .collect(),
+            total: Duration::from_secs(total_secs),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let runs: VecDeque<OutputData> = vec![run(10, 60), run(12, 50), run(8, 70)].into();
+
+        assert_eq!(degrades(&runs, &run(10, 60)), None);
+        assert_eq!(degrades(&runs, &run(5, 30)), None);
+        assert_eq!(
+            degrades(&runs, &run(4, 60)).unwrap(),
+            "it output 4 line(s), compared to a median of 10 in the baseline"
+        );
+        assert_eq!(
+            degrades(&runs, &run(10, 29)).unwrap(),
+            "it finished in 29s, compared to a median of 1m in the baseline"
+        );
+    }
+
+    #[test]
+    fn failed_write_keeps_baseline() {
+        let dir = env::temp_dir().join(format!("bp-failed-write-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("orig.json");
+
+        let config = Config::default();
+        let mut writer = Writer::new(&dir, &config, Access::Exclusive).unwrap();
+        writer.write_line(Stream::Stdout, b"Finished\n").unwrap();
+        writer.finish(true).unwrap();
+        drop(writer);
+        let baseline = fs::read(&path).unwrap();
+
+        // A directory in place of the temporary file makes the write fail.
+        fs::create_dir(dir.join("orig.json.tmp")).unwrap();
+        let mut writer = Writer::new(&dir, &config, Access::Exclusive).unwrap();
+        writer.force_baseline(Baseline::Full);
+        writer.write_line(Stream::Stdout, b"Finished\n").unwrap();
+        assert!(writer.finish(true).is_err());
+        drop(writer);
+        assert_eq!(fs::read(&path).unwrap(), baseline);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn incremental_run_selects_incremental_baseline() {
+        let dir = env::temp_dir().join(format!("bp-incremental-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let full = OutputData {
+            lines: (0..40)
+                .map(|idx| line(&idx.to_string(), 25 * idx))
+                .collect(),
+            total: Duration::from_millis(1000),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let incremental = OutputData {
+            lines: vec![line("39", 40)],
+            total: Duration::from_millis(50),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let (full_path, incremental_path) = (dir.join("orig.json"), dir.join("incremental.json"));
+        for (path, run) in [(&full_path, full), (&incremental_path, incremental)] {
+            let runs = iter::once(run).collect();
+            write_runs(path, false, &StoredOutput::Runs { runs }).unwrap();
+        }
+        let config = Config::default();
+
+        // Five lines are recorded in the first tenth of the full baseline.
+        let mut writer = Writer::new(&dir, &config, Access::ReadOnly).unwrap();
+        for idx in 0..5 {
+            let line = format!("{}", idx).into_bytes();
+            writer.write_line(Stream::Stdout, &line).unwrap();
+        }
+        writer.select(Duration::from_millis(100), false);
+        assert_eq!(writer.baseline(), Baseline::Full);
+        assert_eq!(writer.len(), Some(Duration::from_millis(1000)));
+
+        let mut writer = Writer::new(&dir, &config, Access::Exclusive).unwrap();
+        writer.write_line(Stream::Stdout, b"39").unwrap();
+        writer.select(Duration::from_millis(50), false);
+        assert_eq!(writer.baseline(), Baseline::Full);
+        writer.select(Duration::from_millis(100), false);
+        assert_eq!(writer.baseline(), Baseline::Incremental);
+        assert_eq!(writer.len(), Some(Duration::from_millis(50)));
+        writer.finish(true).unwrap();
+        drop(writer);
+
+        let runs = |path: &Path| read_runs(&File::open(path).unwrap(), path).unwrap().len();
+        assert_eq!(runs(&full_path), 1);
+        assert_eq!(runs(&incremental_path), 2);
+
+        // The baseline chosen by the user is kept.
+        let mut writer = Writer::new(&dir, &config, Access::ReadOnly).unwrap();
+        writer.force_baseline(Baseline::Full);
+        writer.write_line(Stream::Stdout, b"39").unwrap();
+        writer.select(Duration::from_millis(100), true);
+        assert_eq!(writer.baseline(), Baseline::Full);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prefix_match_fallback() {
+        let run = OutputData {
+            lines: vec![
+                line("Compiling foo v1.0.0 (/tmp/abc)", 100),
+                line("Compiling bar v1.0.0 (/tmp/abc)", 200),
+                line("Finished", 300),
+            ],
+            total: Duration::from_millis(400),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let mut orig =
+            OrigOutput::from_runs(iter::once(run).collect(), &Normalizer::default()).unwrap();
+        let key = |line: &str| (Stream::Stdout, line.as_bytes().to_owned());
+
+        // Prefix matching is only used once enabled.
+        let elapsed = Duration::from_secs(0);
+        let bar = key("Compiling bar v1.0.0 (/tmp/xyz)");
+        assert_eq!(orig.write_line(&bar, elapsed), (None, false));
+        orig.index_prefixes();
+        assert_eq!(
+            orig.match_prefix(&key("Compiling baz v2.0.0 (/tmp/abc)")),
+            None
+        );
+        assert_eq!(orig.match_prefix(&key("Finish")), None);
+        assert_eq!(
+            orig.write_line(&bar, elapsed),
+            (Some(Duration::from_millis(200)), true)
+        );
+        assert_eq!(orig.position, Duration::from_millis(200));
+
+        // Exact matches are preferred.
+        assert_eq!(
+            orig.write_line(&key("Finished"), elapsed),
+            (Some(Duration::from_millis(300)), true)
+        );
+    }
+
+    #[test]
+    fn smoothing_reduces_jitter() {
+        // Lines are recognized in bursts, so the raw position jumps every few ticks.
+        let raw: Vec<Duration> = [0, 0, 300, 300, 300, 700, 700, 800, 1200, 1200, 1200, 1500]
+            .iter()
+            .map(|&ms| Duration::from_millis(ms))
+            .collect();
+
+        let mut smoother = Smoother::new(0.5);
+        let smoothed: Vec<Duration> = raw
+            .iter()
+            .map(|&pos| {
+                smoother.update(pos);
+                smoother.value
+            })
+            .collect();
+
+        let max_step = |seq: &[Duration]| seq.windows(2).map(|w| w[1] - w[0]).max().unwrap();
+        assert!(max_step(&smoothed) < max_step(&raw));
+        assert!(smoothed.windows(2).all(|w| w[0] <= w[1]));
+        assert!(smoothed.iter().zip(&raw).all(|(s, r)| s <= r));
+    }
+
+    #[test]
+    fn ignored_lines_are_not_recorded() {
+        let config = Config {
+            ignore: vec![r"^Compiling ".to_owned()],
+            ..Config::default()
+        };
+        let dir = env::temp_dir();
+        let path = dir.join(format!("bp-missing-{}.json", std::process::id()));
+        let mut writer =
+            Writer::with_baseline(&dir, path, false, &config, Access::ReadOnly).unwrap();
+
+        for line in &["Compiling foo\n", "Compiling bar\n", "Finished\n"] {
+            let line_match = writer.write_line(Stream::Stdout, line.as_bytes()).unwrap();
+            assert!(!line_match.recognized);
+        }
+
+        let run = writer.curr.finish(None);
+        assert_eq!(
+            run.lines
+                .iter()
+                .map(|line| &line.data[..])
+                .collect::<Vec<_>>(),
+            vec![b"Finished".as_ref()]
+        );
+    }
+
+    #[test]
+    fn ignored_lines_are_skipped_in_baseline() {
+        let run = OutputData {
+            lines: vec![line("Compiling foo", 100), line("Finished", 200)],
+            total: Duration::from_millis(300),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let normalizer = Normalizer::new(&[], &[r"^Compiling ".to_owned()]).unwrap();
+        let mut orig = OrigOutput::from_runs(iter::once(run).collect(), &normalizer).unwrap();
+        assert_eq!(orig.durs, vec![Duration::from_millis(200)]);
+
+        orig.write_line(
+            &(Stream::Stdout, b"Finished".to_vec()),
+            Duration::from_secs(0),
+        );
+        assert_eq!(orig.position, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn indented_lines_match_with_trim_indent() {
+        let run = || OutputData {
+            lines: vec![
+                line("running tests", 100),
+                line("    running test foo", 200),
+                line("    running test bar", 300),
+            ],
+            total: Duration::from_millis(400),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let lines: &[&[u8]] = &[b"  running test foo\n", b"\trunning test bar\n"];
+
+        let write = |orig: &mut OrigOutput, normalizer: &Normalizer, line: &[u8]| {
+            let key = (Stream::Stdout, normalizer.key(line));
+            orig.write_line(&key, Duration::from_secs(0));
+            orig.position
+        };
+
+        let normalizer = Normalizer::default().trim_indent(true);
+        let mut orig = OrigOutput::from_runs(iter::once(run()).collect(), &normalizer).unwrap();
+        assert_eq!(
+            write(&mut orig, &normalizer, lines[0]),
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            write(&mut orig, &normalizer, lines[1]),
+            Duration::from_millis(300)
+        );
+
+        let normalizer = Normalizer::default();
+        let mut orig = OrigOutput::from_runs(iter::once(run()).collect(), &normalizer).unwrap();
+        for line in lines {
+            write(&mut orig, &normalizer, line);
+        }
+        assert_eq!(orig.position, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn line_fraction_fallback_when_stalled() {
+        let run = OutputData {
+            lines: (0..10)
+                .map(|idx| line(&idx.to_string(), 100 * idx))
+                .collect(),
+            total: Duration::from_millis(1000),
+            recorded_at: None,
+            sampled_from: None,
+        };
+        let mut orig =
+            OrigOutput::from_runs(iter::once(run).collect(), &Normalizer::default()).unwrap();
+
+        // Only half the lines of the baseline appear, and only the first is recognized.
+        orig.write_line(&(Stream::Stdout, b"5".to_vec()), Duration::from_millis(500));
+        for idx in 0..4 {
+            let line = format!("unrecognized {}", idx).into_bytes();
+            orig.write_line(&(Stream::Stdout, line), Duration::from_millis(600 + idx));
+        }
+        assert_eq!(orig.position, Duration::from_millis(500));
+        assert_eq!(orig.line_fraction(), 0.5);
+
+        orig.write_line(&(Stream::Stdout, b"6".to_vec()), Duration::from_millis(700));
+        assert_eq!(orig.position, Duration::from_millis(600));
+
+        // Once no lines have been recognized for a while, the line count is used instead.
+        for idx in 0..2 {
+            let line = format!("unrecognized {}", idx + 4).into_bytes();
+            orig.write_line(&(Stream::Stdout, line), Duration::from_secs(5));
+        }
+        assert_eq!(orig.line_fraction(), 0.8);
+        assert_eq!(orig.position, Duration::from_millis(800));
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use crate::cmd::{self, CommandOptions};
+use crate::config::Config;
+use crate::{diff, Result};
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub command: cmd::CommandOpts,
+    /// Print the estimate as HH:MM:SS instead of a number of seconds
+    #[structopt(long = "hms")]
+    pub hms: bool,
+}
+
+/// Print the expected duration of a command from its recorded runs, without running it. The
+/// output is a single line, for use in shell prompts and status bars.
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    let command = CommandOptions::new(&opts.command, &config)?;
+    log::trace!("command: {:#?}", command);
+
+    let dir = opts.command.data_dir(&command, &config)?;
+    let diff = diff::Writer::new(&dir, &config, diff::Access::ReadOnly)?;
+    let len = match diff.len() {
+        Some(len) => len,
+        None => {
+            log::info!("no runs of '{}' have been recorded", command);
+            return Ok(1);
+        }
+    };
+
+    println!("{}", fmt_eta(len, opts.hms));
+    Ok(0)
+}
+
+/// Format a duration as whole seconds, rounded to the nearest second.
+fn fmt_eta(dur: Duration, hms: bool) -> String {
+    let secs = (dur + Duration::from_millis(500)).as_secs();
+    if hms {
+        format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        secs.to_string()
+    }
+}
@@ -0,0 +1,142 @@
+use std::io::{self, Write};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use structopt::StructOpt;
+use tokio::runtime::Runtime;
+
+use crate::cmd::{self, CommandOptions};
+use crate::config::Config;
+use crate::diff::{self, LineMatch, Stream};
+use crate::output::Streams;
+use crate::{util, Result};
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub command: cmd::CommandOpts,
+    /// Print each line as a JSON object
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// How a line of output was used to estimate progress.
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    /// The line is in the baseline, and advanced the progress bar.
+    Recognized,
+    /// The line is in the baseline, but was output before lines which were recorded earlier
+    /// than it, so it was ignored.
+    Reordered,
+    /// The line is not in the baseline.
+    New,
+    /// The line is from an output stream which is not used to estimate progress.
+    Excluded,
+}
+
+#[derive(Debug, Serialize)]
+struct Explanation<'a> {
+    stream: Stream,
+    line: &'a str,
+    status: Status,
+    elapsed_ms: u64,
+    recorded_ms: Option<u64>,
+}
+
+/// Run the command, printing how each line of its output matches the recorded baseline. The
+/// baseline is not updated.
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    let command = CommandOptions::new(&opts.command, &config)?;
+    log::trace!("command: {:#?}", command);
+
+    let dir = opts.command.data_dir(&command, &config)?;
+    let diff = diff::Writer::new(&dir, &config, diff::Access::ReadOnly)?;
+    if diff.len().is_none() {
+        log::warn!("no runs of '{}' have been recorded", command);
+    }
+    if !opts.json {
+        println!(
+            "{:<10} {:>10} {:>10}  {:<6}  line",
+            "status", "elapsed", "recorded", "stream"
+        );
+    }
+
+    let diff = Arc::new(Mutex::new(diff));
+    let json = opts.json;
+    let progress_from = command.progress_from;
+    let (diff1, diff2) = (diff.clone(), diff.clone());
+    let (_, status_fut) = command.spawn(
+        Stdio::inherit(),
+        cmd::LineOptions::default(),
+        move |line| explain(&diff1, progress_from, Stream::Stdout, &line, json),
+        move |line| explain(&diff2, progress_from, Stream::Stderr, &line, json),
+    )?;
+
+    let status = Runtime::new()?.block_on(status_fut)?;
+    if !status.success() {
+        log::error!("process '{}' {}", command, cmd::describe_exit(status));
+    }
+    Ok(cmd::exit_code(status))
+}
+
+fn explain(
+    diff: &Mutex<diff::Writer>,
+    progress_from: Streams,
+    stream: Stream,
+    line: &[u8],
+    json: bool,
+) -> io::Result<()> {
+    let (status, elapsed, recorded) = if progress_from.contains(stream) {
+        let LineMatch {
+            elapsed,
+            recorded,
+            recognized,
+        } = diff
+            .lock()
+            .unwrap()
+            .write_line(stream, line)
+            .map_err(|err| io::Error::other(err.compat()))?;
+        let status = match recorded {
+            Some(_) if recognized => Status::Recognized,
+            Some(_) => Status::Reordered,
+            None => Status::New,
+        };
+        (status, elapsed, recorded)
+    } else {
+        let elapsed = diff.lock().unwrap().elapsed();
+        (Status::Excluded, elapsed, None)
+    };
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+    if json {
+        let explanation = Explanation {
+            stream,
+            line,
+            status,
+            elapsed_ms: elapsed.as_millis() as u64,
+            recorded_ms: recorded.map(|dur| dur.as_millis() as u64),
+        };
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        json::to_writer(&mut stdout, &explanation)?;
+        writeln!(stdout)
+    } else {
+        writeln!(
+            io::stdout(),
+            "{:<10} {:>10} {:>10}  {:<6}  {}",
+            format!("{:?}", status).to_lowercase(),
+            fmt_duration(elapsed),
+            recorded.map(fmt_duration).unwrap_or_else(|| "-".to_owned()),
+            stream,
+            line
+        )
+    }
+}
+
+fn fmt_duration(dur: Duration) -> String {
+    humantime::format_duration(util::truncate_millis(dur)).to_string()
+}
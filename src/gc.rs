@@ -0,0 +1,171 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use failure::{Fail, ResultExt};
+use lazy_static::lazy_static;
+use regex::Regex;
+use structopt::StructOpt;
+
+use crate::cmd;
+use crate::diff;
+use crate::hash;
+use crate::output;
+use crate::Result;
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub data_dir: cmd::DataDirOpts,
+    /// Remove data for commands which have not been run within the given duration (e.g. "30days")
+    #[structopt(
+        name = "OLDER_THAN",
+        long = "older-than",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    pub older_than: Option<Duration>,
+    /// Remove data for commands run in the given directory or its subdirectories
+    #[structopt(name = "WORKDIR", long = "workdir", parse(from_os_str))]
+    pub workdir: Option<PathBuf>,
+    /// List the directories which would be removed, without removing them
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+}
+
+/// Remove data directories created by older versions of the hashing scheme, which will never
+/// be used again. Directories which don't look like they were created by bp are left alone.
+/// If `--older-than` or `--workdir` are given, directories for commands matching all of them
+/// are also removed.
+pub fn run(opts: &Opts) -> Result<i32> {
+    let base = opts.data_dir.base()?;
+    let entries = match fs::read_dir(&base) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            log::debug!("data directory '{}' does not exist", base.display());
+            return Ok(0);
+        }
+        Err(err) => {
+            return Err(err
+                .context(format!("failed to read directory '{}'", base.display()))
+                .into());
+        }
+    };
+
+    for entry in entries {
+        let entry =
+            entry.with_context(|_| format!("failed to read directory '{}'", base.display()))?;
+        let path = entry.path();
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let stale = !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(hash::PREFIX);
+        if stale && !is_data_dir(&entry.file_name().to_string_lossy(), &path) {
+            // The data directory may be shared with unrelated files, which must never be
+            // removed.
+            log::info!(
+                "skipping directory '{}', which was not created by bp",
+                path.display()
+            );
+            continue;
+        }
+        if !stale {
+            match is_match(opts, &path) {
+                Ok(true) => (),
+                Ok(false) => {
+                    log::trace!("keeping directory '{}'", path.display());
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!("{}", crate::fmt_error(&err));
+                    continue;
+                }
+            }
+        }
+
+        if opts.dry_run {
+            println!("{}", path.display());
+            continue;
+        }
+        fs::remove_dir_all(&path)
+            .with_context(|_| format!("failed to remove directory '{}'", path.display()))?;
+        log::info!("removed directory '{}'", path.display());
+    }
+
+    Ok(0)
+}
+
+/// Files which bp writes to the data directory of a command, in this or older versions.
+const DATA_FILES: &[&str] = &["orig.json", "orig.json.lock", ".lock", "command.toml"];
+
+/// Check whether a directory was created by bp, because its name has the prefix of an older
+/// hashing scheme, such as `v0-`, or it contains files which bp writes.
+fn is_data_dir(name: &str, dir: &Path) -> bool {
+    lazy_static! {
+        static ref PREFIX_RE: Regex = Regex::new(r"^v\d+-").unwrap();
+    }
+
+    PREFIX_RE.is_match(name) || DATA_FILES.iter().any(|file| dir.join(file).exists())
+}
+
+/// Check whether the data directory for a command matches the filters given on the command
+/// line.
+fn is_match(opts: &Opts, dir: &Path) -> Result<bool> {
+    if opts.older_than.is_none() && opts.workdir.is_none() {
+        return Ok(false);
+    }
+
+    if let Some(workdir) = &opts.workdir {
+        let workdir = env::current_dir()
+            .context("failed to get current directory")?
+            .join(workdir);
+        let path = dir.join("command").with_extension("toml");
+        if !path.exists() {
+            // Pipelines don't record a command, since they are identified by name only.
+            return Ok(false);
+        }
+        let command = output::read_command(&path)?;
+        log::trace!("command: {:#?}", command);
+        if !command.workdir.starts_with(&workdir) {
+            return Ok(false);
+        }
+    }
+
+    if let Some(older_than) = opts.older_than {
+        let last_run = last_run(dir)?;
+        let age = SystemTime::now()
+            .duration_since(last_run)
+            .unwrap_or_default();
+        if age < older_than {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Get the time a command was last run, using the timestamp recorded in its baseline or
+/// otherwise the time its data directory was last modified.
+fn last_run(dir: &Path) -> Result<SystemTime> {
+    let runs = diff::load(dir)?;
+    if let Some(recorded_at) = runs.back().and_then(|run| run.recorded_at()) {
+        return Ok(recorded_at);
+    }
+
+    let mut last_run = SystemTime::UNIX_EPOCH;
+    for entry in fs::read_dir(dir)
+        .with_context(|_| format!("failed to read directory '{}'", dir.display()))?
+    {
+        let path = entry?.path();
+        let modified = fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .with_context(|_| format!("failed to get metadata for file '{}'", path.display()))?;
+        last_run = last_run.max(modified);
+    }
+    Ok(last_run)
+}
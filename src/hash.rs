@@ -1,8 +1,13 @@
+use std::ascii;
 use std::hash::Hash;
 use std::io::Write;
 
 use crypto_hash::{Algorithm, Hasher};
 
+/// The prefix of hashes created with the current version of the hashing scheme. The version
+/// should be incremented whenever the hashed data changes.
+pub const PREFIX: &str = "v1-";
+
 pub fn hash<H>(data: H) -> String
 where
     H: Hash,
@@ -12,6 +17,25 @@ where
     hasher.finish()
 }
 
+/// Get the bytes which are hashed by `hash`.
+pub fn inputs<H>(data: H) -> Vec<u8>
+where
+    H: Hash,
+{
+    let mut hasher = Inputs(Vec::new());
+    data.hash(&mut hasher);
+    hasher.0
+}
+
+/// Escape hash inputs for display.
+pub fn escape(inputs: &[u8]) -> String {
+    let bytes: Vec<u8> = inputs
+        .iter()
+        .flat_map(|&byte| ascii::escape_default(byte))
+        .collect();
+    String::from_utf8(bytes).unwrap()
+}
+
 struct Sha256(Hasher);
 
 impl Sha256 {
@@ -29,3 +53,15 @@ impl std::hash::Hasher for Sha256 {
         unreachable!()
     }
 }
+
+struct Inputs(Vec<u8>);
+
+impl std::hash::Hasher for Inputs {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!()
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use failure::{Fail, ResultExt};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::cmd::{self, CommandOptions};
+use crate::config::Config;
+use crate::{util, Result};
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub command: cmd::CommandOpts,
+    /// The maximum number of runs to show, starting from the most recent
+    #[structopt(name = "LIMIT", long = "limit", short = "n")]
+    pub limit: Option<usize>,
+    /// Print the history as JSON
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// A record of a single run of a command.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub recorded_at: SystemTime,
+    pub total: Duration,
+    pub success: bool,
+    /// The duration predicted by the baseline when the run started, if there was one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicted: Option<Duration>,
+}
+
+/// The maximum number of runs kept in the history file.
+const MAX_RECORDS: usize = 1000;
+
+/// The number of recent runs over which the accuracy of predictions is measured.
+const ACCURACY_RUNS: usize = 10;
+
+/// Get the mean difference between the predicted and actual durations of recent successful
+/// runs, as a fraction of the prediction, and the number of runs it was measured over.
+pub fn prediction_error(records: &[Record]) -> Option<(f64, usize)> {
+    let errors: Vec<f64> = records
+        .iter()
+        .rev()
+        .filter(|record| record.success)
+        .filter_map(|record| Some(relative_error(record.predicted?, record.total)?.abs()))
+        .take(ACCURACY_RUNS)
+        .collect();
+    if errors.is_empty() {
+        return None;
+    }
+    Some((
+        errors.iter().sum::<f64>() / errors.len() as f64,
+        errors.len(),
+    ))
+}
+
+/// Get the difference between the actual and predicted duration, as a fraction of the
+/// prediction.
+pub fn relative_error(predicted: Duration, actual: Duration) -> Option<f64> {
+    if predicted == Duration::default() {
+        return None;
+    }
+    Some((actual.as_secs_f64() - predicted.as_secs_f64()) / predicted.as_secs_f64())
+}
+
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    let command = CommandOptions::new(&opts.command, &config)?;
+    log::trace!("command: {:#?}", command);
+
+    let path = opts
+        .command
+        .data_dir(&command, &config)?
+        .join("history")
+        .with_extension("json");
+    let mut records = read(&path)?;
+    if let Some(limit) = opts.limit {
+        if records.len() > limit {
+            records.drain(..records.len() - limit);
+        }
+    }
+
+    if opts.json {
+        json::to_writer_pretty(io::stdout(), &records)?;
+        println!();
+    } else if records.is_empty() {
+        log::info!("no runs of '{}' have been recorded", command);
+    } else {
+        println!("{:<24} {:>12}  status", "recorded at", "duration");
+        for record in &records {
+            println!(
+                "{:<24} {:>12}  {}",
+                humantime::format_rfc3339_seconds(record.recorded_at).to_string(),
+                humantime::format_duration(util::truncate_millis(record.total)).to_string(),
+                if record.success { "success" } else { "failure" },
+            );
+        }
+    }
+
+    Ok(0)
+}
+
+/// Read the history file at the given path, returning an empty history if it does not exist.
+pub fn read(path: &Path) -> Result<Vec<Record>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err
+                .context(format!("failed to open file '{}'", path.display()))
+                .into());
+        }
+    };
+    let records = json::from_reader(BufReader::new(file))
+        .with_context(|_| format!("failed to read JSON file '{}'", path.display()))?;
+    Ok(records)
+}
+
+/// Append a record to the history file at the given path, dropping the oldest records beyond
+/// `MAX_RECORDS`. The file is written to a temporary file and then moved into place, so it is
+/// left intact if writing fails part way through. If the existing file is corrupt, it is backed
+/// up and a new history is started.
+pub fn append(path: &Path, record: Record) -> Result<()> {
+    let mut records = match fs::read(path) {
+        Ok(bytes) => match json::from_slice(&bytes) {
+            Ok(records) => records,
+            Err(err) => {
+                let backup_path = util::with_suffix(path, ".corrupt");
+                util::replace_file(path, &backup_path).with_context(|_| {
+                    format!(
+                        "failed to move file '{}' to '{}'",
+                        path.display(),
+                        backup_path.display()
+                    )
+                })?;
+                log::warn!(
+                    "failed to read JSON file '{}' ({}), it has been moved to '{}'",
+                    path.display(),
+                    err,
+                    backup_path.display()
+                );
+                Vec::new()
+            }
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            return Err(err
+                .context(format!("failed to read file '{}'", path.display()))
+                .into());
+        }
+    };
+    records.push(record);
+    if records.len() > MAX_RECORDS {
+        records.drain(..records.len() - MAX_RECORDS);
+    }
+
+    let tmp_path = util::with_suffix(path, ".tmp");
+    let bytes = json::to_vec(&records)?;
+    fs::write(&tmp_path, bytes)
+        .with_context(|_| format!("failed to write to file '{}'", tmp_path.display()))?;
+    util::replace_file(&tmp_path, path).with_context(|_| {
+        format!(
+            "failed to move file '{}' to '{}'",
+            tmp_path.display(),
+            path.display()
+        )
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn append_drops_oldest_records() {
+        let dir = env::temp_dir().join(format!("bp-history-cap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.json");
+        let record = |secs: u64| Record {
+            recorded_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            total: Duration::from_secs(1),
+            success: true,
+            predicted: None,
+        };
+        let records: Vec<Record> = (0..MAX_RECORDS as u64).map(record).collect();
+        fs::write(&path, json::to_vec(&records).unwrap()).unwrap();
+
+        append(&path, record(MAX_RECORDS as u64)).unwrap();
+        let records = read(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let records = records.unwrap();
+        assert_eq!(records.len(), MAX_RECORDS);
+        assert_eq!(records[0].recorded_at, record(1).recorded_at);
+        assert_eq!(
+            records[MAX_RECORDS - 1].recorded_at,
+            record(MAX_RECORDS as u64).recorded_at
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Estimates the progress of long-running commands from the output of previous runs.
+//!
+//! This is the library behind the `bp` command line tool. A [`Session`] can be used to embed
+//! its progress estimate in other tools, which run the command themselves:
+//!
+//! ```no_run
+//! use std::env;
+//! use std::path::Path;
+//!
+//! use build_progress::{CommandOptions, Config, Session};
+//!
+//! # fn main() -> build_progress::Result<()> {
+//! let command = CommandOptions::from_args(vec!["make".into()], env::current_dir()?);
+//! let mut session = Session::new(&command, Path::new("target/bp"), &Config::default())?;
+//! session.write_stdout(b"Compiling foo\n")?;
+//! if let Some(len) = session.len() {
+//!     println!("{:?} of {:?} completed", session.completed(), len);
+//! }
+//! session.finish(true)?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod archive;
+mod bench;
+#[doc(hidden)]
+pub mod cli;
+mod cmd;
+mod compare;
+mod config;
+mod diff;
+mod eta;
+mod explain;
+mod gc;
+mod hash;
+mod history;
+mod logger;
+mod normalize;
+mod notify;
+mod os_str;
+mod output;
+mod path;
+mod pipeline;
+mod session;
+mod stats;
+mod util;
+mod watch;
+
+pub use crate::cmd::CommandOptions;
+pub use crate::config::Config;
+pub use crate::session::Session;
+
+pub type Error = failure::Error;
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn fmt_error(err: &Error) -> String {
+    let mut pretty = err.to_string();
+    for cause in err.iter_causes() {
+        pretty.push_str(&format!("\ncaused by: {}", cause));
+    }
+    pretty
+}
@@ -1,54 +1,209 @@
+use std::env;
 use std::fmt::Display;
+use std::io::{self, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::SystemTime;
 
 use console::{style, Term};
+use failure::{bail, format_err};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use lazy_static::lazy_static;
 use log::Log;
+use regex::Regex;
 use structopt::StructOpt;
 
-pub fn init(opts: Opts) {
-    log::set_max_level(opts.level_filter());
-    log::set_logger(&LOGGER as &Logger).unwrap();
+use crate::config::{BarWidth, ProgressConfig};
+use crate::Result;
+
+/// The logger installed by `init`. The functions in this module write to it, and do nothing
+/// if no logger has been installed.
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Install the logger, writing messages and the progress bar to `term`. With `record_only`, the
+/// progress bar and informational messages are hidden.
+pub fn init(opts: Opts, term: Term, record_only: bool) {
+    let logger = LOGGER.get_or_init(|| {
+        let mut logger = Logger::new(opts, term);
+        if record_only {
+            logger.record_only();
+        }
+        logger
+    });
+    match opts.color {
+        ColorChoice::Always => console::set_colors_enabled(true),
+        ColorChoice::Never => console::set_colors_enabled(false),
+        ColorChoice::Auto => {
+            // See https://no-color.org/
+            let no_color = env::var_os("NO_COLOR").is_some_and(|val| !val.is_empty());
+            if no_color || !logger.term.is_term() {
+                console::set_colors_enabled(false);
+            }
+        }
+    }
+    log::set_max_level(logger.level);
+    log::set_logger(logger).unwrap();
+}
+
+fn with_logger<F>(f: F)
+where
+    F: FnOnce(&Logger),
+{
+    if let Some(logger) = LOGGER.get() {
+        f(logger);
+    }
 }
 
 pub fn log_bytes<B>(bytes: B)
 where
     B: AsRef<[u8]>,
 {
-    if log::max_level() >= log::Level::Info {
-        let mut bytes = bytes.as_ref();
-        if bytes.ends_with(b"\n") {
-            bytes = &bytes[..bytes.len() - 1];
-        }
-        if bytes.ends_with(b"\r") {
-            bytes = &bytes[..bytes.len() - 1];
-        }
-        LOGGER.write_raw(String::from_utf8_lossy(bytes));
-    }
+    with_logger(|logger| logger.log_bytes(bytes));
+}
+
+/// Write the child's stdout bytes to stdout unmodified.
+pub fn tee_stdout(bytes: &[u8]) {
+    with_logger(|logger| logger.tee(bytes, io::stdout(), Term::stdout().is_term()));
+}
+
+/// Write the child's stderr bytes to stderr unmodified.
+pub fn tee_stderr(bytes: &[u8]) {
+    with_logger(|logger| logger.tee(bytes, io::stderr(), Term::stderr().is_term()));
+}
+
+pub fn print_summary<D>(summary: D)
+where
+    D: Display,
+{
+    with_logger(|logger| logger.print_summary(summary));
 }
 
-pub fn start_progress(len: u64, msg: &str) {
-    LOGGER
-        .progress
-        .set_draw_target(ProgressDrawTarget::to_term(LOGGER.term.clone(), None));
-    LOGGER.progress.set_length(len);
-    LOGGER.progress.set_message(msg);
+pub fn start_progress(len: u64, msg: &str, config: &ProgressConfig) {
+    with_logger(|logger| logger.start_progress(len, msg, config));
 }
 
 pub fn tick_progress_bar() {
-    LOGGER.progress.tick();
+    with_logger(Logger::tick_progress_bar);
+}
+
+/// Check whether the progress bar is shown, which requires the logger to write to a terminal.
+pub fn is_term() -> bool {
+    LOGGER.get().is_some_and(|logger| logger.term.is_term())
+}
+
+/// Check whether all output is disabled with `--silent`.
+pub fn is_silent() -> bool {
+    LOGGER.get().is_some_and(|logger| logger.silent)
+}
+
+/// Ring the terminal bell, unless output is limited with `--quiet` or `--silent`.
+pub fn ring_bell() {
+    with_logger(Logger::ring_bell);
+}
+
+pub fn set_progress_length(len: u64, msg: &str) {
+    with_logger(|logger| logger.set_progress_length(len, msg));
 }
 
 pub fn set_progress_position(pos: u64) {
-    LOGGER.progress.set_position(pos);
+    with_logger(|logger| logger.set_progress_position(pos));
+}
+
+pub fn set_progress_eta<D>(eta: D)
+where
+    D: Display,
+{
+    with_logger(|logger| logger.set_progress_eta(eta));
 }
 
 pub fn finish_progress() {
-    LOGGER.progress.finish();
+    with_logger(Logger::finish_progress);
 }
 
-lazy_static! {
-    static ref LOGGER: Logger = Logger::new();
+/// Keys recognized in `indicatif` templates.
+const TEMPLATE_KEYS: &[&str] = &[
+    "bar",
+    "wide_bar",
+    "spinner",
+    "msg",
+    "wide_msg",
+    "prefix",
+    "pos",
+    "len",
+    "percent",
+    "bytes",
+    "total_bytes",
+    "decimal_bytes",
+    "decimal_total_bytes",
+    "binary_bytes",
+    "binary_total_bytes",
+    "elapsed_precise",
+    "elapsed",
+    "eta_precise",
+    "eta",
+];
+
+fn progress_template(config: &ProgressConfig) -> String {
+    match &config.template {
+        Some(template) => match validate_template(template) {
+            Ok(()) => template.clone(),
+            Err(err) => {
+                log::warn!("invalid progress template, using default: {}", err);
+                default_template(config.bar_width)
+            }
+        },
+        None => default_template(config.bar_width),
+    }
+}
+
+fn progress_style(config: &ProgressConfig, template: &str) -> ProgressStyle {
+    let mut progress_chars = config.progress_chars.as_str();
+    if progress_chars.chars().count() < 2 {
+        log::warn!("progress_chars must contain at least 2 characters, using default");
+        progress_chars = "=> ";
+    }
+
+    ProgressStyle::default_bar()
+        .template(template)
+        .progress_chars(progress_chars)
+}
+
+fn default_template(bar_width: BarWidth) -> String {
+    let bar = match bar_width {
+        BarWidth::Auto => "{wide_bar:.bold}".to_owned(),
+        BarWidth::Fixed(width) => format!("{{bar:{}.bold}}", width),
+    };
+    format!(
+        "[{}] {{percent:>3.bold}}% / {}: {{elapsed:bold}} / {}: {{msg:bold}} / {}: {{prefix:bold}}",
+        bar,
+        style("elapsed").green(),
+        style("total").green(),
+        style("remaining").green(),
+    )
+}
+
+/// Check a template for unknown keys and unbalanced braces, which `indicatif` silently
+/// ignores.
+fn validate_template(template: &str) -> Result<()> {
+    lazy_static! {
+        static ref VAR_RE: Regex = Regex::new(r"\{\{|\}\}|\{([^{}]*)\}|[{}]").unwrap();
+    }
+
+    for caps in VAR_RE.captures_iter(template) {
+        match caps.get(1) {
+            Some(var) => {
+                let key = var.as_str().split(':').next().unwrap_or_default();
+                if !TEMPLATE_KEYS.contains(&key) {
+                    bail!("unknown key '{}'", key);
+                }
+            }
+            None if caps[0].len() == 1 => bail!("unmatched '{}'", &caps[0]),
+            None => (),
+        }
+    }
+    Ok(())
 }
 
 #[derive(Copy, Clone, Debug, StructOpt)]
@@ -67,18 +222,132 @@ pub struct Opts {
         global = true
     )]
     trace: bool,
-    #[structopt(long, short, help = "Disable logging", global = true)]
+    #[structopt(
+        long,
+        short,
+        help = "Disable logging, except for a summary printed to stderr when the command finishes",
+        global = true
+    )]
     quiet: bool,
+    #[structopt(
+        long,
+        help = "Disable all output, including the summary printed with '--quiet'",
+        global = true
+    )]
+    silent: bool,
+    #[structopt(
+        long = "progress-json",
+        help = "Write progress to stderr as newline-delimited JSON instead of drawing a progress bar",
+        global = true
+    )]
+    progress_json: bool,
+    #[structopt(
+        long = "osc-progress",
+        help = "Report progress to the terminal using OSC 9;4 escape sequences, which Windows \
+                Terminal and ConEmu show in the taskbar. Ignored on other terminals",
+        global = true
+    )]
+    osc_progress: bool,
+    #[structopt(
+        name = "WHEN",
+        long = "color",
+        default_value = "auto",
+        raw(possible_values = r#"&["auto", "always", "never"]"#),
+        help = "Controls when to use colored output. Colors are disabled by default if the \
+                NO_COLOR environment variable is set or output is not a terminal",
+        global = true
+    )]
+    color: ColorChoice,
+    #[structopt(
+        name = "FORMAT",
+        long = "log-format",
+        default_value = "text",
+        raw(possible_values = r#"&["text", "json"]"#),
+        help = "The format of log messages. With 'json', each message is written as a JSON \
+                object on its own line. The command's output is not affected",
+        global = true
+    )]
+    log_format: LogFormat,
+    #[structopt(
+        name = "FD",
+        long = "progress-fd",
+        raw(possible_values = r#"&["stdout", "stderr"]"#),
+        help = "Where to write messages and the progress bar, which are always written together. \
+                Defaults to stdout, or stderr if the command's output is written to stdout with \
+                '--output -', in which case 'stdout' is not allowed",
+        global = true
+    )]
+    progress_fd: Option<ProgressFd>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
-struct Logger {
+/// The stream which messages and the progress bar are written to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProgressFd {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Writes log messages and the progress bar to a terminal. Each logger has its own progress
+/// bar, so several can be used at once, but only the one installed by `init` receives messages
+/// from the `log` macros.
+pub struct Logger {
     term: Term,
-    progress: ProgressBar,
+    /// Where progress events are written with `--progress-json`.
+    progress_out: Mutex<Box<dyn Write + Send>>,
+    level: log::LevelFilter,
+    progress_json: bool,
+    log_json: bool,
+    summary: bool,
+    silent: bool,
+    osc_progress: bool,
+    /// Whether the progress bar is never drawn, with `--record-only`.
+    progress_disabled: bool,
+    progress: RwLock<ProgressBar>,
+    progress_len: AtomicU64,
+    /// Whether the progress bar template fills the width of the terminal.
+    progress_wide: AtomicBool,
+    /// The terminal width when the progress bar was last ticked, or 0 if unknown.
+    term_width: AtomicU64,
+    /// The last percentage sent with an OSC sequence, or `u64::MAX` if none has been sent.
+    osc_percent: AtomicU64,
+}
+
+/// The OSC 9;4 state which removes the progress indicator.
+const OSC_PROGRESS_CLEAR: u8 = 0;
+/// The OSC 9;4 state which sets the progress indicator to a percentage.
+const OSC_PROGRESS_SET: u8 = 1;
+
+#[derive(serde::Serialize)]
+struct LogEvent<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    message: String,
+}
+
+#[derive(serde::Serialize)]
+struct ProgressEvent {
+    elapsed_ms: u64,
+    total_ms: u64,
+    fraction: f64,
 }
 
 impl Opts {
     fn level_filter(self) -> log::LevelFilter {
-        if self.quiet {
+        if self.quiet || self.silent {
             log::LevelFilter::Off
         } else if self.trace {
             log::LevelFilter::Trace
@@ -90,21 +359,271 @@ impl Opts {
     }
 }
 
+impl Opts {
+    /// The stream given by '--progress-fd', if any.
+    pub fn progress_fd(self) -> Option<ProgressFd> {
+        self.progress_fd
+    }
+}
+
+impl ProgressFd {
+    pub fn term(self) -> Term {
+        match self {
+            ProgressFd::Stdout => Term::stdout(),
+            ProgressFd::Stderr => Term::stderr(),
+        }
+    }
+}
+
+impl FromStr for ProgressFd {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stdout" => Ok(ProgressFd::Stdout),
+            "stderr" => Ok(ProgressFd::Stderr),
+            _ => Err(format_err!("invalid stream '{}'", s)),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format_err!("invalid log format '{}'", s)),
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format_err!("invalid color choice '{}'", s)),
+        }
+    }
+}
+
 impl Logger {
-    fn new() -> Self {
-        let progress = ProgressBar::hidden();
-        progress.set_style(
-            ProgressStyle::default_bar()
-                .template(&format!(
-                    "[{{bar:64.bold}}] {}: {{elapsed:bold}} / {}: {{msg:bold}}",
-                    style("elapsed").green(),
-                    style("total").green(),
-                ))
-                .progress_chars("=> "),
-        );
+    /// Create a logger which writes to `term`, and writes progress events to stderr.
+    pub fn new(opts: Opts, term: Term) -> Self {
+        Logger::with_output(opts, term, Box::new(io::stderr()))
+    }
+
+    /// Create a logger which writes to `term`, and writes progress events to `progress_out`.
+    pub fn with_output(opts: Opts, term: Term, progress_out: Box<dyn Write + Send>) -> Self {
+        // Check whether the terminal is known to support OSC 9;4 progress sequences. Other
+        // terminals may print them as garbage, so only Windows Terminal and ConEmu are
+        // detected.
+        let osc_supported = term.is_term()
+            && (env::var_os("WT_SESSION").is_some()
+                || env::var_os("ConEmuANSI").is_some_and(|val| val == "ON"));
+
         Logger {
-            term: Term::stdout(),
-            progress,
+            term,
+            progress_out: Mutex::new(progress_out),
+            level: opts.level_filter(),
+            progress_json: opts.progress_json,
+            log_json: match opts.log_format {
+                LogFormat::Text => false,
+                LogFormat::Json => true,
+            },
+            summary: opts.quiet && !opts.silent,
+            silent: opts.silent,
+            osc_progress: opts.osc_progress && osc_supported,
+            progress_disabled: false,
+            progress: RwLock::new(ProgressBar::hidden()),
+            progress_len: AtomicU64::new(0),
+            progress_wide: AtomicBool::new(false),
+            term_width: AtomicU64::new(0),
+            osc_percent: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    /// Hide the progress bar and informational messages, so only the command's output and any
+    /// warnings or errors are shown.
+    pub fn record_only(&mut self) {
+        self.level = self.level.min(log::LevelFilter::Warn);
+        self.osc_progress = false;
+        self.progress_disabled = true;
+    }
+
+    /// Print a line of the command's output above the progress bar. Unlike messages logged by
+    /// `bp` itself, the line is printed as it is, without a prefix or indentation, except that
+    /// invalid UTF-8 is replaced.
+    pub fn log_bytes<B>(&self, bytes: B)
+    where
+        B: AsRef<[u8]>,
+    {
+        if self.level >= log::Level::Info {
+            let mut bytes = bytes.as_ref();
+            if bytes.ends_with(b"\n") {
+                bytes = &bytes[..bytes.len() - 1];
+            }
+            if bytes.ends_with(b"\r") {
+                bytes = &bytes[..bytes.len() - 1];
+            }
+            self.write_raw(String::from_utf8_lossy(bytes));
+        }
+    }
+
+    /// Print a summary of the run to stderr. This is only shown with `--quiet`, since otherwise
+    /// the same information has already been logged.
+    pub fn print_summary<D>(&self, summary: D)
+    where
+        D: Display,
+    {
+        if self.summary {
+            eprintln!("{}", summary);
+        }
+    }
+
+    /// Ring the bell, if writing to a terminal. Otherwise the bell character would end up in
+    /// redirected output.
+    pub fn ring_bell(&self) {
+        // `summary` is set by `--quiet`, which only prints the summary.
+        if !self.silent && !self.summary && self.term.is_term() {
+            self.term.write_str("\x07").ok();
+        }
+    }
+
+    pub fn start_progress(&self, len: u64, msg: &str, config: &ProgressConfig) {
+        self.progress_len.store(len, Ordering::Relaxed);
+        if self.progress_json || self.progress_disabled {
+            return;
+        }
+
+        // A new bar is created each time progress is started, so that the elapsed time is
+        // reset if the command is retried.
+        let progress = ProgressBar::with_draw_target(
+            len,
+            ProgressDrawTarget::to_term(self.term.clone(), None),
+        );
+        let template = progress_template(config);
+        self.progress_wide
+            .store(template.contains("{wide_"), Ordering::Relaxed);
+        progress.set_style(progress_style(config, &template));
+        progress.set_message(msg);
+        let prev = mem::replace(&mut *self.progress.write().unwrap(), progress);
+        if !prev.is_hidden() {
+            prev.finish_and_clear();
+        }
+    }
+
+    pub fn tick_progress_bar(&self) {
+        self.check_resize();
+        self.progress().tick();
+    }
+
+    /// Change the length of the progress bar, if it has been started.
+    pub fn set_progress_length(&self, len: u64, msg: &str) {
+        let prev = self.progress_len.load(Ordering::Relaxed);
+        if prev == 0 || prev == len {
+            return;
+        }
+        self.progress_len.store(len, Ordering::Relaxed);
+        let progress = self.progress();
+        progress.set_length(len);
+        progress.set_message(msg);
+    }
+
+    pub fn set_progress_position(&self, pos: u64) {
+        if self.osc_progress {
+            self.write_osc_progress(pos);
+        }
+        if self.progress_json {
+            self.write_progress_json(pos);
+        } else {
+            self.progress().set_position(pos);
+        }
+    }
+
+    pub fn set_progress_eta<D>(&self, eta: D)
+    where
+        D: Display,
+    {
+        self.progress().set_prefix(&format!("{:#}", eta));
+    }
+
+    pub fn finish_progress(&self) {
+        self.progress().finish();
+        if self.osc_progress && self.osc_percent.swap(u64::MAX, Ordering::Relaxed) != u64::MAX {
+            self.write_osc(OSC_PROGRESS_CLEAR, 0);
+        }
+    }
+
+    fn progress(&self) -> ProgressBar {
+        self.progress.read().unwrap().clone()
+    }
+
+    /// Poll the terminal size, since there is no portable way to be notified of resizes. When
+    /// the terminal is narrowed, most terminals reflow a bar which filled the old width onto
+    /// several rows, but indicatif only clears the last one when redrawing. The extra rows are
+    /// cleared here, and the cursor is left so indicatif's redraw starts from the first of them.
+    fn check_resize(&self) {
+        let width = match self.term.size_checked() {
+            Some((_, width)) => u64::from(width),
+            None => return,
+        };
+        let prev = self.term_width.swap(width, Ordering::Relaxed);
+        if prev == width || prev == 0 || width == 0 {
+            return;
+        }
+
+        let progress = self.progress();
+        if width < prev && self.progress_wide.load(Ordering::Relaxed) && !progress.is_hidden() {
+            let rows = prev.div_ceil(width);
+            self.term.clear_last_lines(rows as usize).ok();
+            self.term.move_cursor_down(1).ok();
+        }
+        log::trace!("terminal resized from {} to {} columns", prev, width);
+    }
+
+    fn write_osc_progress(&self, pos: u64) {
+        let len = self.progress_len.load(Ordering::Relaxed);
+        if len == 0 {
+            // Progress has not been started.
+            return;
+        }
+
+        let percent = (pos.saturating_mul(100) / len).min(100);
+        if self.osc_percent.swap(percent, Ordering::Relaxed) != percent {
+            self.write_osc(OSC_PROGRESS_SET, percent);
+        }
+    }
+
+    fn write_osc(&self, state: u8, percent: u64) {
+        self.term
+            .write_str(&format!("\x1b]9;4;{};{}\x07", state, percent))
+            .ok();
+    }
+
+    /// Progress events are written to stderr by default, so they never interleave with log
+    /// messages on stdout.
+    fn write_progress_json(&self, pos: u64) {
+        let len = self.progress_len.load(Ordering::Relaxed);
+        if len == 0 {
+            // Progress has not been started.
+            return;
+        }
+
+        let event = ProgressEvent {
+            elapsed_ms: pos,
+            total_ms: len,
+            fraction: (pos as f64 / len as f64).min(1.0),
+        };
+
+        let mut out = self.progress_out.lock().unwrap();
+        if json::to_writer(&mut *out, &event).is_ok() {
+            writeln!(out).ok();
         }
     }
 
@@ -124,25 +643,63 @@ impl Logger {
         }
     }
 
+    /// Write bytes to `dst`, which is a terminal if `dst_is_term` is set.
+    fn tee<W>(&self, bytes: &[u8], mut dst: W, dst_is_term: bool)
+    where
+        W: Write,
+    {
+        // If `dst` is redirected, the bytes can't corrupt the progress bar, even if it is
+        // drawn to the other stream.
+        if self.progress().is_hidden() || !dst_is_term {
+            dst.write_all(bytes).ok();
+            dst.flush().ok();
+        } else {
+            // Writing directly to the terminal would corrupt the progress bar, so the line is
+            // printed above it instead.
+            let mut bytes = bytes;
+            if bytes.ends_with(b"\n") {
+                bytes = &bytes[..bytes.len() - 1];
+            }
+            if bytes.ends_with(b"\r") {
+                bytes = &bytes[..bytes.len() - 1];
+            }
+            self.progress().println(String::from_utf8_lossy(bytes));
+        }
+    }
+
     fn write_raw<S>(&self, msg: S)
     where
         S: Into<String> + AsRef<str>,
     {
-        if self.progress.is_hidden() {
+        if self.progress().is_hidden() {
             self.term.write_line(msg.as_ref()).ok();
         } else {
-            self.progress.println(msg);
+            self.progress().println(msg);
         }
     }
 }
 
 impl Log for Logger {
     fn enabled(&self, meta: &log::Metadata) -> bool {
-        meta.target() == "bp" || meta.target().starts_with("bp::")
+        meta.level() <= self.level
+            && (meta.target() == "build_progress" || meta.target().starts_with("build_progress::"))
     }
 
     fn log(&self, record: &log::Record) {
         if self.enabled(&record.metadata()) {
+            if self.log_json {
+                let event = LogEvent {
+                    timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                    level: record.level().to_string().to_lowercase(),
+                    target: record.target(),
+                    message: record.args().to_string(),
+                };
+                if let Ok(json) = json::to_string(&event) {
+                    self.write_raw(json);
+                }
+                return;
+            }
+
             let prefix = match record.level() {
                 log::Level::Trace => style("trace").bold(),
                 log::Level::Debug => style("debug").cyan(),
@@ -158,3 +715,66 @@ impl Log for Logger {
 
     fn flush(&self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn enabled_respects_level_for_submodules() {
+        let logger = Logger::new(Opts::from_iter(&["bp"]), Term::stdout());
+        let meta = |target, level| {
+            log::MetadataBuilder::new()
+                .target(target)
+                .level(level)
+                .build()
+        };
+
+        assert!(logger.enabled(&meta("build_progress", log::Level::Info)));
+        assert!(logger.enabled(&meta("build_progress::diff", log::Level::Info)));
+        assert!(!logger.enabled(&meta("build_progress::diff", log::Level::Trace)));
+        assert!(!logger.enabled(&meta("tokio", log::Level::Info)));
+    }
+
+    #[test]
+    fn progress_json_is_written_to_output() {
+        let opts = Opts::from_iter(&["bp", "--progress-json"]);
+        let buf = Buffer::default();
+        let logger = Logger::with_output(opts, Term::stdout(), Box::new(buf.clone()));
+
+        // Positions are ignored until progress is started.
+        logger.set_progress_position(10);
+        logger.start_progress(200, "", &ProgressConfig::default());
+        logger.set_progress_position(50);
+        logger.set_progress_position(300);
+
+        let buf = buf.0.lock().unwrap();
+        let events: Vec<json::Value> = buf
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                json::json!({ "elapsed_ms": 50, "total_ms": 200, "fraction": 0.25 }),
+                json::json!({ "elapsed_ms": 300, "total_ms": 200, "fraction": 1.0 }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::borrow::Cow;
+
+use failure::ResultExt;
+use regex::bytes::{Regex, RegexSet};
+
+use crate::config::Config;
+use crate::Result;
+
+const ESC: u8 = 0x1b;
+
+/// Text matching a user-supplied pattern is replaced with this placeholder.
+const PLACEHOLDER: &[u8] = b"\0";
+
+/// Converts lines of output to the keys used to match them against the baseline. The raw line
+/// is what gets written to the log file, but lines are matched by key so that insignificant
+/// differences between runs don't prevent them being recognized.
+///
+/// Keys are computed in stages: the trailing newline and ANSI escape sequences are always
+/// removed, then leading whitespace if `trim_indent` is enabled, and finally text matching the
+/// `normalize` patterns is replaced.
+///
+/// Keys are computed from raw bytes, and output is never required to be valid UTF-8. Note
+/// that in patterns, `.` and character classes only match valid UTF-8 unless Unicode mode is
+/// disabled with `(?-u)`.
+#[derive(Debug)]
+pub struct Normalizer {
+    set: RegexSet,
+    regexes: Vec<Regex>,
+    ignore: RegexSet,
+    trim_indent: bool,
+}
+
+impl Normalizer {
+    /// Create a normalizer which additionally replaces text matching any of the given regular
+    /// expressions with a placeholder, applied in order. Every line of output is checked against
+    /// the patterns, so a long list of patterns may slow down matching for commands with a lot
+    /// of output.
+    ///
+    /// Lines matching any of the `ignore` patterns are not used to track progress at all.
+    pub fn new(patterns: &[String], ignore: &[String]) -> Result<Self> {
+        let set = RegexSet::new(patterns).context("invalid normalize pattern")?;
+        let regexes = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<std::result::Result<_, _>>()
+            .context("invalid normalize pattern")?;
+        let ignore = RegexSet::new(ignore).context("invalid ignore pattern")?;
+        Ok(Normalizer {
+            set,
+            regexes,
+            ignore,
+            trim_indent: false,
+        })
+    }
+
+    /// Create a normalizer using the `normalize` and `ignore` patterns and the `trim_indent`
+    /// option from the config.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Normalizer::new(&config.normalize, &config.ignore)?.trim_indent(config.trim_indent))
+    }
+
+    /// Set whether leading whitespace is removed from keys, so lines match even if their
+    /// indentation differs between runs.
+    pub fn trim_indent(mut self, trim_indent: bool) -> Self {
+        self.trim_indent = trim_indent;
+        self
+    }
+
+    /// Check whether a line matches one of the `ignore` patterns. Patterns are matched against
+    /// the line without its newline or ANSI escape sequences, but before normalization.
+    pub fn is_ignored(&self, line: &[u8]) -> bool {
+        self.ignore.len() != 0 && self.ignore.is_match(&strip_ansi(trim_newline(line)))
+    }
+
+    /// Get the key used to match a line of output against the baseline.
+    pub fn key(&self, line: &[u8]) -> Vec<u8> {
+        let mut key = strip_ansi(trim_newline(line));
+        if self.trim_indent {
+            key = trim_start(key);
+        }
+        // The set is checked first, so the common case of a line with no volatile parts is
+        // fast.
+        let matches = self.set.matches(&key);
+        for idx in matches.iter() {
+            if let Cow::Owned(replaced) = self.regexes[idx].replace_all(&key, PLACEHOLDER) {
+                key = Cow::Owned(replaced);
+            }
+        }
+        key.into_owned()
+    }
+}
+
+impl Default for Normalizer {
+    fn default() -> Self {
+        Normalizer::new(&[], &[]).expect("empty pattern list is valid")
+    }
+}
+
+/// Trim a trailing `\n` or `\r\n` from a line, so that lines match across platforms.
+fn trim_newline(mut line: &[u8]) -> &[u8] {
+    if line.ends_with(b"\n") {
+        line = &line[..line.len() - 1];
+    }
+    if line.ends_with(b"\r") {
+        line = &line[..line.len() - 1];
+    }
+    line
+}
+
+/// Trim leading ASCII whitespace from a line.
+fn trim_start(line: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+    let start = line
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    match line {
+        Cow::Borrowed(line) => Cow::Borrowed(&line[start..]),
+        Cow::Owned(mut line) => {
+            line.drain(..start);
+            Cow::Owned(line)
+        }
+    }
+}
+
+/// Strip ANSI CSI escape sequences, such as SGR colour codes (`\x1b[...m`), from a line.
+fn strip_ansi(line: &[u8]) -> Cow<'_, [u8]> {
+    if !line.contains(&ESC) {
+        return Cow::Borrowed(line);
+    }
+
+    let mut result = Vec::with_capacity(line.len());
+    let mut idx = 0;
+    while idx < line.len() {
+        if line[idx] == ESC && line.get(idx + 1) == Some(&b'[') {
+            idx += 2;
+            // Skip parameter and intermediate bytes, followed by the final byte.
+            while idx < line.len() && (0x20..=0x3f).contains(&line[idx]) {
+                idx += 1;
+            }
+            if idx < line.len() && (0x40..=0x7e).contains(&line[idx]) {
+                idx += 1;
+            }
+        } else {
+            result.push(line[idx]);
+            idx += 1;
+        }
+    }
+    Cow::Owned(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_trims_newline() {
+        let normalizer = Normalizer::default();
+        assert_eq!(normalizer.key(b"line\n"), b"line");
+        assert_eq!(normalizer.key(b"line\r\n"), b"line");
+        assert_eq!(normalizer.key(b"line"), b"line");
+        assert_eq!(normalizer.key(b"line\r\n\n"), b"line\r\n");
+    }
+
+    #[test]
+    fn key_trims_indent() {
+        let normalizer = Normalizer::default().trim_indent(true);
+        assert_eq!(
+            normalizer.key(b"    running test foo\n"),
+            b"running test foo"
+        );
+        assert_eq!(
+            normalizer.key(b"\t\x1b[1m  running\x1b[0m test\n"),
+            b"running test"
+        );
+        assert_eq!(normalizer.key(b"  \r\n"), b"");
+        // Trailing whitespace is kept.
+        assert_eq!(normalizer.key(b" a \n"), b"a ");
+
+        assert_eq!(Normalizer::default().key(b"  indented\n"), b"  indented");
+    }
+
+    #[test]
+    fn key_replaces_patterns() {
+        let normalizer =
+            Normalizer::new(&[r"/tmp/\w+".to_owned(), r"\d+".to_owned()], &[]).unwrap();
+        assert_eq!(
+            normalizer.key(b"[12/345] Compiling /tmp/abc123\n"),
+            normalizer.key(b"[13/346] Compiling /tmp/xyz\n")
+        );
+        assert_ne!(
+            normalizer.key(b"Compiling foo"),
+            normalizer.key(b"Compiling bar")
+        );
+    }
+
+    #[test]
+    fn is_ignored_matches_stripped_line() {
+        let normalizer = Normalizer::new(&[], &[r"^Compiling ".to_owned()]).unwrap();
+        assert!(normalizer.is_ignored(b"\x1b[32mCompiling\x1b[0m foo\r\n"));
+        assert!(!normalizer.is_ignored(b"Finished\n"));
+        assert!(!Normalizer::default().is_ignored(b"Compiling foo\n"));
+    }
+
+    #[test]
+    fn key_preserves_invalid_utf8() {
+        let normalizer = Normalizer::default();
+        assert_eq!(normalizer.key(b"\x1b[1m\xff\xfe\x1b[0m\n"), b"\xff\xfe");
+
+        let normalizer = Normalizer::new(&[r"(?-u)id=.".to_owned()], &[]).unwrap();
+        assert_eq!(normalizer.key(b"id=\xff x"), b"\0 x");
+    }
+}
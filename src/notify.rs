@@ -0,0 +1,109 @@
+use std::io;
+#[cfg(unix)]
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::logger;
+
+/// Alert the user that a run has finished with `summary`, if notifications are enabled with
+/// `--notify` and the run took at least `notify_after`. The terminal bell is rung, unless
+/// `--quiet` is given, and a desktop notification is shown if the platform supports it.
+/// Nothing is done with `--silent`.
+pub fn finished(config: &Config, elapsed: Duration, summary: &str, success: bool) {
+    if !should_notify(config, elapsed, logger::is_silent()) {
+        return;
+    }
+
+    logger::ring_bell();
+    let title = if success {
+        "bp: command succeeded"
+    } else {
+        "bp: command failed"
+    };
+    match show(title, summary) {
+        Ok(true) => (),
+        Ok(false) => log::debug!("the desktop notification was not shown"),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            log::debug!("desktop notifications are not available")
+        }
+        Err(err) => log::debug!("failed to show desktop notification: {}", err),
+    }
+}
+
+/// Check whether a run which took `elapsed` should be alerted for.
+fn should_notify(config: &Config, elapsed: Duration, silent: bool) -> bool {
+    config.notify && elapsed >= config.notify_after && !silent
+}
+
+/// Show a desktop notification using `notify-send`, returning whether it succeeded.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn show(title: &str, body: &str) -> io::Result<bool> {
+    run(Command::new("notify-send").args(["--app-name", "bp", title, body]))
+}
+
+/// Show a desktop notification using AppleScript, returning whether it succeeded.
+#[cfg(target_os = "macos")]
+fn show(title: &str, body: &str) -> io::Result<bool> {
+    let script = format!(
+        "display notification {} with title {}",
+        applescript_string(body),
+        applescript_string(title)
+    );
+    run(Command::new("osascript").arg("-e").arg(script))
+}
+
+#[cfg(target_os = "macos")]
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Desktop notifications aren't supported on other platforms, so only the bell is rung.
+#[cfg(not(unix))]
+fn show(_: &str, _: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn run(command: &mut Command) -> io::Result<bool> {
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_after_threshold() {
+        let config = Config {
+            notify: true,
+            notify_after: Duration::from_secs(30),
+            ..Config::default()
+        };
+        assert!(!should_notify(&config, Duration::from_secs(29), false));
+        assert!(should_notify(&config, Duration::from_secs(30), false));
+        assert!(should_notify(&config, Duration::from_secs(60), false));
+
+        let config = Config {
+            notify: false,
+            ..config
+        };
+        assert!(!should_notify(&config, Duration::from_secs(60), false));
+    }
+
+    #[test]
+    fn silent_disables_notifications() {
+        let config = Config {
+            notify: true,
+            notify_after: Duration::default(),
+            ..Config::default()
+        };
+        assert!(should_notify(&config, Duration::from_secs(1), false));
+        assert!(!should_notify(&config, Duration::from_secs(1), true));
+    }
+}
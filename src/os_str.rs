@@ -0,0 +1,138 @@
+//! A canonical, lossless form of OS strings, used to hash them and store them in
+//! `command.toml`. Strings which are valid unicode are stored as they are, and other strings
+//! are stored as `base64:` followed by the base64 encoding of their raw bytes: the bytes
+//! themselves on Unix, or the little-endian UTF-16 code units on Windows.
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+
+/// The prefix of stored strings which are base64 encoded. Unicode strings which start with it
+/// are encoded too, so they aren't mistaken for encoded strings.
+const RAW_PREFIX: &str = "base64:";
+
+/// Get the form of an OS string stored in `command.toml`.
+pub fn to_stored(s: &OsStr) -> String {
+    match s.to_str() {
+        Some(s) if !s.starts_with(RAW_PREFIX) => s.to_owned(),
+        _ => format!("{}{}", RAW_PREFIX, base64::encode(&*raw_bytes(s))),
+    }
+}
+
+/// Read an OS string stored by `to_stored`.
+pub fn from_stored(s: String) -> Result<OsString, String> {
+    if !s.starts_with(RAW_PREFIX) {
+        return Ok(OsString::from(s));
+    }
+    let bytes = base64::decode(&s[RAW_PREFIX.len()..]).map_err(|err| err.to_string())?;
+    from_raw_bytes(&bytes).ok_or_else(|| format!("invalid OS string '{}'", s))
+}
+
+/// Hash an OS string by its canonical bytes, so the hash doesn't depend on how the standard
+/// library represents OS strings internally.
+pub fn hash<H: Hasher>(s: &OsStr, state: &mut H) {
+    canonical_bytes(s).hash(state);
+}
+
+/// Get the bytes which identify an OS string: its UTF-8 encoding if it is valid unicode, and
+/// otherwise its raw bytes. On Unix these are always the same.
+fn canonical_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    match s.to_str() {
+        Some(s) => Cow::Borrowed(s.as_bytes()),
+        None => raw_bytes(s),
+    }
+}
+
+#[cfg(unix)]
+fn raw_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(s.as_bytes())
+}
+
+#[cfg(unix)]
+fn from_raw_bytes(bytes: &[u8]) -> Option<OsString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Some(OsStr::from_bytes(bytes).to_owned())
+}
+
+#[cfg(windows)]
+fn raw_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::windows::ffi::OsStrExt;
+
+    Cow::Owned(s.encode_wide().flat_map(u16::to_le_bytes).collect())
+}
+
+#[cfg(windows)]
+fn from_raw_bytes(bytes: &[u8]) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let wide: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    Some(OsString::from_wide(&wide))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(s: &OsStr) -> OsString {
+        from_stored(to_stored(s)).unwrap()
+    }
+
+    fn hash_inputs(s: &OsStr) -> Vec<u8> {
+        struct Canonical<'a>(&'a OsStr);
+
+        impl Hash for Canonical<'_> {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                hash(self.0, state);
+            }
+        }
+
+        crate::hash::inputs(Canonical(s))
+    }
+
+    #[test]
+    fn unicode_is_stored_as_is() {
+        assert_eq!(to_stored(OsStr::new("value")), "value");
+        assert_eq!(round_trip(OsStr::new("value")), "value");
+
+        let prefixed = OsStr::new("base64:dmFsdWU=");
+        assert_ne!(to_stored(prefixed), "base64:dmFsdWU=");
+        assert_eq!(round_trip(prefixed), prefixed);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_round_trips() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let s = OsStr::from_bytes(b"caf\xe9");
+        assert_eq!(to_stored(s), "base64:Y2Fm6Q==");
+        assert_eq!(round_trip(s), s);
+        assert_ne!(hash_inputs(s), hash_inputs(OsStr::new("caf")));
+        // The hash is unchanged from when OS strings were hashed directly.
+        assert_eq!(hash_inputs(s), crate::hash::inputs(s));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn non_utf16_round_trips() {
+        use std::os::windows::ffi::OsStringExt;
+
+        // An unpaired surrogate.
+        let s = OsString::from_wide(&[0x63, 0xd800, 0x66]);
+        assert!(to_stored(&s).starts_with("base64:"));
+        assert_eq!(round_trip(&s), s);
+        assert_ne!(
+            hash_inputs(&s),
+            hash_inputs(&OsString::from_wide(&[0x63, 0xdc00, 0x66]))
+        );
+    }
+}
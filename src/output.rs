@@ -1,52 +1,326 @@
-use std::fs::{self, File};
-use std::io::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufWriter};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
-use failure::{bail, ResultExt};
+use failure::{bail, format_err, Fail, ResultExt};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use indicatif::HumanDuration;
 
 use crate::cmd::{self, CommandOptions};
-use crate::util::{open_or_create, FileEntry};
-use crate::{diff, logger, Result};
+use crate::config::Config;
+use crate::diff::Stream;
+use crate::util::{open_or_create, remove_if_exists, replace_file, with_suffix, FileEntry};
+use crate::{diff, hash, logger, util, Result};
 
 pub struct Writer {
-    file: File,
-    path: PathBuf,
+    log: Option<LogFile>,
     diff: Mutex<diff::Writer>,
+    progress_from: Streams,
+    tee: bool,
+    capture: Streams,
+    show: Streams,
+    /// How lines are timestamped in the log and on the terminal, if at all.
+    timestamps: Option<Timestamps>,
+    line_count: AtomicUsize,
+    start: Instant,
+    /// The time of the most recent line of output, in milliseconds since `start`.
+    last_line: AtomicU64,
+    stall_warning: Option<Duration>,
+    stalled: AtomicBool,
+    /// The percentage between messages logged with '--verbose-progress', or `None` if they are
+    /// disabled.
+    progress_step: Option<u64>,
+    /// The next percentage at which to log a message.
+    next_milestone: AtomicU64,
+    /// Where the current progress is written for other tools to read, or `None` for read-only
+    /// runs.
+    status_path: Option<PathBuf>,
+    /// How to name the log file with '--output-template'.
+    log_template: Option<LogTemplate>,
 }
 
-impl Writer {
-    pub fn new(opts: &cmd::Opts, cmd: &CommandOptions) -> Result<Self> {
-        let dir = if let Some(dir) = dirs::data_dir() {
-            dir.join(env!("CARGO_PKG_NAME")).join(cmd.hash())
-        } else {
-            bail!("failed to get user's data directory");
+/// The name of a log file kept with '--output-template', with the values of its placeholders.
+struct LogTemplate {
+    template: String,
+    dir: PathBuf,
+    hash: String,
+    timestamp: String,
+    keep: usize,
+}
+
+/// The number of logs kept with '--output-template' if '--keep-logs' isn't given.
+const DEFAULT_KEEP_LOGS: usize = 10;
+/// The placeholders allowed in '--output-template'.
+const LOG_TEMPLATE_KEYS: &[&str] = &["hash", "timestamp", "pid", "exit"];
+
+/// The progress of a running command, written to `status.json` in its data directory each
+/// time the progress bar is updated, and removed when the run finishes. The file is replaced
+/// atomically, so readers never see a partial write.
+///
+/// The format is semi-stable: fields may be added, but existing fields will not be removed or
+/// change meaning without a new name. A file left behind by a crash can be detected because
+/// `pid` no longer refers to a running process.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Status {
+    /// The process ID of `bp`.
+    pub pid: u32,
+    /// The estimated fraction of the run completed, between 0 and 1.
+    pub fraction: f64,
+    /// The time since the command started.
+    pub elapsed_ms: u64,
+    /// The estimated time remaining, or `null` if the run has taken longer than expected.
+    pub eta_ms: Option<u64>,
+    /// When the file was written, as an RFC 3339 timestamp.
+    pub updated_at: String,
+}
+
+/// The name of the file in the data directory which holds the progress of a running command.
+pub const STATUS_FILE: &str = "status.json";
+
+/// A selection of the command's output streams.
+#[derive(
+    Debug, Default, Copy, Clone, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Streams {
+    Stdout,
+    Stderr,
+    #[default]
+    Both,
+}
+
+impl Streams {
+    pub fn is_both(&self) -> bool {
+        *self == Streams::Both
+    }
+
+    pub fn contains(self, stream: Stream) -> bool {
+        matches!(
+            (self, stream),
+            (Streams::Both, _)
+                | (Streams::Stdout, Stream::Stdout)
+                | (Streams::Stderr, Stream::Stderr)
+        )
+    }
+}
+
+impl FromStr for Streams {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stdout" => Ok(Streams::Stdout),
+            "stderr" => Ok(Streams::Stderr),
+            "both" => Ok(Streams::Both),
+            _ => Err(format_err!("invalid stream '{}'", s)),
+        }
+    }
+}
+
+/// How lines of the command's output are timestamped with '--timestamps'.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Timestamps {
+    /// The time since the command started, e.g. `[   1.234s]`.
+    Elapsed,
+    /// The time of day in UTC, e.g. `[2024-01-31T12:00:01.234Z]`.
+    Wallclock,
+}
+
+impl Timestamps {
+    /// Prefix a line with the time it was output at, `elapsed` after the run started.
+    fn apply(self, line: &[u8], started_at: SystemTime, elapsed: Duration) -> Vec<u8> {
+        let prefix = match self {
+            Timestamps::Elapsed => format!("[{:>8.3}s] ", elapsed.as_secs_f64()),
+            Timestamps::Wallclock => format!(
+                "[{}] ",
+                humantime::format_rfc3339_millis(started_at + elapsed)
+            ),
         };
+        let mut stamped = prefix.into_bytes();
+        stamped.extend_from_slice(line);
+        stamped
+    }
+}
 
-        fs::create_dir_all(&dir)
-            .with_context(|_| format!("failed to create directory '{}'", dir.display()))?;
+impl FromStr for Timestamps {
+    type Err = failure::Error;
 
-        let command_path = dir.join("command").with_extension("toml");
-        log::debug!("opening or creating command file '{}'", command_path.display());
-        let (command_file, meta) = open_or_create(&command_path)?;
-        if let Err(err) = check_cmd(&command_file, &command_path, meta, cmd) {
-            log::warn!("{}", crate::fmt_error(&err));
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "elapsed" => Ok(Timestamps::Elapsed),
+            "wallclock" => Ok(Timestamps::Wallclock),
+            _ => Err(format_err!("invalid timestamp format '{}'", s)),
+        }
+    }
+}
+
+/// What to do when the data directory for a command records a different command, because their
+/// hashes collide.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum OnCollision {
+    /// Warn, and share the data directory with the other command.
+    Warn,
+    /// Fail without running the command.
+    Error,
+    /// Record the command in a separate directory, named by adding a suffix to the hash.
+    #[default]
+    Isolate,
+}
+
+impl FromStr for OnCollision {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(OnCollision::Warn),
+            "error" => Ok(OnCollision::Error),
+            "isolate" => Ok(OnCollision::Isolate),
+            _ => Err(format_err!("invalid collision action '{}'", s)),
         }
+    }
+}
 
-        let path = if let Some(path) = &opts.output {
-            cmd.workdir.join(path)
-        } else {
-            dir.join("output").with_extension("log")
+struct LogFile {
+    file: Mutex<LogWriter>,
+    /// The path of the log, which changes if it is renamed to include the exit code.
+    path: Mutex<PathBuf>,
+    compress: bool,
+    /// Whether each run is appended to the file rather than replacing it.
+    append: bool,
+}
+
+enum LogWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Stdout(io::Stdout),
+}
+
+/// The value of '--output' which writes the log to stdout.
+pub const STDOUT_PATH: &str = "-";
+
+impl Writer {
+    pub fn new(opts: &cmd::Opts, config: &Config, cmd: &CommandOptions) -> Result<Self> {
+        let mut dir = opts.command.hash_dir(cmd, config)?;
+        let access = opts.access();
+        if !matches!(access, diff::Access::ReadOnly) {
+            dir = writable_data_dir(dir, opts.allow_temp_data)?;
+        }
+
+        // The directory is reset after resolving any hash collision, so that the data of the
+        // other command is left alone.
+        let dir = prepare_dir(&dir, cmd, access, opts.on_collision)?;
+        if opts.reset {
+            reset_dir(&dir)?;
+        }
+        Writer::open(opts, config, &dir, &cmd.workdir)
+    }
+
+    /// Create a writer for the combined output of a pipeline, which is recorded in `dir`
+    /// rather than the data directory of a single command.
+    pub fn for_pipeline(
+        opts: &cmd::Opts,
+        config: &Config,
+        dir: &Path,
+        workdir: &Path,
+    ) -> Result<Self> {
+        let mut dir = dir.to_owned();
+        if !matches!(opts.access(), diff::Access::ReadOnly) {
+            dir = writable_data_dir(dir, opts.allow_temp_data)?;
+        }
+        if opts.reset {
+            reset_dir(&dir)?;
+        }
+
+        Writer::open(opts, config, &dir, workdir)
+    }
+
+    fn open(opts: &cmd::Opts, config: &Config, dir: &Path, workdir: &Path) -> Result<Self> {
+        let access = opts.access();
+
+        // The baseline is locked before the log file is created, so that a concurrent run of
+        // the same command doesn't truncate the log.
+        let mut diff = match &opts.baseline {
+            Some(path) => diff::Writer::with_baseline(
+                dir,
+                workdir.join(path),
+                opts.update_baseline,
+                config,
+                access,
+            )?,
+            None => diff::Writer::new(dir, config, access)?,
         };
+        if let Some(baseline) = opts.forced_baseline() {
+            diff.force_baseline(baseline);
+        }
+        let diff = Mutex::new(diff);
 
-        let output_file = File::create(&path)
-            .with_context(|_| format!("failed to create file '{}'", path.display()))?;
-        let diff = Mutex::new(diff::Writer::new(&dir)?);
+        let log_template = match &opts.output_template {
+            Some(template) => Some(LogTemplate::new(
+                template,
+                dir,
+                opts.keep_logs.unwrap_or(DEFAULT_KEEP_LOGS),
+            )?),
+            None => None,
+        };
+        let path = if let Some(template) = &log_template {
+            fs::create_dir_all(&template.dir).with_context(|_| {
+                format!("failed to create directory '{}'", template.dir.display())
+            })?;
+            Some(template.path(None))
+        } else if opts.output_is_stdout() {
+            if opts.tee() {
+                bail!("'--tee' cannot be used with '--output {}'", STDOUT_PATH);
+            }
+            Some(PathBuf::from(STDOUT_PATH))
+        } else if let Some(path) = &opts.output {
+            Some(opts.output_path(path, workdir)?)
+        } else if let diff::Access::ReadOnly | diff::Access::Shared = access {
+            // The default log file holds the output of the most recent recorded run, which
+            // another run may be writing to.
+            None
+        } else {
+            Some(
+                dir.join("output")
+                    .with_extension(if config.compress { "log.gz" } else { "log" }),
+            )
+        };
+        // Only a log file given by '--output' is appended to. The default log file in the data
+        // directory always holds the most recent run.
+        let append = opts.append_output && opts.output.is_some();
+        let log = match path {
+            Some(path) => Some(LogFile::create(path, config.compress, append)?),
+            None => None,
+        };
 
         Ok(Writer {
-            file: output_file,
-            path,
+            log,
             diff,
+            progress_from: opts.command.progress_from(),
+            tee: opts.tee(),
+            capture: opts.capture,
+            show: opts.show,
+            timestamps: opts.timestamps,
+            line_count: AtomicUsize::new(0),
+            start: Instant::now(),
+            last_line: AtomicU64::new(0),
+            stall_warning: opts.stall_warning,
+            stalled: AtomicBool::new(false),
+            progress_step: if opts.verbose_progress || !logger::is_term() {
+                Some(opts.progress_step)
+            } else {
+                None
+            },
+            next_milestone: AtomicU64::new(opts.progress_step),
+            status_path: match access {
+                diff::Access::ReadOnly | diff::Access::Shared => None,
+                _ => Some(dir.join(STATUS_FILE)),
+            },
+            log_template,
         })
     }
 
@@ -54,36 +328,636 @@ impl Writer {
         self.diff.get_mut().unwrap()
     }
 
-    pub fn path(&self) -> &Path {
-        &self.path
+    /// Get the path of the log file, if the output is being logged to a file.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.log
+            .as_ref()
+            .map(|log| log.path())
+            .filter(|path| path != Path::new(STDOUT_PATH))
+    }
+
+    /// With '--output-template', rename the log to include the exit code of the run, and
+    /// remove the oldest logs beyond '--keep-logs'.
+    pub fn finish_log(&self, code: i32) -> Result<()> {
+        let (log, template) = match (&self.log, &self.log_template) {
+            (Some(log), Some(template)) => (log, template),
+            _ => return Ok(()),
+        };
+
+        let curr = log.path();
+        let path = template.path(Some(code));
+        if path != curr {
+            replace_file(&curr, &path).with_context(|_| {
+                format!(
+                    "failed to move file '{}' to '{}'",
+                    curr.display(),
+                    path.display()
+                )
+            })?;
+            *log.path.lock().unwrap() = path;
+        }
+        template.prune()
     }
 
     pub fn write_stdout(&self, line: Vec<u8>) -> Result<()> {
-        self.write(&line)?;
-        logger::log_bytes(&line);
+        self.write_line(Stream::Stdout, &line)
+    }
 
-        let mut diff = self.diff.lock().unwrap();
-        diff.write_line(line)?;
-        logger::set_progress_position(diff.completed().as_millis() as u64);
+    pub fn write_stderr(&self, line: Vec<u8>) -> Result<()> {
+        self.write_line(Stream::Stderr, &line)
+    }
+
+    fn write_line(&self, stream: Stream, line: &[u8]) -> Result<()> {
+        self.line_count.fetch_add(1, Ordering::Relaxed);
+        self.last_line
+            .store(self.elapsed_millis(), Ordering::Relaxed);
+        self.stalled.store(false, Ordering::Relaxed);
+        // The line is matched before it is written, so that its timestamp is the same as the
+        // time recorded for it in the baseline.
+        let elapsed = if self.progress_from.contains(stream) {
+            Some(self.write_diff(stream, line)?)
+        } else {
+            None
+        };
+        let stamped = self.timestamps.map(|format| {
+            let diff = self.diff.lock().unwrap();
+            let elapsed = elapsed.unwrap_or_else(|| diff.elapsed());
+            format.apply(line, diff.started_at(), elapsed)
+        });
+        let logged = stamped.as_deref().unwrap_or(line);
+        if self.capture.contains(stream) {
+            self.write(logged)?;
+        }
+        if self.show.contains(stream) {
+            match (self.tee, stream) {
+                (true, Stream::Stdout) => logger::tee_stdout(line),
+                (true, Stream::Stderr) => logger::tee_stderr(line),
+                (false, _) => logger::log_bytes(logged),
+            }
+        }
         Ok(())
     }
 
-    pub fn write_stderr(&self, line: Vec<u8>) -> Result<()> {
-        self.write(&line)?;
-        logger::log_bytes(&line);
+    /// Discard the output written so far, so that the command can be run again. An appended
+    /// log file keeps the output of the failed attempt, and starts a new run after it.
+    pub fn reset(&self) -> Result<()> {
+        if let Some(log) = &self.log {
+            log.reset()?;
+        }
+        self.line_count.store(0, Ordering::Relaxed);
+        self.last_line
+            .store(self.elapsed_millis(), Ordering::Relaxed);
+        self.stalled.store(false, Ordering::Relaxed);
+        if let Some(step) = self.progress_step {
+            self.next_milestone.store(step, Ordering::Relaxed);
+        }
+        self.diff.lock().unwrap().reset();
         Ok(())
     }
 
+    pub fn tick(&self) {
+        // Flush the log regularly so its contents are not lost if we crash.
+        if let Err(err) = self.flush() {
+            log::warn!("{}", crate::fmt_error(&err));
+        }
+        {
+            let mut diff = self.diff.lock().unwrap();
+            diff.tick();
+            update_progress(&diff);
+            self.log_milestone(&diff);
+            self.write_status(&diff);
+        }
+        logger::tick_progress_bar();
+        self.check_stalled();
+    }
+
+    /// Log a message when the estimated progress passes the next multiple of
+    /// `--progress-step`, for logs where the progress bar isn't shown. If several are passed
+    /// at once, only the last is logged.
+    fn log_milestone(&self, diff: &diff::Writer) {
+        let step = match self.progress_step {
+            Some(step) => step,
+            None => return,
+        };
+        let len = match diff.len() {
+            Some(len) if len > Duration::default() => len,
+            _ => return,
+        };
+
+        let fraction = (diff.completed().as_secs_f64() / len.as_secs_f64()).min(1.0);
+        let milestone = (fraction * 100.0) as u64 / step * step;
+        if milestone < self.next_milestone.load(Ordering::Relaxed) {
+            return;
+        }
+        self.next_milestone
+            .store(milestone + step, Ordering::Relaxed);
+
+        let elapsed = Duration::from_secs(self.start.elapsed().as_secs());
+        match diff.eta() {
+            Some(diff::Eta::Remaining(remaining)) => log::info!(
+                "~{}% complete, {} elapsed, ~{:#} remaining",
+                milestone,
+                humantime::format_duration(elapsed),
+                HumanDuration(remaining)
+            ),
+            _ => log::info!(
+                "~{}% complete, {} elapsed, running longer than expected",
+                milestone,
+                humantime::format_duration(elapsed)
+            ),
+        }
+    }
+
+    /// Write the current progress to `status.json`. This is only done on each tick rather than
+    /// for each line, to limit the number of writes.
+    fn write_status(&self, diff: &diff::Writer) {
+        let path = match &self.status_path {
+            Some(path) => path,
+            None => return,
+        };
+        let len = match diff.len() {
+            Some(len) if len > Duration::default() => len,
+            _ => return,
+        };
+
+        let status = Status {
+            pid: std::process::id(),
+            fraction: (diff.completed().as_secs_f64() / len.as_secs_f64()).min(1.0),
+            elapsed_ms: self.elapsed_millis(),
+            eta_ms: match diff.eta() {
+                Some(diff::Eta::Remaining(remaining)) => Some(remaining.as_millis() as u64),
+                _ => None,
+            },
+            updated_at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        };
+        let tmp_path = with_suffix(path, ".tmp");
+        let result = json::to_vec(&status)
+            .map_err(io::Error::from)
+            .and_then(|bytes| fs::write(&tmp_path, bytes))
+            .and_then(|()| replace_file(&tmp_path, path));
+        if let Err(err) = result {
+            log::debug!("failed to write status file '{}': {}", path.display(), err);
+        }
+    }
+
+    fn remove_status(&self) {
+        if let Some(path) = &self.status_path {
+            if let Err(err) = remove_if_exists(path) {
+                log::debug!("{}", crate::fmt_error(&err));
+            }
+        }
+    }
+
+    /// Get the expected duration of the run, if it has run for longer than `factor` times that.
+    pub fn check_overtime(&self, factor: f64) -> Option<Duration> {
+        let diff = self.diff.lock().unwrap();
+        let len = diff.len()?;
+        if diff.elapsed().as_secs_f64() > len.as_secs_f64() * factor {
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    /// Warn once if the command has not produced any output for longer than `--stall-warning`.
+    fn check_stalled(&self) {
+        if let Some(stall_warning) = self.stall_warning {
+            // A line may be written after the elapsed time is read, so `last_line` can be ahead
+            // of it.
+            let idle = self
+                .elapsed_millis()
+                .saturating_sub(self.last_line.load(Ordering::Relaxed));
+            if Duration::from_millis(idle) >= stall_warning
+                && !self.stalled.swap(true, Ordering::Relaxed)
+            {
+                log::warn!(
+                    "the process has not produced any output for {}",
+                    humantime::format_duration(Duration::from_secs(idle / 1000))
+                );
+            }
+        }
+    }
+
+    fn elapsed_millis(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    /// Match a line against the baseline, returning the time into the run it was output at.
+    fn write_diff(&self, stream: Stream, line: &[u8]) -> Result<Duration> {
+        let mut diff = self.diff.lock().unwrap();
+        // The progress bar is only updated by `tick`, since redrawing it for every line is
+        // expensive for commands which produce a lot of output.
+        Ok(diff.write_line(stream, line)?.elapsed)
+    }
+
+    /// Write a line to the log file. Lines are written exactly as they were received from the
+    /// process, even if they are not valid UTF-8, and only the copy displayed on the terminal
+    /// is converted lossily.
     fn write(&self, line: &[u8]) -> Result<()> {
-        Ok((&self.file)
-            .write_all(line)
-            .with_context(|_| format!("failed to write to file '{}'", self.path.display()))?)
+        match &self.log {
+            Some(log) => log.write(line),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match &self.log {
+            Some(log) => log.flush(),
+            None => Ok(()),
+        }
     }
 
     pub fn finish(&self, success: bool) -> Result<()> {
+        {
+            let mut diff = self.diff.lock().unwrap();
+            diff.settle();
+            update_progress(&diff);
+        }
         logger::finish_progress();
+        self.remove_status();
+        if let Some(log) = &self.log {
+            log.finish()?;
+        }
+        if self.line_count.load(Ordering::Relaxed) == 0 {
+            log::warn!(
+                "the process produced no output on stdout or stderr, so progress cannot be \
+                 tracked. Check that the process isn't buffering its output, or try running it \
+                 with '--shell'"
+            );
+        }
         self.diff.lock().unwrap().finish(success)
     }
+
+    /// Finish writing the output of an interrupted run. The baseline is not updated, since
+    /// the output is incomplete.
+    pub fn abort(&self) -> Result<()> {
+        update_progress(&self.diff.lock().unwrap());
+        logger::finish_progress();
+        self.remove_status();
+        if let Some(log) = &self.log {
+            log.finish()?;
+        }
+        self.diff.lock().unwrap().abort();
+        Ok(())
+    }
+}
+
+fn update_progress(diff: &diff::Writer) {
+    // The length changes if the run switches to the incremental baseline.
+    if let Some(len) = diff.len() {
+        let msg = format!("{:#}", HumanDuration(len));
+        logger::set_progress_length(len.as_millis() as u64, &msg);
+    }
+    logger::set_progress_position(diff.completed().as_millis() as u64);
+    if let Some(eta) = diff.eta() {
+        logger::set_progress_eta(eta);
+    }
+}
+
+impl LogTemplate {
+    fn new(template: &str, dir: &Path, keep: usize) -> Result<Self> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("unmatched '{{' in output template '{}'", template),
+            };
+            let key = &rest[start + 1..end];
+            if !LOG_TEMPLATE_KEYS.contains(&key) {
+                bail!("unknown key '{}' in output template '{}'", key, template);
+            }
+            rest = &rest[end + 1..];
+        }
+        if template.is_empty() || template.contains(&['/', '\\'][..]) {
+            bail!("output template '{}' must be a file name", template);
+        }
+
+        // The timestamp is formatted without separators, so it is a valid file name on every
+        // platform and sorts in order.
+        let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(&['-', ':'][..], "");
+        Ok(LogTemplate {
+            template: template.to_owned(),
+            dir: dir.join("logs"),
+            hash: dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            timestamp,
+            keep,
+        })
+    }
+
+    /// Get the path of the log, given the exit code of the run if it has finished.
+    fn path(&self, code: Option<i32>) -> PathBuf {
+        let exit = match code {
+            Some(code) => code.to_string(),
+            None => "running".to_owned(),
+        };
+        let name = self
+            .template
+            .replace("{hash}", &self.hash)
+            .replace("{timestamp}", &self.timestamp)
+            .replace("{pid}", &std::process::id().to_string())
+            .replace("{exit}", &exit);
+        self.dir.join(name)
+    }
+
+    /// Remove the oldest logs, so at most `keep` remain.
+    fn prune(&self) -> Result<()> {
+        if self.keep == 0 {
+            return Ok(());
+        }
+
+        let mut logs = Vec::new();
+        for entry in fs::read_dir(&self.dir)
+            .with_context(|_| format!("failed to read directory '{}'", self.dir.display()))?
+        {
+            let entry = entry
+                .with_context(|_| format!("failed to read directory '{}'", self.dir.display()))?;
+            let modified = entry.metadata().and_then(|meta| meta.modified()).ok();
+            logs.push((modified, entry.path()));
+        }
+        logs.sort();
+        let excess = logs.len().saturating_sub(self.keep);
+        for (_, path) in logs.drain(..excess) {
+            log::debug!("removing old log file '{}'", path.display());
+            remove_if_exists(&path)?;
+        }
+        Ok(())
+    }
+}
+
+impl LogFile {
+    fn create(path: PathBuf, compress: bool, append: bool) -> Result<Self> {
+        let file = LogWriter::create(&path, compress, append)?;
+        let log = LogFile {
+            file: Mutex::new(file),
+            path: Mutex::new(path),
+            compress,
+            append,
+        };
+        if append {
+            log.write_header()?;
+        }
+        Ok(log)
+    }
+
+    fn path(&self) -> PathBuf {
+        self.path.lock().unwrap().clone()
+    }
+
+    /// Write a line separating this run from previous runs in an appended log file.
+    fn write_header(&self) -> Result<()> {
+        let header = format!(
+            "===== run started at {} =====\n",
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        );
+        self.write(header.as_bytes())
+    }
+
+    fn write(&self, line: &[u8]) -> Result<()> {
+        Ok(self
+            .file
+            .lock()
+            .unwrap()
+            .write_all(line)
+            .with_context(|_| format!("failed to write to file '{}'", self.path().display()))?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self
+            .file
+            .lock()
+            .unwrap()
+            .flush()
+            .with_context(|_| format!("failed to write to file '{}'", self.path().display()))?)
+    }
+
+    fn finish(&self) -> Result<()> {
+        Ok(self
+            .file
+            .lock()
+            .unwrap()
+            .finish()
+            .with_context(|_| format!("failed to write to file '{}'", self.path().display()))?)
+    }
+
+    fn reset(&self) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        // The old stream must be finished first, so it doesn't write to the new file when
+        // dropped.
+        file.finish()
+            .with_context(|_| format!("failed to write to file '{}'", self.path().display()))?;
+        *file = LogWriter::create(&self.path(), self.compress, self.append)?;
+        drop(file);
+        if self.append {
+            self.write_header()?;
+        }
+        Ok(())
+    }
+}
+
+impl LogWriter {
+    fn create(path: &Path, compress: bool, append: bool) -> Result<Self> {
+        // The log is written to stdout as it was received, so it is never compressed.
+        if path == Path::new(STDOUT_PATH) {
+            return Ok(LogWriter::Stdout(io::stdout()));
+        }
+
+        let file = if append {
+            // Appended gzip streams are read back as a single stream by most tools.
+            OpenOptions::new().create(true).append(true).open(path)
+        } else {
+            File::create(path)
+        }
+        .with_context(|_| format!("failed to create file '{}'", path.display()))?;
+        let file = BufWriter::new(file);
+        if compress {
+            Ok(LogWriter::Gzip(GzEncoder::new(
+                file,
+                Compression::default(),
+            )))
+        } else {
+            Ok(LogWriter::Plain(file))
+        }
+    }
+
+    /// Flush all buffered output, and for compressed logs write the end of the stream.
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Plain(file) => file.flush(),
+            LogWriter::Gzip(file) => {
+                file.try_finish()?;
+                file.get_mut().flush()
+            }
+            LogWriter::Stdout(stdout) => stdout.flush(),
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            LogWriter::Plain(file) => file.write(buf),
+            LogWriter::Gzip(file) => file.write(buf),
+            LogWriter::Stdout(stdout) => stdout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Plain(file) => file.flush(),
+            LogWriter::Gzip(file) => file.flush(),
+            LogWriter::Stdout(stdout) => stdout.flush(),
+        }
+    }
+}
+
+/// Create the data directory for a command, and check the command recorded in it matches,
+/// returning the directory to use. In read-only mode, the directory is only checked if it
+/// exists.
+pub fn prepare_dir(
+    dir: &Path,
+    cmd: &CommandOptions,
+    access: diff::Access,
+    on_collision: OnCollision,
+) -> Result<PathBuf> {
+    let mut curr_dir = dir.to_owned();
+    for idx in 2.. {
+        let prev_cmd = match check_dir(&curr_dir, cmd, access) {
+            Ok(None) => break,
+            Ok(Some(prev_cmd)) => prev_cmd,
+            Err(err) => {
+                log::warn!("{}", crate::fmt_error(&err));
+                break;
+            }
+        };
+
+        let msg = format!(
+            "hash collision: previous command '{}' not equal to current command '{}'",
+            prev_cmd, cmd
+        );
+        match on_collision {
+            OnCollision::Warn => {
+                log::warn!("{}", msg);
+                break;
+            }
+            OnCollision::Error => bail!("{}, use '--on-collision' to run it anyway", msg),
+            OnCollision::Isolate => {
+                curr_dir = util::with_suffix(dir, &format!("-{}", idx));
+                log::debug!("{}, using directory '{}'", msg, curr_dir.display());
+            }
+        }
+    }
+
+    Ok(curr_dir)
+}
+
+/// Check the command recorded in a data directory, returning it if it doesn't match `cmd`.
+fn check_dir(
+    dir: &Path,
+    cmd: &CommandOptions,
+    access: diff::Access,
+) -> Result<Option<CommandOptions<'static>>> {
+    let command_path = dir.join("command").with_extension("toml");
+    if let diff::Access::ReadOnly = access {
+        match File::open(&command_path) {
+            Ok(file) => {
+                let meta = file.metadata()?;
+                check_cmd(&FileEntry::Existing(file), &command_path, meta, cmd)
+            }
+            Err(err) => {
+                log::debug!(
+                    "failed to open command file '{}': {}",
+                    command_path.display(),
+                    err
+                );
+                Ok(None)
+            }
+        }
+    } else {
+        fs::create_dir_all(dir)
+            .with_context(|_| format!("failed to create directory '{}'", dir.display()))?;
+
+        log::debug!("opening or creating command file '{}'", command_path.display());
+        let (command_file, meta) = open_or_create(&command_path)?;
+        check_cmd(&command_file, &command_path, meta, cmd)
+    }
+}
+
+/// Create the data directory for a run, checking that it can be written to, so that a data
+/// directory in a read-only location fails early with a clear error. With '--allow-temp-data',
+/// a directory of the same name in the system's temporary directory is used instead.
+fn writable_data_dir(dir: PathBuf, allow_temp: bool) -> Result<PathBuf> {
+    let err = match check_writable(&dir) {
+        Ok(()) => return Ok(dir),
+        Err(err) => err,
+    };
+    if !matches!(
+        err.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+    ) {
+        return Err(err
+            .context(format!("failed to create directory '{}'", dir.display()))
+            .into());
+    }
+    if !allow_temp {
+        bail!(
+            "the data directory '{}' is not writable ({}), use '--data-dir' to store data \
+             somewhere else, or '--allow-temp-data' to use a temporary directory",
+            dir.display(),
+            err
+        );
+    }
+
+    let mut temp_dir = std::env::temp_dir().join(env!("CARGO_PKG_NAME"));
+    if let Some(name) = dir.file_name() {
+        temp_dir.push(name);
+    }
+    check_writable(&temp_dir)
+        .with_context(|_| format!("failed to create directory '{}'", temp_dir.display()))?;
+    log::warn!(
+        "the data directory '{}' is not writable ({}), using '{}' instead",
+        dir.display(),
+        err,
+        temp_dir.display()
+    );
+    Ok(temp_dir)
+}
+
+/// Create a directory if it doesn't exist, and check that files can be created in it.
+fn check_writable(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    fs::remove_file(&probe)
+}
+
+/// Remove the recorded output in a data directory, for `--reset`. The command file is kept,
+/// since it has already been checked against the command being run.
+fn reset_dir(dir: &Path) -> Result<()> {
+    for file in &[
+        "orig.json",
+        "orig.json.corrupt",
+        "incremental.json",
+        "incremental.json.corrupt",
+        "failed.json",
+        "failed.json.corrupt",
+        "output.log",
+        "output.log.gz",
+        STATUS_FILE,
+    ] {
+        let path = dir.join(file);
+        if remove_if_exists(&path)? {
+            log::debug!("removed file '{}'", path.display());
+        }
+    }
+    Ok(())
 }
 
 fn check_cmd(
@@ -91,31 +965,47 @@ fn check_cmd(
     path: &Path,
     meta: fs::Metadata,
     curr_cmd: &CommandOptions,
-) -> Result<()> {
+) -> Result<Option<CommandOptions<'static>>> {
     match file {
         FileEntry::Existing(file) => {
-            let mut file = file;
-            let mut string = String::with_capacity(meta.len() as usize);
-            file.read_to_string(&mut string)
-                .with_context(|_| format!("failed to read file '{}'", path.display()))?;
-            let prev_cmd: CommandOptions = toml::from_str(&string)
-                .with_context(|_| format!("failed to parse TOML from file '{}'", path.display()))?;
+            let prev_cmd = read_cmd(file, path, &meta)?;
             log::trace!("previous command: {:#?}", prev_cmd);
-            if *curr_cmd != prev_cmd {
-                bail!(
-                    "hash collision: previous command '{}' not equal to current command '{}'",
-                    prev_cmd,
-                    curr_cmd
+            if curr_cmd.to_stored() != prev_cmd {
+                log::debug!(
+                    "previous command hash inputs: '{}'",
+                    hash::escape(&prev_cmd.hash_inputs())
                 );
+                log::debug!(
+                    "current command hash inputs: '{}'",
+                    hash::escape(&curr_cmd.hash_inputs())
+                );
+                return Ok(Some(prev_cmd));
             }
         }
         FileEntry::New(file) => {
             let mut file = file;
-            let string = toml::to_string_pretty(curr_cmd)?;
-            file.write_all(string.as_bytes()).with_context(|_| {
-                format!("failed to write to file '{}'", path.display())
-            })?;
+            let string = toml::to_string_pretty(&curr_cmd.to_stored())?;
+            file.write_all(string.as_bytes())
+                .with_context(|_| format!("failed to write to file '{}'", path.display()))?;
         }
     }
-    Ok(())
+    Ok(None)
+}
+
+/// Read the command recorded in the `command.toml` file of a data directory.
+pub fn read_command(path: &Path) -> Result<CommandOptions<'static>> {
+    let file =
+        File::open(path).with_context(|_| format!("failed to open file '{}'", path.display()))?;
+    let meta = file
+        .metadata()
+        .with_context(|_| format!("failed to get metadata for file '{}'", path.display()))?;
+    read_cmd(&file, path, &meta)
+}
+
+fn read_cmd(mut file: &File, path: &Path, meta: &fs::Metadata) -> Result<CommandOptions<'static>> {
+    let mut string = String::with_capacity(meta.len() as usize);
+    file.read_to_string(&mut string)
+        .with_context(|_| format!("failed to read file '{}'", path.display()))?;
+    Ok(toml::from_str(&string)
+        .with_context(|_| format!("failed to parse TOML from file '{}'", path.display()))?)
 }
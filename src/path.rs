@@ -0,0 +1,44 @@
+use std::env;
+use std::fs;
+
+use failure::ResultExt;
+use structopt::StructOpt;
+
+use crate::cmd::{self, CommandOptions};
+use crate::config::Config;
+use crate::Result;
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub command: cmd::CommandOpts,
+    /// Create the data directory if it does not exist
+    #[structopt(long = "create")]
+    pub create: bool,
+}
+
+/// Print the directory where data for a command is stored, without running it.
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    let command = CommandOptions::new(&opts.command, &config)?;
+    log::trace!("command: {:#?}", command);
+
+    let mut path = opts.command.data_dir(&command, &config)?;
+    if path.is_relative() {
+        path = env::current_dir()
+            .context("failed to get current directory")?
+            .join(path);
+    }
+
+    if opts.create {
+        fs::create_dir_all(&path)
+            .with_context(|_| format!("failed to create directory '{}'", path.display()))?;
+    }
+
+    println!("{}", path.display());
+    if path.is_dir() {
+        Ok(0)
+    } else {
+        log::warn!("no data has been recorded for '{}'", command);
+        Ok(1)
+    }
+}
@@ -0,0 +1,202 @@
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::{bail, ResultExt};
+use indicatif::HumanDuration;
+use serde::Deserialize;
+use tokio::runtime::Runtime;
+
+use crate::cmd::{self, CommandOptions, Exit};
+use crate::config::Config;
+use crate::{hash, logger, notify, output, util, Result};
+
+/// A list of commands which are run in order, sharing a single baseline.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pipeline {
+    /// The name used to identify the pipeline's recorded output. Defaults to the name of the
+    /// pipeline file.
+    name: Option<String>,
+    #[serde(rename = "stage")]
+    stages: Vec<Stage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stage {
+    /// The name shown when the stage starts. Defaults to the command.
+    name: Option<String>,
+    /// The command to run, as a list of arguments.
+    command: Vec<String>,
+    /// Run the command through the platform shell.
+    #[serde(default)]
+    shell: bool,
+}
+
+/// Run each stage of the pipeline in `path`, stopping at the first which fails. Their output
+/// is recorded together, so the progress bar spans the whole pipeline.
+pub fn run(opts: &cmd::Opts, mut config: Config, path: &Path) -> Result<i32> {
+    if opts.max_lines.is_some() {
+        config.max_lines = opts.max_lines;
+    }
+    if let Some(min_record_duration) = opts.min_record_duration {
+        config.min_record_duration = min_record_duration;
+    }
+    config.no_degrade = (config.no_degrade || opts.no_degrade) && !opts.force_record;
+    config.prefix_match |= opts.prefix_match;
+    config.trim_indent |= opts.trim_indent;
+    config.record_failures |= opts.record_failures;
+    config.notify |= opts.notify;
+    if let Some(notify_after) = opts.notify_after {
+        config.notify_after = notify_after;
+    }
+    if let Some(ms) = opts.refresh_interval {
+        config.progress.refresh_interval = Duration::from_millis(ms);
+    }
+    if let Some(mode) = opts.progress_mode {
+        config.progress.mode = mode;
+    }
+    if opts.retry != 0 {
+        bail!("'--retry' is not supported with '--pipeline'");
+    }
+
+    let pipeline = read(path)?;
+    log::trace!("pipeline: {:#?}", pipeline);
+    let name = match &pipeline.name {
+        Some(name) => name.clone(),
+        None => match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => bail!("failed to get pipeline name from path '{}'", path.display()),
+        },
+    };
+
+    let mut stages = Vec::with_capacity(pipeline.stages.len());
+    for stage in &pipeline.stages {
+        if stage.command.is_empty() {
+            bail!("pipeline '{}' has a stage with an empty command", name);
+        }
+        let args: Vec<OsString> = stage.command.iter().map(OsString::from).collect();
+        let mut command = CommandOptions::with_args(Cow::Owned(args), &opts.command, &config)?;
+        command.shell |= stage.shell;
+        let label = match &stage.name {
+            Some(name) => name.clone(),
+            None => command.to_string(),
+        };
+        stages.push((label, command));
+    }
+
+    let dir = data_dir(opts, &name)?;
+    if opts.dry_run {
+        println!("pipeline:  {}", name);
+        for (idx, (_, command)) in stages.iter().enumerate() {
+            println!("stage {}:   {}", idx + 1, command);
+        }
+        println!("data dir:  {}", dir.display());
+        cmd::print_baseline(&dir, &config)?;
+        return Ok(0);
+    }
+
+    let workdir = stages[0].1.workdir.clone();
+    let mut output = output::Writer::for_pipeline(opts, &config, &dir, &workdir)?;
+    let baseline_len = output.diff().len();
+    if let Some(len) = baseline_len {
+        let msg = format!("{:#}", HumanDuration(len));
+        logger::start_progress(len.as_millis() as u64, &msg, &config.progress);
+    }
+
+    let mut rt = Runtime::new()?;
+    let output = Arc::new(output);
+    let start = Instant::now();
+    let mut code = 0;
+    let mut interrupted = false;
+    for (idx, (label, command)) in stages.iter().enumerate() {
+        log::info!("running stage {} of {}: {}", idx + 1, stages.len(), label);
+        if opts.print_command {
+            log::info!("{}", command.to_shell_line());
+        }
+        code = match cmd::run_once(
+            &mut rt,
+            opts,
+            &config,
+            command,
+            &output,
+            baseline_len.is_some(),
+        )? {
+            Exit::Status(status) if status.success() => continue,
+            Exit::Status(status) => {
+                log::error!("process '{}' {}", command, cmd::describe_exit(status));
+                cmd::exit_code(status)
+            }
+            Exit::Timeout => {
+                log::error!(
+                    "process '{}' timed out after {}",
+                    command,
+                    humantime::format_duration(opts.timeout.unwrap_or_default())
+                );
+                cmd::TIMEOUT_EXIT_CODE
+            }
+            Exit::Overtime(len) => {
+                cmd::log_overtime(opts, command, len);
+                cmd::OVERTIME_EXIT_CODE
+            }
+            Exit::Interrupted => {
+                log::error!("process '{}' was interrupted", command);
+                interrupted = true;
+                cmd::INTERRUPT_EXIT_CODE
+            }
+        };
+        break;
+    }
+    // The pipeline is only recorded if every stage succeeds.
+    if interrupted {
+        output.abort()?;
+    } else {
+        output.finish(code == 0)?;
+    }
+    output.finish_log(code)?;
+
+    if let Some(path) = output.path() {
+        log::info!("output log file is located at '{}'", path.display());
+    }
+    let mut summary = format!(
+        "pipeline '{}' finished in {} with exit code {}",
+        name,
+        humantime::format_duration(util::truncate_millis(start.elapsed())),
+        code
+    );
+    if let Some(path) = output.path() {
+        summary.push_str(&format!(", output logged to '{}'", path.display()));
+    }
+    notify::finished(&config, start.elapsed(), &summary, code == 0);
+    logger::print_summary(summary);
+    Ok(code)
+}
+
+fn read(path: &Path) -> Result<Pipeline> {
+    let string = fs::read_to_string(path)
+        .with_context(|_| format!("failed to open pipeline file '{}'", path.display()))?;
+    let pipeline: Pipeline = toml::from_str(&string)
+        .with_context(|_| format!("failed to read TOML file '{}'", path.display()))?;
+    if pipeline.stages.is_empty() {
+        bail!("pipeline file '{}' has no stages", path.display());
+    }
+    Ok(pipeline)
+}
+
+/// Get the directory where data for the pipeline is stored. Unlike commands, this is keyed by
+/// name and tag only, so stages can be changed without discarding the baseline.
+fn data_dir(opts: &cmd::Opts, name: &str) -> Result<PathBuf> {
+    let hash = match &opts.command.tag {
+        Some(tag) => hash::hash((name, tag)),
+        None => hash::hash(name),
+    };
+    Ok(opts
+        .command
+        .data_dir
+        .base()?
+        .join(format!("{}pipeline-{}", hash::PREFIX, hash)))
+}
@@ -0,0 +1,120 @@
+use std::path::Path;
+use std::time::Duration;
+
+use crate::cmd::CommandOptions;
+use crate::config::Config;
+use crate::diff::{self, Access, Stream};
+use crate::output::{self, Streams};
+use crate::Result;
+
+/// Estimates the progress of a run of a command from its output, using the output recorded
+/// for previous runs. This is the same estimate shown by `bp`, without its command line
+/// interface or progress bar.
+pub struct Session {
+    diff: diff::Writer,
+    progress_from: Streams,
+}
+
+impl Session {
+    /// Start a session for a run of `command`. Previous runs are read from, and this run is
+    /// recorded in, the command's directory under `data_dir`, using the same layout as
+    /// `bp --data-dir`. Fails if another process is recording a run of the same command.
+    pub fn new(command: &CommandOptions, data_dir: &Path, config: &Config) -> Result<Self> {
+        let dir = data_dir.join(command.hash());
+        let dir = output::prepare_dir(
+            &dir,
+            command,
+            Access::Exclusive,
+            output::OnCollision::default(),
+        )?;
+        let diff = diff::Writer::new(&dir, config, Access::Exclusive)?;
+        Ok(Session {
+            diff,
+            progress_from: command.progress_from,
+        })
+    }
+
+    /// Record a line written by the command to stdout.
+    pub fn write_stdout(&mut self, line: &[u8]) -> Result<()> {
+        self.write_line(Stream::Stdout, line)
+    }
+
+    /// Record a line written by the command to stderr.
+    pub fn write_stderr(&mut self, line: &[u8]) -> Result<()> {
+        self.write_line(Stream::Stderr, line)
+    }
+
+    fn write_line(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        if self.progress_from.contains(stream) {
+            self.diff.write_line(stream, line)?;
+        }
+        Ok(())
+    }
+
+    /// Advance the estimate in time between lines. This should be called regularly, for
+    /// example whenever a progress bar is redrawn.
+    pub fn tick(&mut self) {
+        self.diff.tick();
+    }
+
+    /// The expected total duration of the run, or `None` if no previous runs have been
+    /// recorded.
+    pub fn len(&self) -> Option<Duration> {
+        self.diff.len()
+    }
+
+    /// The estimated duration of the run which has been completed so far. This is smoothed
+    /// over the calls to `tick`, as configured by `progress.smoothing`.
+    pub fn completed(&self) -> Duration {
+        self.diff.completed()
+    }
+
+    /// Finish the run. Its output is recorded for future runs if it was successful, or if no
+    /// runs have been recorded yet.
+    pub fn finish(mut self, success: bool) -> Result<()> {
+        self.diff.finish(success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::{env, fs, thread};
+
+    #[test]
+    fn second_session_uses_recorded_run() {
+        let data_dir = env::temp_dir().join(format!("bp-session-{}", std::process::id()));
+        let mut command = CommandOptions::from_args(vec!["make".into()], PathBuf::from("/src"));
+        command.progress_from = Streams::Stdout;
+        let config = Config::default();
+
+        let mut session = Session::new(&command, &data_dir, &config).unwrap();
+        assert_eq!(session.len(), None);
+        session.write_stdout(b"compiling\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        session.write_stderr(b"warning: unused variable\n").unwrap();
+        session.write_stdout(b"linking\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        session.finish(true).unwrap();
+        let runs = diff::load(&data_dir.join(command.hash())).unwrap();
+
+        let mut session = Session::new(&command, &data_dir, &config).unwrap();
+        let len = session.len();
+        session.write_stdout(b"compiling\n").unwrap();
+        session.write_stdout(b"linking\n").unwrap();
+        session.tick();
+        let completed = session.completed();
+        session.finish(true).unwrap();
+        fs::remove_dir_all(&data_dir).unwrap();
+
+        // Only stdout is used to estimate progress, so the stderr line isn't recorded.
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].line_count(), 2);
+        let len = len.unwrap();
+        assert!(len >= Duration::from_millis(100));
+        assert!(completed > Duration::default());
+        assert!(completed <= len);
+    }
+}
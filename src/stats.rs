@@ -0,0 +1,106 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use failure::ResultExt;
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::cmd::{self, CommandOptions};
+use crate::config::Config;
+use crate::normalize::Normalizer;
+use crate::{diff, history, util, Result};
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub command: cmd::CommandOpts,
+    /// Print the statistics as JSON
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// A summary of the recorded baseline for a command.
+#[derive(Debug, Serialize)]
+struct Stats {
+    /// The time the most recent run started.
+    recorded_at: SystemTime,
+    /// The number of runs blended into the baseline.
+    runs: usize,
+    /// The median total duration over all runs.
+    total: Duration,
+    /// The number of lines recorded in the most recent run.
+    lines: usize,
+    /// The number of distinct lines over all runs, after normalization.
+    unique_lines: usize,
+    /// The mean difference between the predicted and actual durations of recent successful
+    /// runs, as a fraction of the prediction.
+    prediction_error: Option<f64>,
+}
+
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    let command = CommandOptions::new(&opts.command, &config)?;
+    log::trace!("command: {:#?}", command);
+
+    let dir = opts.command.data_dir(&command, &config)?;
+    let runs = diff::load(&dir)?;
+    let latest = match runs.back() {
+        Some(latest) => latest,
+        None => {
+            log::info!("no runs of '{}' have been recorded", command);
+            return Ok(1);
+        }
+    };
+
+    // Runs recorded by older versions don't store their start time, so the time the baseline
+    // was last modified is used instead.
+    let recorded_at = match latest.recorded_at() {
+        Some(recorded_at) => recorded_at,
+        None => {
+            let path = dir.join("orig").with_extension("json");
+            fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .with_context(|_| format!("failed to get metadata for file '{}'", path.display()))?
+        }
+    };
+
+    let normalizer = Normalizer::from_config(&config)?;
+    let unique_lines = diff::unique_line_count(&runs, &normalizer);
+    let history = history::read(&dir.join("history").with_extension("json"))?;
+    let prediction_error = history::prediction_error(&history);
+
+    let stats = Stats {
+        recorded_at,
+        runs: runs.len(),
+        total: diff::median(runs.iter().map(|run| run.total()).collect()),
+        lines: latest.line_count(),
+        unique_lines,
+        prediction_error: prediction_error.map(|(error, _)| error),
+    };
+
+    if opts.json {
+        json::to_writer_pretty(io::stdout(), &stats)?;
+        println!();
+    } else {
+        println!(
+            "recorded at:   {}",
+            humantime::format_rfc3339_seconds(stats.recorded_at)
+        );
+        println!("runs:          {}", stats.runs);
+        println!(
+            "total:         {}",
+            humantime::format_duration(util::truncate_millis(stats.total))
+        );
+        println!("lines:         {}", stats.lines);
+        println!("unique lines:  {}", stats.unique_lines);
+        if let Some((error, runs)) = prediction_error {
+            println!(
+                "accuracy:      {:.1}% mean error over the last {} runs",
+                error * 100.0,
+                runs
+            );
+        }
+    }
+
+    Ok(0)
+}
@@ -1,7 +1,11 @@
-use std::fs::{File, Metadata, OpenOptions};
-use std::path::Path;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+#[cfg(windows)]
+use std::thread;
+use std::time::Duration;
 
-use failure::ResultExt;
+use failure::{Fail, ResultExt};
 
 use crate::Result;
 
@@ -48,3 +52,68 @@ where
         Ok((FileEntry::Existing(file), meta))
     }
 }
+
+/// Remove a file, returning `false` if it did not exist.
+pub fn remove_if_exists<P>(path: P) -> Result<bool>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err
+            .context(format!("failed to remove file '{}'", path.display()))
+            .into()),
+    }
+}
+
+/// Append a suffix to a file name, e.g. to get the temporary file used to write `orig.json`.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Atomically replace the file at `to` with the file at `from`.
+pub fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    // On Windows, the rename fails if another process has the target open, e.g. to read the
+    // baseline, so it is retried for a short time.
+    #[cfg(windows)]
+    for _ in 0..REPLACE_RETRIES {
+        match fs::rename(from, to) {
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                thread::sleep(REPLACE_RETRY_INTERVAL)
+            }
+            result => return result,
+        }
+    }
+    fs::rename(from, to)
+}
+
+#[cfg(windows)]
+const REPLACE_RETRIES: u32 = 20;
+#[cfg(windows)]
+const REPLACE_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Truncate a duration to whole milliseconds, for display.
+pub fn truncate_millis(dur: Duration) -> Duration {
+    Duration::new(dur.as_secs(), dur.subsec_millis() * 1_000_000)
+}
+
+/// Remove `.` and `..` components from a path without accessing the file system, so that
+/// equivalent paths are hashed the same way. Unlike `fs::canonicalize`, symlinks are
+/// preserved, matching the paths returned by `env::current_dir`.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
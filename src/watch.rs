@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use console::Term;
+use failure::ResultExt;
+use futures::prelude::*;
+use regex::RegexSet;
+use structopt::StructOpt;
+use tokio::runtime::Runtime;
+
+use crate::cmd;
+use crate::config::Config;
+use crate::{pipeline, Result};
+
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    /// A file or directory to watch for changes. Can be given multiple times. Defaults to the
+    /// current directory
+    #[structopt(name = "PATH", long = "path", number_of_values = 1, parse(from_os_str))]
+    pub paths: Vec<PathBuf>,
+    /// Wait until no files have changed for the given number of milliseconds before running
+    /// the command, so a burst of changes only runs it once
+    #[structopt(name = "DEBOUNCE", long = "debounce", default_value = "500")]
+    pub debounce: u64,
+    /// How often, in milliseconds, the watched paths are scanned for changes. Scanning a large
+    /// tree often can be expensive, so this can be increased at the cost of noticing changes
+    /// later
+    #[structopt(name = "POLL_INTERVAL", long = "poll-interval", default_value = "200")]
+    pub poll_interval: u64,
+    /// Clear the screen before each run
+    #[structopt(long = "clear")]
+    pub clear: bool,
+    #[structopt(flatten)]
+    pub cmd: cmd::Opts,
+}
+
+/// Directories which are never watched, because build tools write to them.
+const IGNORED_DIRS: &[&str] = &["target", "node_modules"];
+
+/// The modification time and size of each file under the watched paths.
+type Snapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Run the command, then run it again each time a file under the watched paths changes, until
+/// Ctrl-C is pressed. Each run is recorded as usual, so the progress bar is shown from the
+/// second run onwards.
+///
+/// Files are polled every `--poll-interval` rather than watched with OS notifications, which
+/// behave differently on each platform and can miss changes on network filesystems. Hidden
+/// files and directories, such as `.git`, build output directories such as `target`, and paths
+/// matched by the `.gitignore` file of a watched directory are skipped, which keeps each scan
+/// cheap and stops the command's own output triggering another run. The files are scanned
+/// before each run starts, so a change made while the command is running triggers another run
+/// once it finishes.
+pub fn run(opts: &Opts, config: Config) -> Result<i32> {
+    let paths = if opts.paths.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        opts.paths.clone()
+    };
+    let paths = paths
+        .into_iter()
+        .map(|path| {
+            let ignore = Ignore::new(&path)?;
+            Ok((path, ignore))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let debounce = Duration::from_millis(opts.debounce);
+    let poll_interval = Duration::from_millis(opts.poll_interval);
+
+    // Ctrl-C is handled by the command while it is running, but it also ends the watch. This
+    // runtime is kept for the whole watch, while each run creates and tears down its own.
+    let mut rt = Runtime::new()?;
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let interrupted2 = interrupted.clone();
+    rt.spawn(
+        tokio_signal::ctrl_c()
+            .flatten_stream()
+            .for_each(move |()| {
+                interrupted2.store(true, Ordering::Relaxed);
+                Ok(())
+            })
+            .map_err(|err| log::warn!("failed to listen for Ctrl-C: {}", err)),
+    );
+
+    let mut code = 0;
+    loop {
+        if opts.clear {
+            Term::stdout().clear_screen().ok();
+        }
+
+        let snapshot = scan(&paths)?;
+        let result = match &opts.cmd.pipeline {
+            Some(path) => pipeline::run(&opts.cmd, config.clone(), path),
+            None => cmd::run(&opts.cmd, config.clone()),
+        };
+        match result {
+            Ok(result) => code = result,
+            Err(err) => log::error!("{}", crate::fmt_error(&err)),
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            break;
+        }
+
+        log::info!("waiting for changes...");
+        if !wait_for_change(&paths, snapshot, poll_interval, debounce, &interrupted)? {
+            break;
+        }
+    }
+
+    rt.shutdown_now().wait().ok();
+    Ok(code)
+}
+
+/// Block until a file under `paths` differs from `snapshot` and no further changes have been
+/// made for `debounce`. Returns `false` if Ctrl-C is pressed first.
+fn wait_for_change(
+    paths: &[(PathBuf, Ignore)],
+    mut snapshot: Snapshot,
+    poll_interval: Duration,
+    debounce: Duration,
+    interrupted: &AtomicBool,
+) -> Result<bool> {
+    let mut changed_at: Option<Instant> = None;
+    loop {
+        if interrupted.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+
+        let curr = scan(paths)?;
+        if curr != snapshot {
+            log::debug!("detected changes to watched files");
+            snapshot = curr;
+            changed_at = Some(Instant::now());
+        } else if changed_at.is_some_and(|changed_at| changed_at.elapsed() >= debounce) {
+            return Ok(true);
+        }
+        thread::sleep(poll_interval);
+    }
+}
+
+fn scan(paths: &[(PathBuf, Ignore)]) -> Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    for (path, ignore) in paths {
+        scan_path(path, Path::new(""), ignore, &mut snapshot)
+            .with_context(|_| format!("failed to watch '{}'", path.display()))?;
+    }
+    Ok(snapshot)
+}
+
+/// Add the files under `path` to the snapshot. `relative` is the path relative to the watched
+/// path it was found under, which is matched against `ignore`.
+fn scan_path(
+    path: &Path,
+    relative: &Path,
+    ignore: &Ignore,
+    snapshot: &mut Snapshot,
+) -> io::Result<()> {
+    let meta = fs::metadata(path)?;
+    if !meta.is_dir() {
+        snapshot.insert(path.to_owned(), (meta.modified().ok(), meta.len()));
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let relative = relative.join(&name);
+        if name.to_string_lossy().starts_with('.') || ignore.is_match(&relative) {
+            continue;
+        }
+        match scan_path(&entry.path(), &relative, ignore, snapshot) {
+            Ok(()) => (),
+            // Files may be removed while the directory is being scanned.
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Paths under a watched directory which are skipped. Only a subset of the `.gitignore` syntax
+/// is supported: patterns without a slash match file names at any depth, and other patterns
+/// match the path relative to the watched directory. Negated patterns are not supported.
+#[derive(Debug)]
+struct Ignore {
+    names: RegexSet,
+    paths: RegexSet,
+}
+
+impl Ignore {
+    fn new(dir: &Path) -> Result<Self> {
+        let mut names: Vec<String> = IGNORED_DIRS
+            .iter()
+            .map(|name| regex::escape(name))
+            .collect();
+        let mut paths = Vec::new();
+
+        let gitignore = dir.join(".gitignore");
+        let contents = if gitignore.is_file() {
+            fs::read_to_string(&gitignore)
+                .with_context(|_| format!("failed to read file '{}'", gitignore.display()))?
+        } else {
+            String::new()
+        };
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+            let pattern = line.trim_end_matches('/');
+            if pattern.contains('/') {
+                paths.push(cmd::glob_to_regex(pattern.trim_start_matches('/')));
+            } else {
+                names.push(cmd::glob_to_regex(pattern));
+            }
+        }
+
+        let invalid = format!("invalid pattern in '{}'", gitignore.display());
+        Ok(Ignore {
+            names: RegexSet::new(&names).context(invalid.clone())?,
+            paths: RegexSet::new(&paths).context(invalid)?,
+        })
+    }
+
+    fn is_match(&self, relative: &Path) -> bool {
+        let name = relative
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let path = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        self.names.is_match(&name) || self.paths.is_match(&path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary directory which is removed when the test ends, even if it fails.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("bp-{}-{}", name, std::process::id()));
+            fs::remove_dir_all(&path).ok();
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn ignored_paths_are_not_scanned() {
+        let dir = TempDir::new("watch-ignore");
+        let dir = &dir.0;
+        for path in &[
+            "src",
+            "target/debug",
+            ".git",
+            "build",
+            "docs/gen",
+            "docs/src",
+        ] {
+            fs::create_dir_all(dir.join(path)).unwrap();
+        }
+        for path in &[
+            "src/main.rs",
+            "src/main.o",
+            "target/debug/bp",
+            ".git/HEAD",
+            "build/out",
+            "docs/gen/index.html",
+            "docs/src/index.md",
+        ] {
+            fs::write(dir.join(path), "").unwrap();
+        }
+        fs::write(
+            dir.join(".gitignore"),
+            "# comment\n*.o\n/build/\ndocs/gen\n",
+        )
+        .unwrap();
+
+        let ignore = Ignore::new(dir).unwrap();
+        let files: Vec<PathBuf> = scan(&[(dir.clone(), ignore)])
+            .unwrap()
+            .keys()
+            .map(|path| path.strip_prefix(dir).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            files,
+            [Path::new("docs/src/index.md"), Path::new("src/main.rs")]
+        );
+    }
+
+    #[test]
+    fn change_before_waiting_is_detected() {
+        let dir = TempDir::new("watch-change");
+        let paths = [(dir.0.clone(), Ignore::new(&dir.0).unwrap())];
+        let snapshot = scan(&paths).unwrap();
+
+        // A file saved while the command was running still triggers a run.
+        fs::write(dir.0.join("main.rs"), "fn main() {}").unwrap();
+        let interval = Duration::from_millis(10);
+        let interrupted = AtomicBool::new(false);
+        assert!(wait_for_change(&paths, snapshot, interval, interval, &interrupted).unwrap());
+
+        let snapshot = scan(&paths).unwrap();
+        interrupted.store(true, Ordering::Relaxed);
+        assert!(!wait_for_change(&paths, snapshot, interval, interval, &interrupted).unwrap());
+    }
+}
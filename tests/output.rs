@@ -0,0 +1,441 @@
+#![cfg(unix)]
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::{env, fs};
+
+/// A temporary directory for a test, which is removed when dropped, even if the test fails.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("bp-{}-{}", name, process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Build a command which runs `bp` with its data stored in `data_dir`.
+fn bp(data_dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bp"));
+    command.arg("--data-dir").arg(data_dir);
+    command
+}
+
+/// Build a command which runs a subcommand of `bp`, with its data stored in `data_dir`.
+fn bp_subcommand(subcommand: &str, data_dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bp"));
+    command.arg(subcommand).arg("--data-dir").arg(data_dir);
+    command
+}
+
+#[test]
+fn output_to_stdout() {
+    let data_dir = TempDir::new("stdout");
+    let output = bp(&data_dir)
+        .args(&["--output", "-", "printf", "first\\nsecond\\n"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"first\nsecond\n");
+
+    // The baseline is still recorded in the data directory.
+    let dirs: Vec<_> = fs::read_dir(&data_dir).unwrap().collect();
+    assert_eq!(dirs.len(), 1);
+    let dir = dirs[0].as_ref().unwrap().path();
+    assert!(dir.join("orig.json").is_file());
+    assert!(!dir.join("output.log").exists());
+}
+
+#[test]
+fn progress_fd() {
+    let data_dir = TempDir::new("progress-fd");
+    let output = bp(&data_dir)
+        .args(&["--progress-fd", "stderr", "--tee", "printf", "first\\n"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"first\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("output log file is located at"));
+
+    let output = bp(&data_dir)
+        .args(&["--progress-fd", "stdout", "--output", "-", "true"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("'--progress-fd stdout' cannot be used with '--output -'"));
+}
+
+#[test]
+fn bench() {
+    let data_dir = TempDir::new("bench");
+    let output = bp_subcommand("bench", &data_dir)
+        .args(&[
+            "--runs", "2", "--warmup", "1", "--json", "printf", "line\\n",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // Messages are written to stderr, so stdout only contains the statistics.
+    let bench: json::Value = json::from_slice(&output.stdout).unwrap();
+    assert_eq!(bench["runs"], 2);
+    assert_eq!(bench["warmup"], 1);
+    assert_eq!(bench["totals"].as_array().unwrap().len(), 2);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("warmup run 1 of 1"));
+    assert!(stderr.contains("run 2 of 2"));
+
+    // A failing run stops the benchmark.
+    let output = bp_subcommand("bench", &data_dir)
+        .args(&["--runs", "3", "--json", "sh", "-c", "exit 4"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn output_template_keeps_logs() {
+    let data_dir = TempDir::new("template");
+    for code in &["0", "1", "2"] {
+        let status = bp(&data_dir)
+            .args(&["--output-template", "{pid}-{exit}.log", "--keep-logs", "2"])
+            .args(&["sh", "-c", "echo $CODE; exit $CODE"])
+            .env("CODE", code)
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(code.parse().unwrap()));
+    }
+
+    let dirs: Vec<_> = fs::read_dir(&data_dir).unwrap().collect();
+    assert_eq!(dirs.len(), 1);
+    let logs_dir = dirs[0].as_ref().unwrap().path().join("logs");
+    let mut logs: Vec<String> = fs::read_dir(&logs_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    // The log of the oldest run is removed, and the others are named by their exit code.
+    logs.sort_by_key(|name| name.rsplit('-').next().unwrap().to_owned());
+    assert_eq!(logs.len(), 2);
+    assert!(logs[0].ends_with("-1.log"));
+    assert!(logs[1].ends_with("-2.log"));
+    let log = fs::read_to_string(logs_dir.join(&logs[1])).unwrap();
+    assert_eq!(log, "2\n");
+}
+
+#[test]
+fn read_only_data_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = TempDir::new("read-only");
+    let run = |data_dir: &Path| {
+        let output = bp(data_dir).args(&["echo", "hello"]).output().unwrap();
+        assert!(!output.status.success());
+        // Messages are written to stdout, since the command's output is not.
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // A data directory under a file can't be created, even by root.
+    let file = base.join("file");
+    fs::write(&file, "").unwrap();
+    let messages = run(&file.join("data"));
+    assert!(messages.contains("failed to create directory"));
+    assert!(!file.join("data").exists());
+
+    let data_dir = base.join("data");
+    fs::create_dir(&data_dir).unwrap();
+    fs::set_permissions(&data_dir, fs::Permissions::from_mode(0o555)).unwrap();
+    // Permissions aren't enforced for root, so this is only checked for other users.
+    if fs::create_dir(data_dir.join("probe")).is_err() {
+        let messages = run(&data_dir);
+        assert!(messages.contains("is not writable"));
+        assert!(messages.contains("'--data-dir'"));
+    }
+    fs::set_permissions(&data_dir, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn export_and_import() {
+    let base = TempDir::new("export");
+    let archive = base.join("archive.json");
+    let (a, b) = (base.join("a"), base.join("b"));
+    let run = |command: &mut Command| {
+        command
+            .args(&["--", "printf", "first\\nsecond\\n"])
+            .status()
+            .unwrap()
+            .success()
+    };
+
+    assert!(run(&mut bp(&a)));
+    assert!(run(bp_subcommand("export", &a).arg("-o").arg(&archive)));
+    assert!(run(bp_subcommand("import", &b).arg(&archive)));
+
+    let baseline = |data_dir: &Path| {
+        let dirs: Vec<_> = fs::read_dir(data_dir).unwrap().collect();
+        assert_eq!(dirs.len(), 1);
+        let dir = dirs[0].as_ref().unwrap().path();
+        // The command is recorded, so a hash collision can be detected.
+        assert!(dir.join("command.toml").exists());
+        fs::read(dir.join("orig.json")).unwrap()
+    };
+    assert_eq!(baseline(&a), baseline(&b));
+
+    // An existing baseline is only replaced with '--force'.
+    assert!(!run(bp_subcommand("import", &b).arg(&archive)));
+    assert!(run(bp_subcommand("import", &b)
+        .arg("--force")
+        .arg(&archive)));
+}
+
+#[test]
+fn output_path_resolution() {
+    let base = TempDir::new("output-path");
+    let workdir = base.join("workdir");
+    fs::create_dir_all(&workdir).unwrap();
+    let run = |extra: &[&str]| {
+        let status = bp(Path::new("data"))
+            .current_dir(&base)
+            .args(&["--workdir", "workdir", "--output", "out.log"])
+            .args(extra)
+            .args(&["echo", "hello"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+
+    // By default the path is relative to the command's working directory.
+    run(&[]);
+    assert_eq!(fs::read(workdir.join("out.log")).unwrap(), b"hello\n");
+    assert!(!base.join("out.log").exists());
+
+    run(&["--cwd-relative-output"]);
+    assert_eq!(fs::read(base.join("out.log")).unwrap(), b"hello\n");
+}
+
+#[test]
+fn hash_collision_is_isolated() {
+    let data_dir = TempDir::new("collision");
+    let path = |args: &[&str]| {
+        let output = bp_subcommand("path", &data_dir)
+            .arg("--")
+            .args(args)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        PathBuf::from(stdout.lines().next().unwrap())
+    };
+    let run = |args: &[&str]| {
+        bp(&data_dir)
+            .arg("--reset")
+            .arg("--")
+            .args(args)
+            .status()
+            .unwrap()
+            .success()
+    };
+
+    // Make the directory for 'echo second' look like it records 'echo first', as if their
+    // hashes collided.
+    assert!(run(&["echo", "first"]));
+    let first = path(&["echo", "first"]);
+    let second = path(&["echo", "second"]);
+    fs::create_dir_all(&second).unwrap();
+    for file in &["command.toml", "orig.json"] {
+        fs::copy(first.join(file), second.join(file)).unwrap();
+    }
+    let orig = fs::read(second.join("orig.json")).unwrap();
+
+    assert!(run(&["echo", "second"]));
+    let isolated = path(&["echo", "second"]);
+    assert_eq!(isolated.file_name().unwrap(), {
+        let mut name = second.file_name().unwrap().to_owned();
+        name.push("-2");
+        name
+    });
+    assert!(isolated.join("orig.json").exists());
+    // '--reset' only affects the isolated directory.
+    assert_eq!(fs::read(second.join("orig.json")).unwrap(), orig);
+}
+
+#[test]
+fn no_record_leaves_data_dir_alone() {
+    let data_dir = TempDir::new("no-record");
+    // Each run prints its own PID, so the log file shows which run wrote it.
+    let command = |extra: &[&str]| {
+        let mut command = bp(&data_dir);
+        command
+            .args(extra)
+            .args(&["sh", "-c", "echo $$; sleep 1"])
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null());
+        command
+    };
+
+    assert!(command(&[]).status().unwrap().success());
+    let dirs: Vec<_> = fs::read_dir(&data_dir).unwrap().collect();
+    let dir = dirs[0].as_ref().unwrap().path();
+    let log = fs::read(dir.join("output.log")).unwrap();
+
+    let mut shared = command(&["--no-record"]).spawn().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    // Other runs using '--no-record' may run at the same time, but a recorded run can't.
+    assert!(!command(&[]).status().unwrap().success());
+    assert!(!dir.join("status.json").exists());
+    assert!(command(&["--no-record"]).status().unwrap().success());
+    assert!(shared.wait().unwrap().success());
+
+    assert_eq!(fs::read(dir.join("output.log")).unwrap(), log);
+}
+
+#[test]
+fn timestamps() {
+    let data_dir = TempDir::new("timestamps");
+    let output = bp(&data_dir)
+        .args(&["--timestamps", "elapsed", "--output", "-"])
+        .args(&["printf", "first\\nsecond\\n"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    for (line, expected) in lines.iter().zip(&["first", "second"]) {
+        let (stamp, rest) = line.split_once("] ").unwrap();
+        assert!(stamp.starts_with('[') && stamp.ends_with('s'));
+        assert_eq!(rest, *expected);
+    }
+}
+
+/// Check whether a process is running, treating a zombie as exited.
+#[cfg(target_os = "linux")]
+fn is_running(pid: &str) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => !stat.rsplit(") ").next().unwrap().starts_with('Z'),
+        Err(_) => false,
+    }
+}
+
+/// Wait for a command to write its process ID to `path`, as `echo $$ > path`.
+#[cfg(target_os = "linux")]
+fn read_pid(path: &std::path::Path) -> String {
+    for _ in 0..100 {
+        if let Ok(pid) = fs::read_to_string(path) {
+            if pid.ends_with('\n') {
+                return pid.trim().to_owned();
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    panic!("process ID was not written to '{}'", path.display());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn child_is_killed_on_error() {
+    let base = TempDir::new("kill-on-error");
+    let pid_path = base.join("pid");
+
+    // Writing the log fails once its buffer is flushed, while the command is still running.
+    let status = bp(&base.join("data"))
+        .args(&["--output", "/dev/full", "sh", "-c"])
+        .arg("echo $$ > \"$PID_PATH\"; seq 1000000; exec sleep 30")
+        .env("PID_PATH", &pid_path)
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert!(!is_running(&read_pid(&pid_path)));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn child_is_killed_with_bp() {
+    let base = TempDir::new("kill-with-bp");
+    let pid_path = base.join("pid");
+
+    let mut bp = bp(&base.join("data"))
+        .args(&["sh", "-c", "echo $$ > \"$PID_PATH\"; exec sleep 30"])
+        .env("PID_PATH", &pid_path)
+        .spawn()
+        .unwrap();
+    let pid = read_pid(&pid_path);
+    assert!(is_running(&pid));
+
+    // bp can't clean up after SIGKILL, so the child must be killed by the kernel.
+    bp.kill().unwrap();
+    bp.wait().unwrap();
+    for _ in 0..100 {
+        if !is_running(&pid) {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert!(!is_running(&pid));
+}
+
+#[test]
+fn exit_codes() {
+    let data_dir = TempDir::new("exit-codes");
+    let code = |args: &[&str]| bp(&data_dir).args(args).output().unwrap().status.code();
+
+    assert_eq!(code(&["sh", "-c", "exit 5"]), Some(5));
+    assert_eq!(code(&["sh", "-c", "kill -9 $$"]), Some(128 + 9));
+    assert_eq!(
+        code(&["--failure-code", "9", "sh", "-c", "exit 5"]),
+        Some(9)
+    );
+    assert_eq!(code(&["--failure-code", "9", "true"]), Some(0));
+    assert_eq!(code(&["/nonexistent/command"]), Some(17));
+    assert_eq!(
+        code(&[
+            "--error-code",
+            "3",
+            "--failure-code",
+            "9",
+            "/nonexistent/command"
+        ]),
+        Some(3)
+    );
+}
+
+#[test]
+fn gc_keeps_foreign_directories() {
+    let data_dir = TempDir::new("gc");
+    for dir in &["v0-old", "old-hash", "notes", "v1-current"] {
+        fs::create_dir_all(data_dir.join(dir)).unwrap();
+    }
+    fs::write(data_dir.join("old-hash").join("orig.json"), "{}").unwrap();
+    fs::write(data_dir.join("notes").join("todo.txt"), "keep me").unwrap();
+
+    let status = bp_subcommand("gc", &data_dir).status().unwrap();
+    assert!(status.success());
+    assert!(!data_dir.join("v0-old").exists());
+    assert!(!data_dir.join("old-hash").exists());
+    assert!(data_dir.join("notes").join("todo.txt").is_file());
+    assert!(data_dir.join("v1-current").is_dir());
+}